@@ -21,11 +21,29 @@ interface Config {
 }
 
 interface BackupDefinition {
+    // Only used by `g2s3 run --job`.
+    name?: string;
     google_drive_folder: string;
     google_secrets: SecretProperty[],
     s3_url: string,
     should_create_bucket: boolean,
     storage_class?: string,
+    storage_class_rules?: string[],
+    sse?: string,
+    sse_kms_key_id?: string,
+    sse_bucket_key_enabled?: boolean,
+    object_lock_mode?: string,
+    object_lock_retain_for?: string,
+    object_lock_legal_hold?: boolean,
+    include?: string[],
+    exclude?: string[],
+    mime_types?: string[],
+    exclude_mime_types?: string[],
+    min_size?: string,
+    max_size?: string,
+    modified_after?: string,
+    modified_before?: string,
+    owner?: string,
     schedule?: schedule.CronOptions;
 }
 
@@ -71,10 +89,35 @@ def handler(event, context): boto3.client("sns").publish(
         ],
     });
 
+    const usesObjectLock = (backupDef: BackupDefinition) =>
+        !!backupDef.object_lock_mode || !!backupDef.object_lock_legal_hold
     for (let [bucketName, shouldCreate] of new Map(config.backup_definitions.map(
         backupDef => [bucketNameFrom(backupDef.s3_url), backupDef.should_create_bucket]))) {
-        (shouldCreate ? new s3.Bucket(scope, `backup-bucket-${bucketName}`, {bucketName: bucketName})
-            : s3.Bucket.fromBucketName(scope, `backup-bucket-${bucketName}`, bucketName)).grantPut(batchJobRole)
+        const objectLockEnabled = config.backup_definitions.some(backupDef =>
+            bucketNameFrom(backupDef.s3_url) == bucketName && usesObjectLock(backupDef))
+        const bucket = shouldCreate
+            ? new s3.Bucket(scope, `backup-bucket-${bucketName}`, {bucketName: bucketName, objectLockEnabled})
+            : s3.Bucket.fromBucketName(scope, `backup-bucket-${bucketName}`, bucketName)
+        bucket.grantPut(batchJobRole)
+        // For the preflight check: HeadBucket and deleting the test object again
+        batchJobRole.addToPolicy(new iam.PolicyStatement({
+            actions: ["s3:ListBucket"],
+            resources: [bucket.bucketArn],
+        }))
+        batchJobRole.addToPolicy(new iam.PolicyStatement({
+            actions: ["s3:DeleteObject"],
+            resources: [bucket.arnForObjects("*.g2s3-preflight")],
+        }))
+        if (objectLockEnabled) {
+            batchJobRole.addToPolicy(new iam.PolicyStatement({
+                actions: ["s3:GetBucketObjectLockConfiguration"],
+                resources: [bucket.bucketArn],
+            }))
+            batchJobRole.addToPolicy(new iam.PolicyStatement({
+                actions: ["s3:PutObjectRetention", "s3:PutObjectLegalHold"],
+                resources: [bucket.arnForObjects("*")],
+            }))
+        }
     }
 
     for (let backupDef of config.backup_definitions) {
@@ -82,6 +125,54 @@ def handler(event, context): boto3.client("sns").publish(
         if (backupDef.storage_class) {
             command.push("--s3-storage-class", backupDef.storage_class)
         }
+        for (let rule of backupDef.storage_class_rules ?? []) {
+            command.push("--storage-class-rule", rule)
+        }
+        if (backupDef.sse) {
+            command.push("--sse", backupDef.sse)
+        }
+        if (backupDef.sse_kms_key_id) {
+            command.push("--sse-kms-key-id", backupDef.sse_kms_key_id)
+            if (backupDef.sse_kms_key_id.startsWith("arn:")) {
+                batchJobRole.addToPolicy(new iam.PolicyStatement({
+                    actions: ["kms:GenerateDataKey", "kms:Decrypt"],
+                    resources: [backupDef.sse_kms_key_id],
+                }))
+            }
+        }
+        if (backupDef.sse_bucket_key_enabled) {
+            command.push("--sse-bucket-key-enabled")
+        }
+        if (backupDef.object_lock_mode) {
+            command.push("--object-lock-mode", backupDef.object_lock_mode)
+        }
+        if (backupDef.object_lock_retain_for) {
+            command.push("--object-lock-retain-for", backupDef.object_lock_retain_for)
+        }
+        for (let [option, values] of [
+            ["--include", backupDef.include],
+            ["--exclude", backupDef.exclude],
+            ["--mime-type", backupDef.mime_types],
+            ["--exclude-mime-type", backupDef.exclude_mime_types],
+        ] as [string, string[] | undefined][]) {
+            for (let value of values ?? []) {
+                command.push(option, value)
+            }
+        }
+        for (let [option, value] of [
+            ["--min-size", backupDef.min_size],
+            ["--max-size", backupDef.max_size],
+            ["--modified-after", backupDef.modified_after],
+            ["--modified-before", backupDef.modified_before],
+            ["--owner", backupDef.owner],
+        ] as [string, string | undefined][]) {
+            if (value) {
+                command.push(option, value)
+            }
+        }
+        if (backupDef.object_lock_legal_hold) {
+            command.push("--object-lock-legal-hold")
+        }
         const jobDefinition = new batch.CfnJobDefinition(
             scope,
             `google-${backupDef.google_drive_folder}-backup-to-s3-job-def`,
@@ -0,0 +1,131 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpListener;
+use std::path::Path;
+
+use oauth2::basic::BasicClient;
+use oauth2::reqwest::http_client;
+use oauth2::url::Url;
+use oauth2::{
+    AuthType, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, HttpRequest,
+    HttpResponse, RedirectUrl, RevocationUrl, Scope, TokenUrl,
+};
+use serde::Deserialize;
+
+use crate::errors::{Error, Result, ResultExt};
+
+/// The scopes every Google source needs, all read-only.
+pub const SCOPES: &[&str] = &[
+    "https://www.googleapis.com/auth/photospicker.mediaitems.readonly",
+    "https://www.googleapis.com/auth/drive.readonly",
+    "https://www.googleapis.com/auth/gmail.readonly",
+    "https://www.googleapis.com/auth/calendar.readonly",
+    "https://www.googleapis.com/auth/contacts.readonly",
+];
+
+#[derive(Deserialize, Debug)]
+struct OAuthData {
+    installed: Installed,
+}
+
+#[derive(Deserialize, Debug)]
+struct Installed {
+    client_id: String,
+    token_uri: String,
+    client_secret: String,
+}
+
+/// Asks the user to authorize the OAuth client in `client_secret_file` (the credentials downloaded
+/// from the GCP console) in the browser, waits for Google to redirect back to localhost:7777 and
+/// writes the tokens, including the refresh token, to `token_file`. This blocks.
+pub fn retrieve_tokens(client_secret_file: &Path, token_file: &Path) -> Result<()> {
+    let oauth: OAuthData = serde_json::from_str(
+        &std::fs::read_to_string(client_secret_file)
+            .chain_err(|| format!("Could not read {}", client_secret_file.display()))?,
+    )
+    .chain_err(|| {
+        format!("Invalid OAuth client credentials in {}", client_secret_file.display())
+    })?;
+
+    let client = BasicClient::new(
+        ClientId::new(oauth.installed.client_id),
+        Some(ClientSecret::new(oauth.installed.client_secret)),
+        AuthUrl::new("https://accounts.google.com/o/oauth2/v2/auth".to_string()).unwrap(),
+        Some(TokenUrl::new(oauth.installed.token_uri).chain_err(|| "Invalid token_uri")?),
+    )
+    .set_redirect_uri(RedirectUrl::new("http://locahost:7777".to_string()).unwrap())
+    .set_revocation_uri(
+        RevocationUrl::new("https://oauth2.googleapis.com/revoke".to_string()).unwrap(),
+    )
+    .set_auth_type(AuthType::RequestBody);
+    let mut authorize_url = client.authorize_url(CsrfToken::new_random);
+    for scope in SCOPES {
+        authorize_url = authorize_url.add_scope(Scope::new(scope.to_string()));
+    }
+    let (auth_url, csrf_token) = authorize_url
+        .add_extra_param("access_type", "offline")
+        .add_extra_param("include_granted_scopes", "true")
+        .url();
+    println!(
+        "Browse to: {}",
+        auth_url
+            .to_string()
+            .replace("http%3A%2F%2Flocahost%3A7777", "http%3A//localhost:7777")
+            .replace("%2F", "/")
+    );
+
+    let listener = TcpListener::bind("127.0.0.1:7777").chain_err(|| "Could not listen on 7777")?;
+    let mut stream = listener
+        .incoming()
+        .flatten()
+        .next()
+        .ok_or_else(|| Error::from("No redirect from Google"))?;
+    let (code, state) = {
+        let mut reader = BufReader::new(&stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).chain_err(|| "Could not read redirect")?;
+
+        let redirect_url = request_line.split_whitespace().nth(1).unwrap_or_default();
+        let url = Url::parse(&("http://localhost".to_string() + redirect_url))
+            .chain_err(|| format!("Invalid redirect {redirect_url}"))?;
+        let param = |name: &str| {
+            url.query_pairs()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.into_owned())
+                .ok_or_else(|| Error::from(format!("Google redirected without {name}")))
+        };
+        (AuthorizationCode::new(param("code")?), CsrfToken::new(param("state")?))
+    };
+
+    let message = "Go back to your terminal :)";
+    stream
+        .write_all(
+            format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n{}", message.len(), message)
+                .as_bytes(),
+        )
+        .chain_err(|| "Could not answer redirect")?;
+
+    if state.secret() != csrf_token.secret() {
+        return Err(Error::from("Google returned an unexpected state"));
+    }
+
+    // Exchange the code with a token.
+    let token_response = client
+        .exchange_code(code)
+        .request(localhost_http_client)
+        .chain_err(|| "Could not exchange the code for tokens")?;
+    std::fs::write(token_file, serde_json::to_string_pretty(&token_response).unwrap())
+        .chain_err(|| format!("Could not write {}", token_file.display()))?;
+    println!("Wrote the tokens to {}", token_file.display());
+    Ok(())
+}
+
+/// Sends token requests with the redirect URI spelled the way it was registered.
+fn localhost_http_client(
+    mut request: HttpRequest,
+) -> std::result::Result<HttpResponse, oauth2::reqwest::Error<reqwest::Error>> {
+    let new_body = String::from_utf8_lossy(&request.body)
+        .replace("http%3A%2F%2Flocahost%3A7777", "http%3A//localhost:7777")
+        .replace("%2F", "/");
+    request.body = new_body.into_bytes();
+    http_client(request)
+}
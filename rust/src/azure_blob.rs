@@ -0,0 +1,634 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use async_trait::async_trait;
+use aws_sdk_s3::types::StorageClass;
+use base64::Engine;
+use futures::stream;
+use hmac::{Hmac, Mac};
+use percent_encoding::{utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
+use reqwest::{Method, StatusCode};
+use sha2::Sha256;
+use url::Url;
+
+use crate::destination::{Destination, ObjectInfo, ObjectReader, ObjectWriter};
+use crate::errors::{Error, Result, ResultExt};
+
+/// Blobs are uploaded in blocks of this size. Anything smaller is uploaded with a single Put Blob.
+const BLOCK_SIZE: usize = 64 * 1024 * 1024;
+
+const API_VERSION: &str = "2021-12-02";
+
+const PREFLIGHT_BLOB_NAME: &str = ".g2s3-preflight";
+
+/// Characters that are percent-encoded in blob paths. `/` is kept, since it separates "folders".
+const PATH_ENCODE_SET: &AsciiSet =
+    &NON_ALPHANUMERIC.remove(b'/').remove(b'-').remove(b'_').remove(b'.').remove(b'~');
+
+/// Credentials and endpoint of an Azure storage account.
+#[derive(Clone, Default)]
+pub struct AzureOptions {
+    pub account: Option<String>,
+    /// Base64-encoded shared key of the account.
+    pub key: Option<String>,
+    /// Shared access signature, as an alternative to the shared key.
+    pub sas_token: Option<String>,
+    /// Defaults to https://<account>.blob.core.windows.net. For Azurite, use
+    /// http://127.0.0.1:10000/<account>.
+    pub endpoint: Option<String>,
+}
+
+/// Blobs under an `azblob://container/some/folder` URL.
+pub struct AzureBlobDestination {
+    url: String,
+    client: Arc<AzureClient>,
+    container: String,
+    prefix: String,
+}
+
+impl AzureBlobDestination {
+    pub fn new(url: &str, options: &AzureOptions) -> Result<AzureBlobDestination> {
+        let parsed = Url::parse(url).chain_err(|| format!("{url} is not a valid URL"))?;
+        let container = parsed
+            .host_str()
+            .filter(|h| !h.is_empty())
+            .ok_or_else(|| Error::from(format!("{url} does not contain a container name")))?
+            .to_string();
+        let prefix = parsed.path().trim_matches('/').to_string();
+        Ok(AzureBlobDestination {
+            url: url.to_string(),
+            client: Arc::new(AzureClient::new(options)?),
+            container,
+            prefix,
+        })
+    }
+
+    fn blob_path(&self, name: &str) -> String {
+        match self.prefix.as_str() {
+            "" => format!("{}/{name}", self.container),
+            prefix => format!("{}/{prefix}/{name}", self.container),
+        }
+    }
+}
+
+#[async_trait]
+impl Destination for AzureBlobDestination {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn check_writable(&self) -> Result<()> {
+        let path = self.blob_path(PREFLIGHT_BLOB_NAME);
+        self.client
+            .put_blob(&path, b"g2s3 preflight check".to_vec(), None, &HashMap::new())
+            .await?;
+        self.client.send(Method::DELETE, &path, &[], vec![], vec![]).await?;
+        Ok(())
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        storage_class: &StorageClass,
+        metadata: HashMap<String, String>,
+    ) -> Result<Box<dyn ObjectWriter>> {
+        Ok(Box::new(BlockBlobUpload {
+            client: self.client.clone(),
+            path: self.blob_path(name),
+            access_tier: access_tier_for(storage_class),
+            metadata,
+            block_ids: vec![],
+            buf: Vec::with_capacity(BLOCK_SIZE),
+        }))
+    }
+
+    async fn metadata(&self, name: &str) -> Result<Option<HashMap<String, String>>> {
+        let path = self.blob_path(name);
+        let resp = self
+            .client
+            .send_unchecked(Method::HEAD, &path, &[], vec![], vec![])
+            .await
+            .chain_err(|| format!("Could not get metadata of {path}"))?;
+        match resp.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Ok(Some(metadata_from(check_status(resp, &Method::HEAD, &path).await?.headers()))),
+        }
+    }
+
+    async fn read(&self, name: &str) -> Result<ObjectReader> {
+        let path = self.blob_path(name);
+        let resp = self
+            .client
+            .send(Method::GET, &path, &[], vec![], vec![])
+            .await
+            .chain_err(|| format!("Could not download {path}"))?;
+        let metadata = metadata_from(resp.headers());
+        let content = stream::unfold(resp, move |mut resp| {
+            let path = path.clone();
+            async move {
+                match resp.chunk().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk.to_vec()), resp)),
+                    Ok(None) => None,
+                    Err(e) => {
+                        Some((Err(Error::from(format!("Download error for {path}: {e}"))), resp))
+                    }
+                }
+            }
+        });
+        Ok(ObjectReader { metadata, content: Box::pin(content) })
+    }
+
+    async fn list(&self) -> Result<Vec<ObjectInfo>> {
+        let prefix = match self.prefix.as_str() {
+            "" => String::new(),
+            prefix => format!("{prefix}/"),
+        };
+        let mut objects = vec![];
+        let mut marker = String::new();
+        loop {
+            let mut query = vec![("restype", "container"), ("comp", "list"), ("prefix", &prefix)];
+            if !marker.is_empty() {
+                query.push(("marker", &marker));
+            }
+            let resp = self
+                .client
+                .send(Method::GET, &self.container, &query, vec![], vec![])
+                .await
+                .chain_err(|| format!("Could not list blobs in {}", self.url))?;
+            let xml = resp.text().await.chain_err(|| format!("Could not list {}", self.url))?;
+            let (blobs, next_marker) = parse_blob_list(&xml)?;
+            objects.extend(blobs.into_iter().filter_map(|blob| {
+                Some(ObjectInfo {
+                    name: blob.name.strip_prefix(&prefix)?.to_string(),
+                    size: blob.size,
+                })
+            }));
+            match next_marker {
+                Some(next_marker) => marker = next_marker,
+                None => break,
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let path = self.blob_path(name);
+        self.client
+            .send(Method::DELETE, &path, &[], vec![], vec![])
+            .await
+            .chain_err(|| format!("Could not delete {path}"))?;
+        Ok(())
+    }
+}
+
+/// Streams a block blob. Data is buffered until a full block is available, so blobs smaller than
+/// `BLOCK_SIZE` end up as a single Put Blob, and everything else as staged blocks that are
+/// committed with Put Block List at the end.
+struct BlockBlobUpload {
+    client: Arc<AzureClient>,
+    path: String,
+    access_tier: Option<&'static str>,
+    metadata: HashMap<String, String>,
+    block_ids: Vec<String>,
+    buf: Vec<u8>,
+}
+
+impl BlockBlobUpload {
+    async fn put_block(&mut self, data: Vec<u8>) -> Result<()> {
+        let block_id = base64::engine::general_purpose::STANDARD
+            .encode(format!("{:08}", self.block_ids.len()));
+        self.client
+            .send(
+                Method::PUT,
+                &self.path,
+                &[("comp", "block"), ("blockid", &block_id)],
+                vec![],
+                data,
+            )
+            .await
+            .chain_err(|| {
+                format!("Could not upload block {} of {}", self.block_ids.len(), self.path)
+            })?;
+        self.block_ids.push(block_id);
+        Ok(())
+    }
+
+    async fn complete(&mut self) -> Result<usize> {
+        if self.block_ids.is_empty() {
+            let buf = std::mem::take(&mut self.buf);
+            self.client.put_blob(&self.path, buf, self.access_tier, &self.metadata).await?;
+            return Ok(1);
+        }
+        if !self.buf.is_empty() {
+            let block = std::mem::take(&mut self.buf);
+            self.put_block(block).await?;
+        }
+        let mut block_list = String::from(r#"<?xml version="1.0" encoding="utf-8"?><BlockList>"#);
+        for block_id in &self.block_ids {
+            block_list.push_str(&format!("<Latest>{block_id}</Latest>"));
+        }
+        block_list.push_str("</BlockList>");
+        self.client
+            .send(
+                Method::PUT,
+                &self.path,
+                &[("comp", "blocklist")],
+                blob_headers(self.access_tier, &self.metadata),
+                block_list.into_bytes(),
+            )
+            .await
+            .chain_err(|| format!("Could not commit block list of {}", self.path))?;
+        Ok(self.block_ids.len())
+    }
+}
+
+#[async_trait]
+impl ObjectWriter for BlockBlobUpload {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= BLOCK_SIZE {
+            let block = std::mem::replace(&mut self.buf, Vec::with_capacity(BLOCK_SIZE));
+            self.put_block(block).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<usize> {
+        self.complete().await
+    }
+
+    /// Uncommitted blocks cannot be deleted. Azure discards them after a week.
+    async fn abort(self: Box<Self>) {}
+}
+
+/// Maps S3 storage classes to the Azure access tier with the closest cost profile, so the same
+/// storage class options work for both. `None` means the account's default tier.
+fn access_tier_for(storage_class: &StorageClass) -> Option<&'static str> {
+    match storage_class {
+        StorageClass::Standard | StorageClass::ReducedRedundancy => Some("Hot"),
+        StorageClass::StandardIa | StorageClass::OnezoneIa | StorageClass::GlacierIr => {
+            Some("Cool")
+        }
+        StorageClass::Glacier => Some("Cold"),
+        StorageClass::DeepArchive => Some("Archive"),
+        _ => None,
+    }
+}
+
+/// Azure metadata names must be C# identifiers, so the dashes in our metadata keys are stored as
+/// underscores.
+fn blob_headers(
+    access_tier: Option<&str>,
+    metadata: &HashMap<String, String>,
+) -> Vec<(String, String)> {
+    let mut headers: Vec<(String, String)> = metadata
+        .iter()
+        .map(|(k, v)| (format!("x-ms-meta-{}", k.replace('-', "_")), v.clone()))
+        .collect();
+    if let Some(access_tier) = access_tier {
+        headers.push(("x-ms-access-tier".to_string(), access_tier.to_string()));
+    }
+    headers
+}
+
+fn metadata_from(headers: &reqwest::header::HeaderMap) -> HashMap<String, String> {
+    headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let key = name.as_str().strip_prefix("x-ms-meta-")?;
+            Some((key.replace('_', "-"), value.to_str().ok()?.to_string()))
+        })
+        .collect()
+}
+
+struct BlobItem {
+    name: String,
+    size: u64,
+}
+
+/// Parses the response of List Blobs into the blobs and the marker of the next page, if any.
+fn parse_blob_list(xml: &str) -> Result<(Vec<BlobItem>, Option<String>)> {
+    let mut blobs = vec![];
+    let mut next_marker = None;
+    let mut path: Vec<String> = vec![];
+    let mut name = String::new();
+    for token in xmlparser::Tokenizer::from(xml) {
+        match token.chain_err(|| "Could not parse blob list")? {
+            xmlparser::Token::ElementStart { local, .. } => path.push(local.to_string()),
+            xmlparser::Token::ElementEnd { end: xmlparser::ElementEnd::Open, .. } => {}
+            xmlparser::Token::ElementEnd { .. } => {
+                path.pop();
+            }
+            xmlparser::Token::Text { text } => {
+                let text = unescape(text.as_str());
+                match path.iter().map(String::as_str).collect::<Vec<_>>().as_slice() {
+                    [.., "Blob", "Name"] => name = text,
+                    [.., "Blob", "Properties", "Content-Length"] => blobs.push(BlobItem {
+                        name: std::mem::take(&mut name),
+                        size: text.parse().chain_err(|| format!("Invalid blob size {text}"))?,
+                    }),
+                    [.., "NextMarker"] if !text.is_empty() => next_marker = Some(text),
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok((blobs, next_marker))
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+enum Credentials {
+    SharedKey(Vec<u8>),
+    Sas(String),
+}
+
+/// A minimal client for the Blob service REST API.
+struct AzureClient {
+    http: reqwest::Client,
+    account: String,
+    endpoint: String,
+    credentials: Credentials,
+}
+
+impl AzureClient {
+    fn new(options: &AzureOptions) -> Result<AzureClient> {
+        let account = options.account.clone().ok_or_else(|| {
+            Error::from("Azure destinations need a storage account (AZURE_STORAGE_ACCOUNT)")
+        })?;
+        let credentials = match (options.key.as_deref(), options.sas_token.as_deref()) {
+            (Some(key), _) => Credentials::SharedKey(
+                base64::engine::general_purpose::STANDARD
+                    .decode(key)
+                    .chain_err(|| "Azure storage key must be base64 encoded")?,
+            ),
+            (None, Some(sas_token)) => {
+                Credentials::Sas(sas_token.trim_start_matches('?').to_string())
+            }
+            (None, None) => {
+                return Err(Error::from(
+                    "Azure destinations need a storage key (AZURE_STORAGE_KEY) or a SAS token \
+                     (AZURE_STORAGE_SAS_TOKEN)",
+                ))
+            }
+        };
+        let endpoint = options
+            .endpoint
+            .clone()
+            .unwrap_or_else(|| format!("https://{account}.blob.core.windows.net"))
+            .trim_end_matches('/')
+            .to_string();
+        Ok(AzureClient { http: reqwest::Client::new(), account, endpoint, credentials })
+    }
+
+    async fn put_blob(
+        &self,
+        path: &str,
+        data: Vec<u8>,
+        access_tier: Option<&str>,
+        metadata: &HashMap<String, String>,
+    ) -> Result<()> {
+        let mut headers = blob_headers(access_tier, metadata);
+        headers.push(("x-ms-blob-type".to_string(), "BlockBlob".to_string()));
+        self.send(Method::PUT, path, &[], headers, data)
+            .await
+            .chain_err(|| format!("Could not upload {path}"))?;
+        Ok(())
+    }
+
+    /// Sends a request for `path` (`<container>/<blob>`) and fails unless the response status is
+    /// a success.
+    async fn send(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let resp = self.send_unchecked(method.clone(), path, query, headers, body).await?;
+        check_status(resp, &method, path).await
+    }
+
+    async fn send_unchecked(
+        &self,
+        method: Method,
+        path: &str,
+        query: &[(&str, &str)],
+        headers: Vec<(String, String)>,
+        body: Vec<u8>,
+    ) -> Result<reqwest::Response> {
+        let mut url = Url::parse(&format!(
+            "{}/{}",
+            self.endpoint,
+            utf8_percent_encode(path, PATH_ENCODE_SET)
+        ))
+        .chain_err(|| format!("Invalid blob path {path}"))?;
+        if let Credentials::Sas(sas_token) = &self.credentials {
+            url.set_query(Some(sas_token));
+        }
+        for (name, value) in query {
+            url.query_pairs_mut().append_pair(name, value);
+        }
+
+        let mut headers = headers;
+        headers.push(("x-ms-date".to_string(), httpdate::fmt_http_date(SystemTime::now())));
+        headers.push(("x-ms-version".to_string(), API_VERSION.to_string()));
+        if let Credentials::SharedKey(key) = &self.credentials {
+            let signature = sign(
+                key,
+                &string_to_sign(&method, &self.account, &url, query, &headers, body.len()),
+            );
+            headers.push((
+                "Authorization".to_string(),
+                format!("SharedKey {}:{signature}", self.account),
+            ));
+        }
+
+        let mut request = self.http.request(method.clone(), url);
+        for (name, value) in headers {
+            request = request.header(name, value);
+        }
+        if !body.is_empty() || method == Method::PUT {
+            request = request.body(body);
+        }
+        request.send().await.chain_err(|| format!("{method} {path} failed"))
+    }
+}
+
+async fn check_status(
+    resp: reqwest::Response,
+    method: &Method,
+    path: &str,
+) -> Result<reqwest::Response> {
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let message = resp.text().await.unwrap_or_default();
+        return Err(Error::from(format!("{method} {path} failed with {status}: {message}")));
+    }
+    Ok(resp)
+}
+
+/// Builds the string to sign for Shared Key authorization, see
+/// https://learn.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key
+fn string_to_sign(
+    method: &Method,
+    account: &str,
+    url: &Url,
+    query: &[(&str, &str)],
+    headers: &[(String, String)],
+    content_length: usize,
+) -> String {
+    let content_length =
+        if content_length == 0 { String::new() } else { content_length.to_string() };
+    let mut ms_headers: Vec<(String, &str)> = headers
+        .iter()
+        .filter(|(name, _)| name.to_lowercase().starts_with("x-ms-"))
+        .map(|(name, value)| (name.to_lowercase(), value.trim()))
+        .collect();
+    ms_headers.sort();
+    let mut params: Vec<(String, &str)> =
+        query.iter().map(|(name, value)| (name.to_lowercase(), *value)).collect();
+    params.sort();
+
+    let mut s = format!("{method}\n\n\n{content_length}\n\n\n\n\n\n\n\n\n");
+    for (name, value) in ms_headers {
+        s.push_str(&format!("{name}:{value}\n"));
+    }
+    s.push_str(&format!("/{account}{}", url.path()));
+    for (name, value) in params {
+        s.push_str(&format!("\n{name}:{value}"));
+    }
+    s
+}
+
+fn sign(key: &[u8], string_to_sign: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any size");
+    mac.update(string_to_sign.as_bytes());
+    base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use aws_sdk_s3::types::StorageClass;
+    use futures::TryStreamExt;
+    use reqwest::Method;
+    use url::Url;
+
+    use crate::azure_blob::{
+        access_tier_for, parse_blob_list, string_to_sign, AzureBlobDestination, AzureOptions,
+    };
+    use crate::destination::{Destination, ObjectInfo};
+
+    #[test]
+    fn string_to_sign_contains_canonicalized_headers_and_resource() {
+        let url = Url::parse(
+            "http://127.0.0.1:10000/devstoreaccount1/backups/a%20b.txt?comp=block&blockid=MDA%3D",
+        )
+        .unwrap();
+        let headers = vec![
+            ("x-ms-version".to_string(), "2021-12-02".to_string()),
+            ("x-ms-date".to_string(), "Fri, 04 Nov 2022 10:00:00 GMT".to_string()),
+        ];
+
+        assert_eq!(
+            string_to_sign(
+                &Method::PUT,
+                "devstoreaccount1",
+                &url,
+                &[("comp", "block"), ("blockid", "MDA=")],
+                &headers,
+                5
+            ),
+            "PUT\n\n\n5\n\n\n\n\n\n\n\n\n\
+             x-ms-date:Fri, 04 Nov 2022 10:00:00 GMT\nx-ms-version:2021-12-02\n\
+             /devstoreaccount1/devstoreaccount1/backups/a%20b.txt\nblockid:MDA=\ncomp:block"
+        );
+    }
+
+    #[test]
+    fn blob_list_is_parsed_with_next_marker() {
+        let xml = r#"<?xml version="1.0" encoding="utf-8"?>
+            <EnumerationResults ServiceEndpoint="http://127.0.0.1:10000/devstoreaccount1" ContainerName="backups">
+              <Prefix>photos/</Prefix>
+              <Blobs>
+                <Blob><Name>photos/a &amp; b.jpg</Name><Properties><Content-Length>1234</Content-Length><BlobType>BlockBlob</BlobType></Properties></Blob>
+                <Blob><Name>photos/c.jpg</Name><Properties><Content-Length>0</Content-Length></Properties><Metadata /></Blob>
+              </Blobs>
+              <NextMarker>2!100!MDAwMDE2</NextMarker>
+            </EnumerationResults>"#;
+
+        let (blobs, next_marker) = parse_blob_list(xml).unwrap();
+
+        assert_eq!(
+            blobs.iter().map(|b| (b.name.as_str(), b.size)).collect::<Vec<_>>(),
+            vec![("photos/a & b.jpg", 1234), ("photos/c.jpg", 0)]
+        );
+        assert_eq!(next_marker.as_deref(), Some("2!100!MDAwMDE2"));
+        assert_eq!(
+            parse_blob_list("<EnumerationResults><NextMarker /></EnumerationResults>").unwrap().1,
+            None
+        );
+    }
+
+    #[test]
+    fn storage_classes_map_to_access_tiers() {
+        assert_eq!(access_tier_for(&StorageClass::Standard), Some("Hot"));
+        assert_eq!(access_tier_for(&StorageClass::StandardIa), Some("Cool"));
+        assert_eq!(access_tier_for(&StorageClass::Glacier), Some("Cold"));
+        assert_eq!(access_tier_for(&StorageClass::DeepArchive), Some("Archive"));
+        assert_eq!(access_tier_for(&StorageClass::IntelligentTiering), None);
+    }
+
+    /// Runs against Azurite, started with `./scripts/start-azurite.sh`.
+    #[tokio::test]
+    #[ignore]
+    async fn objects_can_be_written_read_listed_and_deleted_in_azurite() {
+        let destination = AzureBlobDestination::new(
+            "azblob://g2s3-test/some/folder",
+            &AzureOptions {
+                account: Some("devstoreaccount1".to_string()),
+                key: Some(
+                    "Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw=="
+                        .to_string(),
+                ),
+                sas_token: None,
+                endpoint: Some("http://127.0.0.1:10000/devstoreaccount1".to_string()),
+            },
+        )
+        .unwrap();
+        destination.check_writable().await.unwrap();
+
+        let metadata = HashMap::from([("g2s3-compression".to_string(), "zstd".to_string())]);
+        let mut writer = destination
+            .create("a b.txt", &StorageClass::StandardIa, metadata.clone())
+            .await
+            .unwrap();
+        writer.write(b"hello world").await.unwrap();
+        writer.finish().await.unwrap();
+
+        assert!(destination
+            .list()
+            .await
+            .unwrap()
+            .contains(&ObjectInfo { name: "a b.txt".to_string(), size: 11 }));
+        assert_eq!(destination.metadata("a b.txt").await.unwrap(), Some(metadata.clone()));
+        let reader = destination.read("a b.txt").await.unwrap();
+        assert_eq!(reader.metadata, metadata);
+        let content: Vec<Vec<u8>> = reader.content.try_collect().await.unwrap();
+        assert_eq!(content.concat(), b"hello world");
+
+        destination.delete("a b.txt").await.unwrap();
+        assert_eq!(destination.metadata("a b.txt").await.unwrap(), None);
+    }
+}
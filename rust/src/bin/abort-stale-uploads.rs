@@ -0,0 +1,23 @@
+extern crate core;
+
+use clap::Parser;
+
+use google_backup_to_s3::cli::{run, AbortStaleUploadsArgs};
+use google_backup_to_s3::cli_factories::GlobalArgs;
+
+/// Aborts incomplete multipart uploads in S3. Same as `g2s3 abort-stale-uploads`.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(flatten)]
+    global: GlobalArgs,
+
+    #[command(flatten)]
+    command: AbortStaleUploadsArgs,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    run(&args.global, args.command.run(&args.global)).await
+}
@@ -0,0 +1,23 @@
+extern crate core;
+
+use clap::Parser;
+
+use google_backup_to_s3::cli::{run, ContactsArgs};
+use google_backup_to_s3::cli_factories::GlobalArgs;
+
+/// Exports all Google contacts as vCard and JSON. Same as `g2s3 backup contacts`.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(flatten)]
+    global: GlobalArgs,
+
+    #[command(flatten)]
+    command: ContactsArgs,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    run(&args.global, args.command.run(&args.global)).await
+}
@@ -0,0 +1,23 @@
+extern crate core;
+
+use clap::Parser;
+
+use google_backup_to_s3::cli::{run, PhotosArgs};
+use google_backup_to_s3::cli_factories::GlobalArgs;
+
+/// Backs up the Google Photos media items selected in the browser. Same as `g2s3 backup photos`.
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+struct Args {
+    #[command(flatten)]
+    global: GlobalArgs,
+
+    #[command(flatten)]
+    command: PhotosArgs,
+}
+
+#[tokio::main]
+async fn main() {
+    let args = Args::parse();
+    run(&args.global, args.command.run(&args.global)).await
+}
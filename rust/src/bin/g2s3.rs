@@ -0,0 +1,10 @@
+extern crate core;
+
+use clap::Parser;
+
+use google_backup_to_s3::cli::Cli;
+
+#[tokio::main]
+async fn main() {
+    Cli::parse().run().await
+}
@@ -0,0 +1,830 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+
+use chrono::{
+    DateTime, Datelike, Duration, FixedOffset, Months, NaiveDate, NaiveDateTime, Offset, TimeZone,
+    Utc,
+};
+use chrono_tz::{OffsetComponents, OffsetName, Tz, TzOffset};
+use futures::stream;
+use google_drive3::api::File;
+use google_drive3::oauth2::authorized_user::AuthorizedUserSecret;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use crate::destination::Destination;
+use crate::errors::{Error, Result, ResultExt};
+use crate::google_api::GoogleApi;
+use crate::sync_state::{read_states, write_state};
+use crate::{
+    check_destinations, combine_results, copy_file, upload_manifests, BackupOptions, Content,
+};
+
+const API: &str = "https://www.googleapis.com/calendar/v3";
+
+const SCOPE: &str = "https://www.googleapis.com/auth/calendar.readonly";
+
+/// The sync tokens incremental runs continue from, stored in every destination.
+pub const STATE_NAME: &str = "g2s3-calendar-state.json";
+
+/// Client for the Google Calendar API.
+pub struct Calendar {
+    api: GoogleApi,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct CalendarListEntry {
+    pub id: String,
+    #[serde(default)]
+    pub summary: String,
+    pub time_zone: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CalendarList {
+    #[serde(default)]
+    items: Vec<CalendarListEntry>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Event {
+    pub id: String,
+    #[serde(default)]
+    pub status: String,
+    pub i_cal_u_i_d: Option<String>,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    pub location: Option<String>,
+    pub html_link: Option<String>,
+    pub created: Option<String>,
+    pub updated: Option<String>,
+    pub sequence: Option<u64>,
+    pub transparency: Option<String>,
+    pub start: Option<EventTime>,
+    pub end: Option<EventTime>,
+    /// RRULE, EXRULE, RDATE and EXDATE lines of a recurring event.
+    #[serde(default)]
+    pub recurrence: Vec<String>,
+    /// Set on exceptions of a recurring event.
+    pub original_start_time: Option<EventTime>,
+    pub organizer: Option<Person>,
+    #[serde(default)]
+    pub attendees: Vec<Person>,
+    #[serde(default)]
+    pub attachments: Vec<Attachment>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct EventTime {
+    /// Set for all-day events.
+    pub date: Option<String>,
+    pub date_time: Option<String>,
+    pub time_zone: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Person {
+    pub email: Option<String>,
+    pub display_name: Option<String>,
+    pub response_status: Option<String>,
+    #[serde(default)]
+    pub optional: bool,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Attachment {
+    pub file_url: String,
+    pub title: Option<String>,
+    pub mime_type: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct EventList {
+    #[serde(default)]
+    items: Vec<Event>,
+    next_page_token: Option<String>,
+    next_sync_token: Option<String>,
+}
+
+/// Sync token per calendar ID.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct CalendarState {
+    pub sync_tokens: HashMap<String, String>,
+}
+
+impl Calendar {
+    pub async fn new(aus: AuthorizedUserSecret) -> Result<Calendar> {
+        Ok(Calendar { api: GoogleApi::new(aus, &[SCOPE]).await? })
+    }
+
+    pub async fn list_calendars(&self) -> Result<Vec<CalendarListEntry>> {
+        let mut calendars = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut request = self.api.http().get(format!("{API}/users/me/calendarList"));
+            if let Some(page_token) = page_token.as_ref() {
+                request = request.query(&[("pageToken", page_token)]);
+            }
+            let list: CalendarList =
+                self.api.send_json(request).await.chain_err(|| "Could not list calendars")?;
+            calendars.extend(list.items);
+            match list.next_page_token {
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => break,
+            }
+        }
+        Ok(calendars)
+    }
+
+    /// Lists the events of a calendar, or only those changed since `sync_token`, with the sync
+    /// token for the next run. Returns `None` if the sync token has expired.
+    pub async fn list_events(
+        &self,
+        calendar_id: &str,
+        sync_token: Option<&str>,
+    ) -> Result<Option<(Vec<Event>, Option<String>)>> {
+        let url = format!(
+            "{API}/calendars/{}/events",
+            utf8_percent_encode(calendar_id, NON_ALPHANUMERIC)
+        );
+        let mut events = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut request = self.api.http().get(&url).query(&[("maxResults", "2500")]);
+            if let Some(sync_token) = sync_token {
+                request = request.query(&[("syncToken", sync_token)]);
+            }
+            if let Some(page_token) = page_token.as_ref() {
+                request = request.query(&[("pageToken", page_token)]);
+            }
+            let resp = self
+                .api
+                .send(request)
+                .await
+                .chain_err(|| format!("Could not list events of {calendar_id}"))?;
+            if resp.status() == StatusCode::GONE {
+                return Ok(None);
+            }
+            if !resp.status().is_success() {
+                return Err(Error::from(format!(
+                    "Could not list events of {calendar_id}: {}",
+                    resp.status()
+                )));
+            }
+            let body = resp.bytes().await.chain_err(|| "Could not list events")?;
+            let list: EventList = serde_json::from_slice(&body).chain_err(|| "Invalid events")?;
+            events.extend(list.items);
+            match list.next_page_token {
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => return Ok(Some((events, list.next_sync_token))),
+            }
+        }
+    }
+}
+
+/// Exports every calendar the account can see into one `.ics` object per calendar. With
+/// `incremental`, only events changed since the previous run are exported, into objects named
+/// after the time of the run, so earlier exports are kept.
+pub async fn back_up_calendars(
+    calendar: Arc<Calendar>,
+    destinations: Vec<Arc<dyn Destination>>,
+    incremental: bool,
+    options: &BackupOptions,
+) -> Result<()> {
+    check_destinations(&destinations).await?;
+
+    let previous = match incremental {
+        true => previous_state(&destinations).await?,
+        false => None,
+    };
+    let now = Utc::now();
+    let mut state = CalendarState::default();
+    let (tx, rx) = mpsc::unbounded_channel();
+    for entry in calendar.list_calendars().await? {
+        let sync_token = previous.as_ref().and_then(|s| s.sync_tokens.get(&entry.id));
+        let listed = match calendar.list_events(&entry.id, sync_token.map(String::as_str)).await {
+            Ok(Some(listed)) => Ok((listed, sync_token.is_some())),
+            Ok(None) => {
+                log::warn!("Sync token of {} expired, exporting all events", entry.summary);
+                calendar.list_events(&entry.id, None).await.and_then(|listed| {
+                    listed
+                        .map(|listed| (listed, false))
+                        .ok_or_else(|| Error::from("Sync token expired without one"))
+                })
+            }
+            Err(e) => Err(e),
+        };
+        let ((events, next_sync_token), changes_only) = match listed {
+            Ok(listed) => listed,
+            Err(e) => {
+                for i in 0..destinations.len() {
+                    let error = Error::from(format!("Could not export {}: {e}", entry.summary));
+                    tx.send((i, Err(error))).unwrap();
+                }
+                continue;
+            }
+        };
+        if let Some(next_sync_token) = next_sync_token {
+            state.sync_tokens.insert(entry.id.clone(), next_sync_token);
+        }
+
+        let name = object_name(&entry);
+        let key = match changes_only {
+            true => format!("calendars/{name}-changes-{}.ics", now.format("%Y%m%dT%H%M%SZ")),
+            false => format!("calendars/{name}.ics"),
+        };
+        log::info!("Exporting {} events of {} to {key}", events.len(), entry.summary);
+        let ics = to_icalendar(&entry, &events, now).into_bytes();
+        let file = File {
+            id: Some(entry.id.clone()),
+            name: Some(format!("{name}.ics")),
+            mime_type: Some("text/calendar".to_string()),
+            size: Some(ics.len().to_string()),
+            modified_time: Some(now.to_rfc3339()),
+            ..Default::default()
+        };
+        let content: Content = Box::pin(stream::iter([Ok(ics)]));
+        let results =
+            copy_file(&destinations, &file, key, "text/calendar", async { Ok(content) }, options)
+                .await;
+        for (i, result) in results.into_iter().enumerate() {
+            tx.send((i, result)).unwrap();
+        }
+    }
+
+    let mut results = upload_manifests(&destinations, rx, options).await;
+    for (destination, result) in destinations.iter().zip(results.iter_mut()) {
+        if result.is_ok() {
+            *result = write_state(destination.as_ref(), STATE_NAME, &state).await;
+        }
+    }
+    combine_results(&destinations, results)
+}
+
+/// The calendar name with a hash of its ID, since several calendars can have the same name.
+fn object_name(entry: &CalendarListEntry) -> String {
+    let suffix = &hex::encode(Sha256::digest(entry.id.as_bytes()))[..8];
+    format!("{}-{suffix}", entry.summary.replace('/', "_"))
+}
+
+/// The state of the previous run, if all destinations have the same one.
+async fn previous_state(destinations: &[Arc<dyn Destination>]) -> Result<Option<CalendarState>> {
+    let states: Vec<Option<CalendarState>> = read_states(destinations, STATE_NAME).await?;
+    match states.first() {
+        Some(Some(first)) if states.iter().all(|s| s.as_ref() == Some(first)) => {
+            Ok(Some(first.clone()))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// Formats the events of a calendar as an iCalendar (RFC 5545) document.
+fn to_icalendar(entry: &CalendarListEntry, events: &[Event], now: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//g2s3//Google Calendar backup//EN".to_string(),
+        format!("X-WR-CALNAME:{}", escape(&entry.summary)),
+    ];
+    if let Some(time_zone) = entry.time_zone.as_ref() {
+        lines.push(format!("X-WR-TIMEZONE:{time_zone}"));
+    }
+    let calendar_zone = entry.time_zone.as_deref().and_then(|z| z.parse::<Tz>().ok());
+    let mut zones = Zones::new();
+    let events: Vec<String> =
+        events.iter().flat_map(|e| event_lines(e, calendar_zone, &mut zones, now)).collect();
+    for (zone, first_year) in zones.values() {
+        lines.extend(vtimezone(*zone, *first_year, now.year()));
+    }
+    lines.extend(events);
+    lines.push("END:VCALENDAR".to_string());
+    lines.iter().map(|l| fold(l)).collect()
+}
+
+/// Date-times are kept in the time zone of the event, or else of the calendar, and every zone they
+/// use is added to `zones`.
+fn event_lines(
+    event: &Event,
+    calendar_zone: Option<Tz>,
+    zones: &mut Zones,
+    now: DateTime<Utc>,
+) -> Vec<String> {
+    let mut lines = vec!["BEGIN:VEVENT".to_string()];
+    let uid = event.i_cal_u_i_d.as_deref().unwrap_or(&event.id);
+    lines.push(format!("UID:{}", escape(uid)));
+    let stamp = event.updated.as_deref().and_then(utc_time);
+    lines.push(format!(
+        "DTSTAMP:{}",
+        stamp.clone().unwrap_or_else(|| now.format("%Y%m%dT%H%M%SZ").to_string())
+    ));
+    let zone = |time: &EventTime| {
+        time.time_zone.as_deref().and_then(|z| z.parse::<Tz>().ok()).or(calendar_zone)
+    };
+    if let Some(start) = event.start.as_ref() {
+        lines.push(time_line("DTSTART", start, zone(start), zones));
+    }
+    if let Some(end) = event.end.as_ref() {
+        lines.push(time_line("DTEND", end, zone(end), zones));
+    }
+    if let Some(original_start_time) = event.original_start_time.as_ref() {
+        lines.push(time_line(
+            "RECURRENCE-ID",
+            original_start_time,
+            zone(original_start_time),
+            zones,
+        ));
+    }
+    // Recurrences expand in the zone of the start, so they keep their local time across DST changes.
+    let start_zone = event.start.as_ref().and_then(zone);
+    for line in &event.recurrence {
+        lines.push(recurrence_line(line, start_zone, zones));
+    }
+    let status = match event.status.as_str() {
+        "confirmed" => Some("CONFIRMED"),
+        "tentative" => Some("TENTATIVE"),
+        "cancelled" => Some("CANCELLED"),
+        _ => None,
+    };
+    if let Some(status) = status {
+        lines.push(format!("STATUS:{status}"));
+    }
+    for (name, value) in [
+        ("SUMMARY", &event.summary),
+        ("DESCRIPTION", &event.description),
+        ("LOCATION", &event.location),
+    ] {
+        if let Some(value) = value {
+            lines.push(format!("{name}:{}", escape(value)));
+        }
+    }
+    if let Some(html_link) = event.html_link.as_ref() {
+        lines.push(format!("URL:{html_link}"));
+    }
+    if let Some(created) = event.created.as_deref().and_then(utc_time) {
+        lines.push(format!("CREATED:{created}"));
+    }
+    if let Some(updated) = stamp {
+        lines.push(format!("LAST-MODIFIED:{updated}"));
+    }
+    if let Some(sequence) = event.sequence {
+        lines.push(format!("SEQUENCE:{sequence}"));
+    }
+    if event.transparency.as_deref() == Some("transparent") {
+        lines.push("TRANSP:TRANSPARENT".to_string());
+    }
+    if let Some(organizer) = event.organizer.as_ref().filter(|o| o.email.is_some()) {
+        lines.push(format!("ORGANIZER{}", person_value(organizer)));
+    }
+    for attendee in event.attendees.iter().filter(|a| a.email.is_some()) {
+        let partstat = match attendee.response_status.as_deref() {
+            Some("accepted") => "ACCEPTED",
+            Some("declined") => "DECLINED",
+            Some("tentative") => "TENTATIVE",
+            _ => "NEEDS-ACTION",
+        };
+        let role = if attendee.optional { "OPT-PARTICIPANT" } else { "REQ-PARTICIPANT" };
+        lines.push(format!("ATTENDEE;ROLE={role};PARTSTAT={partstat}{}", person_value(attendee)));
+    }
+    for attachment in &event.attachments {
+        let mut line = "ATTACH".to_string();
+        if let Some(mime_type) = attachment.mime_type.as_ref() {
+            line.push_str(&format!(";FMTTYPE={mime_type}"));
+        }
+        if let Some(title) = attachment.title.as_ref() {
+            line.push_str(&format!(";X-FILENAME={}", quote(title)));
+        }
+        line.push_str(&format!(":{}", attachment.file_url));
+        lines.push(line);
+    }
+    lines.push("END:VEVENT".to_string());
+    lines
+}
+
+/// `;CN=<name>:mailto:<email>` for an organizer or attendee.
+fn person_value(person: &Person) -> String {
+    let name = match person.display_name.as_ref() {
+        Some(name) => format!(";CN={}", quote(name)),
+        None => String::new(),
+    };
+    format!("{name}:mailto:{}", person.email.as_deref().unwrap_or_default())
+}
+
+/// A date or date-time property. Date-times are given in `zone` with its TZID, or in UTC if the zone
+/// is unknown.
+fn time_line(name: &str, time: &EventTime, zone: Option<Tz>, zones: &mut Zones) -> String {
+    if let Some(date) = time.date.as_ref() {
+        return format!("{name};VALUE=DATE:{}", date.replace('-', ""));
+    }
+    let date_time = time.date_time.as_deref().unwrap_or_default();
+    match (DateTime::parse_from_rfc3339(date_time), zone) {
+        (Ok(time), Some(zone)) => {
+            let local = time.with_timezone(&zone);
+            use_zone(zones, zone, local.year());
+            format!("{name};TZID={}:{}", zone.name(), local.format("%Y%m%dT%H%M%S"))
+        }
+        (Ok(time), None) => format!("{name}:{}", time.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ")),
+        (Err(_), _) => format!("{name}:{date_time}"),
+    }
+}
+
+/// An RRULE, EXRULE, RDATE or EXDATE line of an event starting in `zone`. Google keeps them as they
+/// were given, so dates with a TZID get its VTIMEZONE, and floating dates, which would be read in
+/// the zone of whoever opens the file, are tied to the zone of the start.
+fn recurrence_line(line: &str, zone: Option<Tz>, zones: &mut Zones) -> String {
+    let Some((name, values)) = line.split_once(':') else {
+        return line.to_string();
+    };
+    if !name.starts_with("RDATE") && !name.starts_with("EXDATE") {
+        return line.to_string();
+    }
+    let year = values.get(..4).and_then(|y| y.parse().ok());
+    if let Some(tzid) = name.split(';').find_map(|p| p.strip_prefix("TZID=")) {
+        if let (Ok(tz), Some(year)) = (tzid.parse::<Tz>(), year) {
+            use_zone(zones, tz, year);
+        }
+        return line.to_string();
+    }
+    match (zone, year) {
+        (Some(zone), Some(year))
+            if !name.contains("VALUE=") && values.split(',').all(|v| !v.ends_with('Z')) =>
+        {
+            use_zone(zones, zone, year);
+            format!("{name};TZID={}:{values}", zone.name())
+        }
+        _ => line.to_string(),
+    }
+}
+
+/// The time zones used in a calendar by TZID, with the first year they are used in.
+type Zones = BTreeMap<String, (Tz, i32)>;
+
+fn use_zone(zones: &mut Zones, zone: Tz, year: i32) {
+    let (_, first_year) = zones.entry(zone.name().to_string()).or_insert((zone, year));
+    *first_year = (*first_year).min(year);
+}
+
+/// The VTIMEZONE definition of `zone`, with its offset changes from the year before `first_year` up
+/// to `this_year`. The changes of the last year repeat yearly if the year before had the same ones,
+/// so recurring events keep the right offsets beyond it.
+fn vtimezone(zone: Tz, first_year: i32, this_year: i32) -> Vec<String> {
+    let last_year = this_year.max(first_year);
+    let changes = offset_changes(zone, first_year - 1, last_year);
+    let mut lines = vec!["BEGIN:VTIMEZONE".to_string(), format!("TZID:{}", zone.name())];
+    if changes.is_empty() {
+        let start = NaiveDate::from_ymd_opt(last_year, 1, 1).unwrap().and_hms_opt(0, 0, 0).unwrap();
+        let offset = zone.offset_from_utc_datetime(&start);
+        lines.extend(observance("19700101T000000", offset.fix(), &offset, None));
+    }
+    for change in &changes {
+        let repeats = change.local.year() == last_year
+            && changes.iter().any(|c| {
+                c.local.year() == last_year - 1
+                    && c.local.month() == change.local.month()
+                    && c.from == change.from
+                    && c.to.fix() == change.to.fix()
+            });
+        let local = change.local.format("%Y%m%dT%H%M%S").to_string();
+        let rule = repeats.then(|| yearly_rule(change.local.date()));
+        lines.extend(observance(&local, change.from, &change.to, rule));
+    }
+    lines.push("END:VTIMEZONE".to_string());
+    lines
+}
+
+/// A STANDARD or DAYLIGHT component, changing from offset `from` to `to` at the local time `start`.
+fn observance(start: &str, from: FixedOffset, to: &TzOffset, rule: Option<String>) -> Vec<String> {
+    let kind = if to.dst_offset().is_zero() { "STANDARD" } else { "DAYLIGHT" };
+    let mut lines = vec![
+        format!("BEGIN:{kind}"),
+        format!("DTSTART:{start}"),
+        format!("TZOFFSETFROM:{}", utc_offset(from)),
+        format!("TZOFFSETTO:{}", utc_offset(to.fix())),
+    ];
+    if let Some(abbreviation) = to.abbreviation() {
+        lines.push(format!("TZNAME:{abbreviation}"));
+    }
+    lines.extend(rule);
+    lines.push(format!("END:{kind}"));
+    lines
+}
+
+struct OffsetChange {
+    /// The local time the change happens at, in the offset before it.
+    local: NaiveDateTime,
+    from: FixedOffset,
+    to: TzOffset,
+}
+
+/// The changes of the UTC offset of `zone` from the start of `from_year` to the end of `to_year`.
+fn offset_changes(zone: Tz, from_year: i32, to_year: i32) -> Vec<OffsetChange> {
+    let offset_at = |utc: NaiveDateTime| zone.offset_from_utc_datetime(&utc).fix();
+    let year_start = |year| NaiveDate::from_ymd_opt(year, 1, 1).unwrap().and_hms_opt(0, 0, 0);
+    let (mut day, end) = (year_start(from_year).unwrap(), year_start(to_year + 1).unwrap());
+    let mut changes = vec![];
+    while day < end {
+        let next_day = day + Duration::days(1);
+        if offset_at(day) != offset_at(next_day) {
+            // Narrow the change down to the second it happens at.
+            let (mut before, mut after) = (day, next_day);
+            while (after - before).num_seconds() > 1 {
+                let middle = before + Duration::seconds((after - before).num_seconds() / 2);
+                if offset_at(middle) == offset_at(before) {
+                    before = middle;
+                } else {
+                    after = middle;
+                }
+            }
+            let from = offset_at(before);
+            changes.push(OffsetChange {
+                local: after + Duration::seconds(from.local_minus_utc().into()),
+                from,
+                to: zone.offset_from_utc_datetime(&after),
+            });
+        }
+        day = next_day;
+    }
+    changes
+}
+
+/// An RRULE for the same weekday of the month every year, e.g. the last Sunday of March.
+fn yearly_rule(date: NaiveDate) -> String {
+    let weekday = date.weekday().to_string().to_uppercase();
+    let days_in_month = NaiveDate::from_ymd_opt(date.year(), date.month(), 1)
+        .unwrap()
+        .checked_add_months(Months::new(1))
+        .unwrap()
+        .pred_opt()
+        .unwrap()
+        .day();
+    let week = if date.day() + 7 > days_in_month {
+        "-1".to_string()
+    } else {
+        ((date.day() - 1) / 7 + 1).to_string()
+    };
+    format!("RRULE:FREQ=YEARLY;BYMONTH={};BYDAY={week}{}", date.month(), &weekday[..2])
+}
+
+/// `+HHMM`, or `+HHMMSS` for offsets with seconds.
+fn utc_offset(offset: FixedOffset) -> String {
+    let seconds = offset.local_minus_utc();
+    let sign = if seconds < 0 { '-' } else { '+' };
+    let seconds = seconds.unsigned_abs();
+    let hhmm = format!("{sign}{:02}{:02}", seconds / 3600, seconds / 60 % 60);
+    match seconds % 60 {
+        0 => hhmm,
+        s => format!("{hhmm}{s:02}"),
+    }
+}
+
+fn utc_time(rfc3339: &str) -> Option<String> {
+    DateTime::parse_from_rfc3339(rfc3339)
+        .ok()
+        .map(|t| t.with_timezone(&Utc).format("%Y%m%dT%H%M%SZ").to_string())
+}
+
+/// Escapes a TEXT value. vCard uses the same escaping.
+pub(crate) fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// Quotes a parameter value, which cannot contain double quotes.
+fn quote(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "'"))
+}
+
+/// Folds a content line into lines of at most 75 octets, without splitting UTF-8 characters, and
+/// terminates it with CRLF. vCard lines are folded the same way.
+pub(crate) fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            len = 1;
+        }
+        folded.push(c);
+        len += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::calendar::{
+        fold, object_name, to_icalendar, Attachment, CalendarListEntry, Event, EventTime, Person,
+    };
+
+    #[test]
+    fn events_are_exported_with_recurrence_attendees_and_attachments() {
+        let entry = CalendarListEntry {
+            id: "team@example.com".to_string(),
+            summary: "Team".to_string(),
+            time_zone: Some("Europe/Berlin".to_string()),
+        };
+        let events = vec![
+            Event {
+                id: "abc".to_string(),
+                status: "confirmed".to_string(),
+                i_cal_u_i_d: Some("abc@google.com".to_string()),
+                summary: Some("Standup; daily, short".to_string()),
+                updated: Some("2024-05-01T08:00:00.000Z".to_string()),
+                start: Some(EventTime {
+                    date_time: Some("2024-05-01T10:00:00+02:00".to_string()),
+                    time_zone: Some("Europe/Berlin".to_string()),
+                    ..Default::default()
+                }),
+                recurrence: vec!["RRULE:FREQ=DAILY;BYDAY=MO,TU,WE,TH,FR".to_string()],
+                attendees: vec![Person {
+                    email: Some("ann@example.com".to_string()),
+                    display_name: Some("Ann".to_string()),
+                    response_status: Some("accepted".to_string()),
+                    optional: true,
+                }],
+                attachments: vec![Attachment {
+                    file_url: "https://drive.google.com/open?id=1".to_string(),
+                    title: Some("Notes".to_string()),
+                    mime_type: Some("application/vnd.google-apps.document".to_string()),
+                }],
+                ..Default::default()
+            },
+            Event {
+                id: "abc_20240502T080000Z".to_string(),
+                status: "cancelled".to_string(),
+                i_cal_u_i_d: Some("abc@google.com".to_string()),
+                original_start_time: Some(EventTime {
+                    date_time: Some("2024-05-02T08:00:00Z".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+            Event {
+                id: "holiday".to_string(),
+                start: Some(EventTime {
+                    date: Some("2024-05-01".to_string()),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        ];
+
+        let ics = to_icalendar(&entry, &events, Utc.with_ymd_and_hms(2024, 5, 3, 0, 0, 0).unwrap());
+
+        let (head, rest) = ics.split_once("BEGIN:VTIMEZONE\r\nTZID:Europe/Berlin\r\n").unwrap();
+        let (_, events) = rest.split_once("END:VTIMEZONE\r\n").unwrap();
+        assert_eq!(
+            format!("{head}{events}"),
+            "BEGIN:VCALENDAR\r\nVERSION:2.0\r\nPRODID:-//g2s3//Google Calendar backup//EN\r\n\
+             X-WR-CALNAME:Team\r\nX-WR-TIMEZONE:Europe/Berlin\r\n\
+             BEGIN:VEVENT\r\nUID:abc@google.com\r\nDTSTAMP:20240501T080000Z\r\n\
+             DTSTART;TZID=Europe/Berlin:20240501T100000\r\n\
+             RRULE:FREQ=DAILY;BYDAY=MO,TU,WE,TH,FR\r\nSTATUS:CONFIRMED\r\n\
+             SUMMARY:Standup\\; daily\\, short\r\nLAST-MODIFIED:20240501T080000Z\r\n\
+             ATTENDEE;ROLE=OPT-PARTICIPANT;PARTSTAT=ACCEPTED;CN=\"Ann\":mailto:ann@example\r\n\
+             \x20.com\r\n\
+             ATTACH;FMTTYPE=application/vnd.google-apps.document;X-FILENAME=\"Notes\":http\r\n\
+             \x20s://drive.google.com/open?id=1\r\n\
+             END:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:abc@google.com\r\nDTSTAMP:20240503T000000Z\r\n\
+             RECURRENCE-ID;TZID=Europe/Berlin:20240502T100000\r\nSTATUS:CANCELLED\r\nEND:VEVENT\r\n\
+             BEGIN:VEVENT\r\nUID:holiday\r\nDTSTAMP:20240503T000000Z\r\n\
+             DTSTART;VALUE=DATE:20240501\r\nEND:VEVENT\r\n\
+             END:VCALENDAR\r\n"
+        );
+    }
+
+    #[test]
+    fn recurring_events_keep_their_time_zone_across_dst_changes() {
+        let entry = CalendarListEntry {
+            id: "me@example.com".to_string(),
+            summary: "Me".to_string(),
+            time_zone: Some("America/New_York".to_string()),
+        };
+        // Weekly at 09:00 in Berlin from before to after the change to summer time on 2024-03-31.
+        let events = vec![Event {
+            id: "weekly".to_string(),
+            start: Some(EventTime {
+                date_time: Some("2024-03-20T08:00:00Z".to_string()),
+                time_zone: Some("Europe/Berlin".to_string()),
+                ..Default::default()
+            }),
+            end: Some(EventTime {
+                date_time: Some("2024-03-20T10:00:00+01:00".to_string()),
+                time_zone: Some("Europe/Berlin".to_string()),
+                ..Default::default()
+            }),
+            recurrence: vec![
+                "RRULE:FREQ=WEEKLY;UNTIL=20240417T070000Z".to_string(),
+                "EXDATE:20240403T090000".to_string(),
+                "RDATE;TZID=America/New_York:20240420T030000".to_string(),
+            ],
+            ..Default::default()
+        }];
+
+        let ics = to_icalendar(&entry, &events, Utc.with_ymd_and_hms(2024, 5, 3, 0, 0, 0).unwrap());
+
+        let lines: Vec<&str> = ics.split("\r\n").collect();
+        let event = &lines[lines.iter().position(|l| *l == "BEGIN:VEVENT").unwrap()..];
+        assert_eq!(
+            event[3..8],
+            [
+                "DTSTART;TZID=Europe/Berlin:20240320T090000",
+                "DTEND;TZID=Europe/Berlin:20240320T100000",
+                "RRULE:FREQ=WEEKLY;UNTIL=20240417T070000Z",
+                "EXDATE;TZID=Europe/Berlin:20240403T090000",
+                "RDATE;TZID=America/New_York:20240420T030000",
+            ]
+        );
+        // One definition per zone used, whose last changes repeat every year.
+        assert_eq!(lines.iter().filter(|l| l.starts_with("BEGIN:VTIMEZONE")).count(), 2);
+        let berlin = lines.iter().position(|l| *l == "TZID:Europe/Berlin").unwrap();
+        let berlin = &lines
+            [berlin..berlin + lines[berlin..].iter().position(|l| *l == "END:VTIMEZONE").unwrap()];
+        assert!(berlin.windows(6).any(|w| w
+            == [
+                "BEGIN:DAYLIGHT",
+                "DTSTART:20240331T020000",
+                "TZOFFSETFROM:+0100",
+                "TZOFFSETTO:+0200",
+                "TZNAME:CEST",
+                "RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=-1SU",
+            ]));
+        assert!(berlin.windows(6).any(|w| w
+            == [
+                "BEGIN:STANDARD",
+                "DTSTART:20241027T030000",
+                "TZOFFSETFROM:+0200",
+                "TZOFFSETTO:+0100",
+                "TZNAME:CET",
+                "RRULE:FREQ=YEARLY;BYMONTH=10;BYDAY=-1SU",
+            ]));
+        // 2023 is there for events early in the year, before the first change of 2024.
+        assert!(berlin.contains(&"DTSTART:20231029T030000"));
+        assert!(lines.contains(&"RRULE:FREQ=YEARLY;BYMONTH=3;BYDAY=2SU"));
+    }
+
+    #[test]
+    fn zones_without_offset_changes_get_a_single_observance() {
+        let entry = CalendarListEntry {
+            id: "me@example.com".to_string(),
+            summary: "Me".to_string(),
+            time_zone: Some("Asia/Kolkata".to_string()),
+        };
+        let events = vec![Event {
+            id: "once".to_string(),
+            start: Some(EventTime {
+                date_time: Some("2024-03-20T08:00:00Z".to_string()),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }];
+
+        let ics = to_icalendar(&entry, &events, Utc.with_ymd_and_hms(2024, 5, 3, 0, 0, 0).unwrap());
+
+        assert!(ics.contains(
+            "BEGIN:VTIMEZONE\r\nTZID:Asia/Kolkata\r\nBEGIN:STANDARD\r\nDTSTART:19700101T000000\r\n\
+             TZOFFSETFROM:+0530\r\nTZOFFSETTO:+0530\r\nTZNAME:IST\r\nEND:STANDARD\r\n\
+             END:VTIMEZONE\r\n"
+        ));
+        assert!(ics.contains("DTSTART;TZID=Asia/Kolkata:20240320T133000\r\n"));
+    }
+
+    #[test]
+    fn calendars_with_the_same_name_get_different_objects() {
+        let calendar = |id: &str| CalendarListEntry {
+            id: id.to_string(),
+            summary: "Family/Kids".to_string(),
+            time_zone: None,
+        };
+
+        let name = object_name(&calendar("a@group.calendar.google.com"));
+        assert!(name.starts_with("Family_Kids-"));
+        assert_eq!(name, object_name(&calendar("a@group.calendar.google.com")));
+        assert_ne!(name, object_name(&calendar("b@group.calendar.google.com")));
+    }
+
+    #[test]
+    fn long_lines_are_folded_between_characters() {
+        let folded = fold(&format!("SUMMARY:{}", "ä".repeat(40)));
+
+        assert!(folded.split("\r\n").all(|l| l.len() <= 75));
+        assert_eq!(folded.replace("\r\n ", ""), format!("SUMMARY:{}\r\n", "ä".repeat(40)));
+    }
+}
@@ -0,0 +1,623 @@
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::{Parser, Subcommand};
+use error_chain::ChainedError;
+use log::{error, info, warn};
+
+use crate::auth::retrieve_tokens;
+use crate::back_up;
+use crate::calendar::{back_up_calendars, Calendar};
+use crate::cli_factories::{
+    authorized_user_secret, backup_options_from, create_encryption_key, create_s3_client,
+    destination_options_from, destinations_from, filter_from, set_up_logging, BackupArgs,
+    FilterArgs, GlobalArgs,
+};
+use crate::config::{BackupDefinition, Config};
+use crate::contacts::{back_up_contacts, People};
+use crate::cron::Cron;
+use crate::daemon::run_daemon;
+use crate::destination::{destination_for, Destination};
+use crate::drive::{create_drive_hub, Drive};
+use crate::errors::{Error, Result, ResultExt};
+use crate::gmail::{back_up_gmail, Gmail, GmailFormat};
+use crate::photos::{back_up_photos, Photos};
+use crate::plan::{plan_back_up, PlanFormat};
+use crate::restore::{restore, RestoreOptions};
+use crate::s3::{abort_stale_multipart_uploads, Encryption, ObjectLock};
+use crate::takeout::{back_up_takeout, TakeoutOptions};
+use crate::verify::verify;
+
+/// Backs up Google Drive, Photos, Gmail, Calendar, Contacts and Takeout exports to S3, Azure Blob
+/// Storage, Google Cloud Storage or local folders.
+#[derive(Parser, Debug)]
+#[command(name = "g2s3", author, version, about, long_about = None)]
+pub struct Cli {
+    #[command(flatten)]
+    pub global: GlobalArgs,
+
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+// Parsed once, so the size of the variants does not matter.
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Back up a Google source
+    #[command(subcommand)]
+    Backup(BackupCommand),
+    /// Move a Google Drive folder to the trash
+    Trash(TrashArgs),
+    /// Retrieve the Google refresh token the backup commands need
+    Auth(AuthArgs),
+    /// Check that a backup has every object its manifest lists
+    Verify(VerifyArgs),
+    /// Restore a backup into a local directory
+    Restore(RestoreArgs),
+    /// List the objects in a backup
+    Ls(LsArgs),
+    /// Abort incomplete multipart uploads in S3
+    AbortStaleUploads(AbortStaleUploadsArgs),
+    /// Run the backup jobs defined in a config file
+    Run(RunArgs),
+    /// Keep running and start the jobs in a config file on their schedules
+    Daemon(DaemonArgs),
+}
+
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+pub enum BackupCommand {
+    /// Back up a Google Drive folder
+    Drive(DriveArgs),
+    /// Back up the Google Photos media items selected in the browser
+    Photos(PhotosArgs),
+    /// Back up the Gmail mailbox
+    Gmail(GmailArgs),
+    /// Export all Google calendars as iCalendar
+    Calendar(CalendarArgs),
+    /// Export all Google contacts as vCard and JSON
+    Contacts(ContactsArgs),
+    /// Copy Google Takeout exports from Drive
+    Takeout(TakeoutArgs),
+}
+
+impl Cli {
+    pub async fn run(&self) {
+        run(&self.global, self.command.run(&self.global)).await
+    }
+}
+
+impl Command {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        match self {
+            Command::Backup(BackupCommand::Drive(args)) => args.run(global).await,
+            Command::Backup(BackupCommand::Photos(args)) => args.run(global).await,
+            Command::Backup(BackupCommand::Gmail(args)) => args.run(global).await,
+            Command::Backup(BackupCommand::Calendar(args)) => args.run(global).await,
+            Command::Backup(BackupCommand::Contacts(args)) => args.run(global).await,
+            Command::Backup(BackupCommand::Takeout(args)) => args.run(global).await,
+            Command::Trash(args) => args.run(global).await,
+            Command::Auth(args) => args.run(global).await,
+            Command::Verify(args) => args.run(global).await,
+            Command::Restore(args) => args.run(global).await,
+            Command::Ls(args) => args.run(global).await,
+            Command::AbortStaleUploads(args) => args.run(global).await,
+            Command::Run(args) => args.run(global).await,
+            Command::Daemon(args) => args.run(global).await,
+        }
+    }
+}
+
+/// Sets up logging, runs `command` and exits with status 1 if it fails.
+pub async fn run(global: &GlobalArgs, command: impl Future<Output = Result<()>>) {
+    set_up_logging(global.log_level);
+    info!("Starting");
+
+    if let Err(ref e) = command.await {
+        error!("{}", e.display_chain());
+        ::std::process::exit(1);
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DriveArgs {
+    #[command(flatten)]
+    pub backup: BackupArgs,
+
+    #[command(flatten)]
+    pub filter: FilterArgs,
+
+    /// The Google Drive folder to back up: its ID, a https://drive.google.com/drive/folders/<id>
+    /// URL, a path like "/Some/Folder" or the name of a folder directly in My Drive
+    #[arg()]
+    pub source: String,
+
+    /// Where to copy the files, in the format s3://bucket-name/some/folder,
+    /// azblob://container/some/folder, gs://bucket-name/some/folder or file:///some/folder
+    /// Can also use {date} which will get substituted by the current date.
+    /// With several destinations, every file is downloaded once and copied to all of them.
+    #[arg(required = true)]
+    pub destinations: Vec<String>,
+
+    /// Only list what would be copied where, without downloading or writing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// How --dry-run prints the plan: table or json
+    #[arg(long, default_value = "table", requires = "dry_run")]
+    pub plan_format: String,
+}
+
+impl DriveArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let options = backup_options_from(&self.backup, global)?;
+        let filter = filter_from(&self.filter)?;
+        let drive = drive_from(global).await?;
+        let destinations = destinations_from(&self.backup, global, &self.destinations).await?;
+        if self.dry_run {
+            let format = self.plan_format.parse::<PlanFormat>()?;
+            let plan = plan_back_up(drive, &destinations, &self.source, &filter, &options).await?;
+            plan.print(format);
+            return Ok(());
+        }
+        back_up(drive, destinations, &self.source, &filter, &options).await
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PhotosArgs {
+    #[command(flatten)]
+    pub backup: BackupArgs,
+
+    /// Where to copy the media items, in the format s3://bucket-name/some/folder,
+    /// azblob://container/some/folder, gs://bucket-name/some/folder or file:///some/folder
+    /// Can also use {date} which will get substituted by the current date.
+    #[arg(required = true)]
+    pub destinations: Vec<String>,
+}
+
+impl PhotosArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let options = backup_options_from(&self.backup, global)?;
+        let photos = Photos::new(google_credentials(global).await?).await?;
+        let destinations = destinations_from(&self.backup, global, &self.destinations).await?;
+        back_up_photos(Arc::new(photos), destinations, &options).await
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct GmailArgs {
+    #[command(flatten)]
+    pub backup: BackupArgs,
+
+    /// How to store messages: eml (one messages/<year>/<month>/<id>.eml object per message) or
+    /// mbox (one mbox/<year>-<month>.mbox archive per month)
+    #[arg(long, default_value = "eml")]
+    pub format: String,
+
+    /// Only copy messages added since the previous run, which stored its Gmail history ID in the
+    /// destinations. Use destinations without {date} for this.
+    #[arg(long)]
+    pub incremental: bool,
+
+    /// Where to copy the messages, in the format s3://bucket-name/some/folder,
+    /// azblob://container/some/folder, gs://bucket-name/some/folder or file:///some/folder
+    /// Can also use {date} which will get substituted by the current date.
+    #[arg(required = true)]
+    pub destinations: Vec<String>,
+}
+
+impl GmailArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let options = backup_options_from(&self.backup, global)?;
+        let format = self.format.parse::<GmailFormat>()?;
+        let gmail = Gmail::new(google_credentials(global).await?).await?;
+        let destinations = destinations_from(&self.backup, global, &self.destinations).await?;
+        back_up_gmail(Arc::new(gmail), destinations, format, self.incremental, &options).await
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct CalendarArgs {
+    #[command(flatten)]
+    pub backup: BackupArgs,
+
+    /// Only export events changed since the previous run, which stored its sync tokens in the
+    /// destinations. Use destinations without {date} for this.
+    #[arg(long)]
+    pub incremental: bool,
+
+    /// Where to copy the calendars, in the format s3://bucket-name/some/folder,
+    /// azblob://container/some/folder, gs://bucket-name/some/folder or file:///some/folder
+    /// Can also use {date} which will get substituted by the current date.
+    #[arg(required = true)]
+    pub destinations: Vec<String>,
+}
+
+impl CalendarArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let options = backup_options_from(&self.backup, global)?;
+        let calendar = Calendar::new(google_credentials(global).await?).await?;
+        let destinations = destinations_from(&self.backup, global, &self.destinations).await?;
+        back_up_calendars(Arc::new(calendar), destinations, self.incremental, &options).await
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct ContactsArgs {
+    #[command(flatten)]
+    pub backup: BackupArgs,
+
+    /// Where to copy the contacts, in the format s3://bucket-name/some/folder,
+    /// azblob://container/some/folder, gs://bucket-name/some/folder or file:///some/folder
+    /// Can also use {date} which will get substituted by the current date.
+    #[arg(required = true)]
+    pub destinations: Vec<String>,
+}
+
+impl ContactsArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let options = backup_options_from(&self.backup, global)?;
+        let people = People::new(google_credentials(global).await?).await?;
+        let destinations = destinations_from(&self.backup, global, &self.destinations).await?;
+        back_up_contacts(Arc::new(people), destinations, &options).await
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TakeoutArgs {
+    #[command(flatten)]
+    pub backup: BackupArgs,
+
+    /// The Google Drive folder Takeout delivers its archives to, usually "Takeout". Can also be an
+    /// ID, a folder URL or a path, like for backup drive.
+    #[arg()]
+    pub source: String,
+
+    /// Store every file in the archives as its own object under takeout/<export name>/ instead of
+    /// the archives themselves
+    #[arg(long)]
+    pub unpack: bool,
+
+    /// Move archives to the Drive trash once they are copied to every destination and match their
+    /// Drive checksum
+    #[arg(long)]
+    pub trash: bool,
+
+    /// Where to copy the archives, in the format s3://bucket-name/some/folder,
+    /// azblob://container/some/folder, gs://bucket-name/some/folder or file:///some/folder
+    /// Can also use {date} which will get substituted by the current date.
+    #[arg(required = true)]
+    pub destinations: Vec<String>,
+}
+
+impl TakeoutArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let options = backup_options_from(&self.backup, global)?;
+        let drive = drive_from(global).await?;
+        let destinations = destinations_from(&self.backup, global, &self.destinations).await?;
+        let takeout = TakeoutOptions { unpack: self.unpack, trash: self.trash };
+        back_up_takeout(drive, destinations, &self.source, &takeout, &options).await
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct TrashArgs {
+    /// The Google Drive folder to move to trash, as an absolute path like "/Some/Folder"
+    #[arg()]
+    pub folder: String,
+}
+
+impl TrashArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let drive = drive_from(global).await?;
+        let folder = drive.get_file_from(Path::new(&self.folder)).await?;
+        drive.trash_file(folder.id.as_ref().unwrap()).await
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AuthArgs {
+    /// The OAuth client credentials downloaded from the GCP console
+    #[arg()]
+    pub client_secret_file: PathBuf,
+
+    /// Where to write the tokens, including the refresh token
+    #[arg()]
+    pub token_file: PathBuf,
+}
+
+impl AuthArgs {
+    pub async fn run(&self, _global: &GlobalArgs) -> Result<()> {
+        let (client_secret_file, token_file) =
+            (self.client_secret_file.clone(), self.token_file.clone());
+        tokio::task::spawn_blocking(move || retrieve_tokens(&client_secret_file, &token_file))
+            .await
+            .chain_err(|| "Could not retrieve tokens")?
+    }
+}
+
+/// Keys to read a backup with.
+#[derive(clap::Args, Debug)]
+pub struct ReadKeyArgs {
+    /// Base64-encoded 256-bit key the objects were uploaded with, if they use SSE-C
+    #[arg(long, env = "SSE_CUSTOMER_KEY", hide_env_values = true)]
+    pub sse_customer_key: Option<String>,
+
+    /// Key file the backup was encrypted with (see back-up-drive-folder --encryption-key-file)
+    #[arg(long, conflicts_with = "encryption_passphrase")]
+    pub encryption_key_file: Option<PathBuf>,
+
+    /// Passphrase the backup was encrypted with
+    #[arg(long, env = "ENCRYPTION_PASSPHRASE", hide_env_values = true)]
+    pub encryption_passphrase: Option<String>,
+}
+
+impl ReadKeyArgs {
+    fn restore_options(&self, global: &GlobalArgs) -> Result<RestoreOptions> {
+        Ok(RestoreOptions {
+            encryption_key: create_encryption_key(
+                self.encryption_key_file.as_deref(),
+                self.encryption_passphrase.as_deref(),
+            )?,
+            concurrency: global.concurrency,
+        })
+    }
+
+    async fn source(&self, url: &str, global: &GlobalArgs) -> Result<Arc<dyn Destination>> {
+        let encryption = Encryption::new(None, None, false, self.sse_customer_key.as_deref())?;
+        destination_for(
+            url,
+            &destination_options_from(global, encryption, ObjectLock::default()).await,
+        )
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    pub keys: ReadKeyArgs,
+
+    /// Also download and decode every object, and compare the sizes of files that were not packed
+    /// with the manifest
+    #[arg(long)]
+    pub deep: bool,
+
+    /// The backup to check, in the format s3://bucket-name/some/folder,
+    /// azblob://container/some/folder, gs://bucket-name/some/folder or file:///some/folder
+    #[arg()]
+    pub backup: String,
+}
+
+impl VerifyArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let options = self.keys.restore_options(global)?;
+        let backup = self.keys.source(&self.backup, global).await?;
+        let report = verify(backup.as_ref(), &options, self.deep).await?;
+        report.print();
+        match report.is_ok() {
+            true => Ok(()),
+            false => Err(Error::from(format!("{} is incomplete", self.backup))),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RestoreArgs {
+    #[command(flatten)]
+    pub keys: ReadKeyArgs,
+
+    /// The backup to restore, in the format s3://bucket-name/some/folder,
+    /// azblob://container/some/folder, gs://bucket-name/some/folder or file:///some/folder
+    #[arg()]
+    pub source: String,
+
+    /// Local directory to restore the files into
+    #[arg()]
+    pub target_dir: PathBuf,
+}
+
+impl RestoreArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let options = self.keys.restore_options(global)?;
+        let source = self.keys.source(&self.source, global).await?;
+        restore(source.as_ref(), &self.target_dir, &options).await
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct LsArgs {
+    /// The backup to list, in the format s3://bucket-name/some/folder,
+    /// azblob://container/some/folder, gs://bucket-name/some/folder or file:///some/folder
+    #[arg()]
+    pub backup: String,
+}
+
+impl LsArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let options =
+            destination_options_from(global, Encryption::default(), ObjectLock::default()).await;
+        let mut objects = destination_for(&self.backup, &options)?.list().await?;
+        objects.sort_by(|a, b| a.name.cmp(&b.name));
+        for object in objects {
+            println!("{:>14} {}", object.size, object.name);
+        }
+        Ok(())
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct AbortStaleUploadsArgs {
+    /// Only abort uploads initiated longer ago than this, e.g. 12h, 7d.
+    #[arg(long, default_value = "24h", value_parser = humantime::parse_duration)]
+    pub older_than: std::time::Duration,
+
+    /// Only report which uploads would be aborted and how many bytes that would reclaim.
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// The backup destination to clean up. This must be in the format s3://bucket-name/some/folder
+    #[arg()]
+    pub destination: String,
+}
+
+impl AbortStaleUploadsArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let s3 = create_s3_client(&global.s3_client).await;
+        abort_stale_multipart_uploads(&s3, &self.destination, self.older_than, self.dry_run)
+            .await?
+            .print();
+        Ok(())
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct RunArgs {
+    /// JSON or TOML file with backup_definitions, in the format of
+    /// cdk/bin/deployment-config.json
+    #[arg(long)]
+    pub config: PathBuf,
+
+    /// Only run the job with this name (or google_drive_folder, if it has no name)
+    #[arg(long)]
+    pub job: Option<String>,
+}
+
+/// The arguments of one job in a config file.
+#[derive(Parser, Debug)]
+#[command(no_binary_name = true)]
+struct JobArgs {
+    #[command(flatten)]
+    drive: DriveArgs,
+}
+
+/// Parses the `g2s3 backup drive` arguments of the jobs in `config`, all or only the one called
+/// `job`, so invalid jobs are reported before any runs.
+fn jobs_from<'a>(
+    config: &'a Config,
+    job: Option<&str>,
+) -> Result<Vec<(&'a BackupDefinition, DriveArgs)>> {
+    config
+        .jobs(job)?
+        .into_iter()
+        .map(|job| {
+            let args = JobArgs::try_parse_from(job.to_args())
+                .chain_err(|| format!("Invalid job {}", job.name()))?;
+            Ok((job, args.drive))
+        })
+        .collect()
+}
+
+impl RunArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let config = Config::load(&self.config)?;
+        let mut failed = vec![];
+        for (job, args) in jobs_from(&config, self.job.as_deref())? {
+            info!("Running job {}", job.name());
+            if let Err(e) = args.run(global).await {
+                error!("Job {} failed: {}", job.name(), e.display_chain());
+                failed.push(job.name());
+            }
+        }
+        match failed.is_empty() {
+            true => Ok(()),
+            false => Err(Error::from(format!("Failed jobs: {}", failed.join(", ")))),
+        }
+    }
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DaemonArgs {
+    /// JSON or TOML file with backup_definitions, in the format of
+    /// cdk/bin/deployment-config.json. Jobs without a schedule are ignored.
+    #[arg(long)]
+    pub config: PathBuf,
+
+    /// Where to keep the time of the last successful run of every job
+    #[arg(long, default_value = "g2s3-daemon-state.json")]
+    pub state_file: PathBuf,
+}
+
+impl DaemonArgs {
+    pub async fn run(&self, global: &GlobalArgs) -> Result<()> {
+        let config = Config::load(&self.config)?;
+        let mut jobs = vec![];
+        for (job, args) in jobs_from(&config, None)? {
+            match &job.schedule {
+                Some(schedule) => {
+                    let cron = Cron::from_schedule(schedule)
+                        .chain_err(|| format!("Invalid schedule of job {}", job.name()))?;
+                    jobs.push((job.name().to_string(), cron, args));
+                }
+                None => warn!("Job {} has no schedule and will not run", job.name()),
+            }
+        }
+        if jobs.is_empty() {
+            return Err(Error::from(format!("{} has no scheduled jobs", self.config.display())));
+        }
+        run_daemon(jobs, global, &self.state_file).await
+    }
+}
+
+async fn google_credentials(
+    global: &GlobalArgs,
+) -> Result<google_drive3::oauth2::authorized_user::AuthorizedUserSecret> {
+    authorized_user_secret(global.google_credentials_file.as_deref()).await
+}
+
+async fn drive_from(global: &GlobalArgs) -> Result<Arc<Drive>> {
+    Ok(Arc::new(Drive::new(create_drive_hub(google_credentials(global).await?).await)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use clap::Parser;
+
+    use crate::cli::{BackupCommand, Cli, Command};
+
+    #[test]
+    fn path_can_be_iterated() {
+        let mut parts = vec![];
+        for part in Path::new("/some//path/in/here/") {
+            parts.push(part);
+        }
+        assert_eq!(parts, vec!["/", "some", "path", "in", "here"]);
+    }
+
+    #[test]
+    fn global_options_can_follow_the_subcommand() {
+        let cli = Cli::try_parse_from([
+            "g2s3",
+            "--log-level",
+            "debug",
+            "backup",
+            "drive",
+            "--compression",
+            "zstd",
+            "My Folder",
+            "s3://bucket/folder",
+            "--region",
+            "eu-west-1",
+            "--concurrency",
+            "8",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.global.log_level, log::LevelFilter::Debug);
+        assert_eq!(cli.global.concurrency, 8);
+        assert_eq!(cli.global.s3_client.s3_region.as_deref(), Some("eu-west-1"));
+        match cli.command {
+            Command::Backup(BackupCommand::Drive(args)) => {
+                assert_eq!(args.source, "My Folder");
+                assert_eq!(args.destinations, vec!["s3://bucket/folder"]);
+                assert_eq!(args.backup.compression.as_deref(), Some("zstd"));
+            }
+            command => panic!("Unexpected command {command:?}"),
+        }
+        assert!(Cli::try_parse_from(["g2s3", "--concurrency", "0", "ls", "file:///x"]).is_err());
+    }
+}
@@ -1,7 +1,43 @@
-use crate::errors::Result;
+use aws_config::meta::region::RegionProviderChain;
+use aws_config::Region;
+use aws_sdk_s3::config::{RequestChecksumCalculation, ResponseChecksumValidation};
+
+use crate::azure_blob::AzureOptions;
+use crate::client_side_encryption::MasterKey;
+use crate::compression::CompressionRules;
+use crate::destination::{destination_for, Destination, DestinationOptions};
+use crate::errors::{Result, ResultExt};
+use crate::filter::{parse_patterns, parse_time, FileFilter};
+use crate::gcs::GcsOptions;
+use crate::packing::PackingOptions;
+use crate::s3::{Encryption, ObjectLock};
+use crate::storage_class::{parse_storage_class, StorageClassRules};
+use crate::BackupOptions;
 use core::result::Result::Ok;
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use yup_oauth2::authorized_user::AuthorizedUserSecret;
+use yup_oauth2::read_authorized_user_secret;
+
+/// The Google credentials from `credentials_file` if given, else from the `CLIENT_ID`,
+/// `CLIENT_SECRET` and `REFRESH_TOKEN` environment variables, or else from
+/// `private/authorized_user_secret.json`. They are shared by all Google sources.
+pub async fn authorized_user_secret(
+    credentials_file: Option<&Path>,
+) -> Result<AuthorizedUserSecret> {
+    if let Some(credentials_file) = credentials_file {
+        return read_authorized_user_secret(credentials_file).await.chain_err(|| {
+            format!("Could not read Google credentials from {}", credentials_file.display())
+        });
+    }
+    match create_aus_from_env_vars() {
+        Ok(authorized_user_secret) => Ok(authorized_user_secret),
+        Err(_) => read_authorized_user_secret("private/authorized_user_secret.json")
+            .await
+            .chain_err(|| "Could not read Google credentials from environment or file"),
+    }
+}
 
 pub fn create_aus_from_env_vars() -> Result<AuthorizedUserSecret> {
     Ok(AuthorizedUserSecret {
@@ -12,7 +48,325 @@ pub fn create_aus_from_env_vars() -> Result<AuthorizedUserSecret> {
     })
 }
 
-pub fn set_up_logging() {
+/// Options shared by all commands. They can be given before or after the subcommand.
+#[derive(clap::Args, Clone, Debug)]
+pub struct GlobalArgs {
+    /// JSON file with the Google credentials (client_id, client_secret and refresh_token).
+    /// Defaults to the CLIENT_ID, CLIENT_SECRET and REFRESH_TOKEN environment variables, or else
+    /// private/authorized_user_secret.json
+    #[arg(long, global = true, env = "GOOGLE_CREDENTIALS_FILE")]
+    pub google_credentials_file: Option<PathBuf>,
+
+    /// Log level: off, error, warn, info, debug or trace
+    #[arg(long, global = true, default_value = "info")]
+    pub log_level: log::LevelFilter,
+
+    /// How many files to copy or restore at the same time
+    #[arg(long, global = true, default_value_t = 4,
+          value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+    pub concurrency: usize,
+
+    #[command(flatten)]
+    pub s3_client: S3ClientArgs,
+
+    #[command(flatten)]
+    pub azure_client: AzureClientArgs,
+
+    #[command(flatten)]
+    pub gcs_client: GcsClientArgs,
+}
+
+/// Options to reach S3-compatible storage like MinIO, Ceph, Wasabi or Backblaze B2 instead of AWS.
+#[derive(clap::Args, Clone, Debug)]
+pub struct S3ClientArgs {
+    /// Endpoint of S3-compatible storage, e.g. http://localhost:9000 for a local MinIO
+    #[arg(long, global = true, env = "S3_ENDPOINT_URL")]
+    pub s3_endpoint_url: Option<String>,
+
+    /// Address buckets as <endpoint>/<bucket> instead of <bucket>.<endpoint>.
+    /// Most S3-compatible storage needs this.
+    #[arg(long, global = true)]
+    pub s3_force_path_style: bool,
+
+    /// Region to use instead of the one configured in the environment
+    #[arg(long, visible_alias = "region", global = true)]
+    pub s3_region: Option<String>,
+}
+
+pub async fn create_s3_client(args: &S3ClientArgs) -> aws_sdk_s3::Client {
+    let region = RegionProviderChain::first_try(args.s3_region.clone().map(Region::new))
+        .or_default_provider();
+    let sdk_config = aws_config::from_env().region(region).load().await;
+    let mut config =
+        aws_sdk_s3::config::Builder::from(&sdk_config).force_path_style(args.s3_force_path_style);
+    if let Some(endpoint_url) = args.s3_endpoint_url.as_ref() {
+        // Many S3-compatible stores do not support the flexible checksums AWS S3 computes by default.
+        config = config
+            .endpoint_url(endpoint_url)
+            .request_checksum_calculation(RequestChecksumCalculation::WhenRequired)
+            .response_checksum_validation(ResponseChecksumValidation::WhenRequired);
+    }
+    aws_sdk_s3::Client::from_conf(config.build())
+}
+
+/// Credentials for azblob:// destinations.
+#[derive(clap::Args, Clone, Debug)]
+pub struct AzureClientArgs {
+    /// Azure storage account of azblob:// destinations
+    #[arg(long, global = true, env = "AZURE_STORAGE_ACCOUNT")]
+    pub azure_storage_account: Option<String>,
+
+    /// Shared key of the Azure storage account
+    #[arg(long, global = true, env = "AZURE_STORAGE_KEY", hide_env_values = true)]
+    pub azure_storage_key: Option<String>,
+
+    /// SAS token to use instead of the shared key
+    #[arg(long, global = true, env = "AZURE_STORAGE_SAS_TOKEN", hide_env_values = true)]
+    pub azure_storage_sas_token: Option<String>,
+
+    /// Blob service endpoint, e.g. http://127.0.0.1:10000/devstoreaccount1 for Azurite.
+    /// Defaults to https://<account>.blob.core.windows.net
+    #[arg(long, global = true, env = "AZURE_STORAGE_ENDPOINT")]
+    pub azure_storage_endpoint: Option<String>,
+}
+
+pub fn create_azure_options(args: &AzureClientArgs) -> AzureOptions {
+    AzureOptions {
+        account: args.azure_storage_account.clone(),
+        key: args.azure_storage_key.clone(),
+        sas_token: args.azure_storage_sas_token.clone(),
+        endpoint: args.azure_storage_endpoint.clone(),
+    }
+}
+
+/// Credentials for gs:// destinations.
+#[derive(clap::Args, Clone, Debug)]
+pub struct GcsClientArgs {
+    /// Service account key file for gs:// destinations
+    #[arg(long, global = true, env = "GOOGLE_APPLICATION_CREDENTIALS")]
+    pub gcs_credentials_file: Option<PathBuf>,
+
+    /// Endpoint of the GCS JSON API, e.g. http://localhost:4443 for fake-gcs-server
+    #[arg(long, global = true, env = "STORAGE_EMULATOR_HOST")]
+    pub gcs_endpoint: Option<String>,
+}
+
+pub fn create_gcs_options(args: &GcsClientArgs) -> GcsOptions {
+    GcsOptions {
+        credentials_file: args.gcs_credentials_file.clone(),
+        endpoint: args.gcs_endpoint.clone(),
+    }
+}
+
+/// Options shared by all backup commands.
+#[derive(clap::Args, Debug)]
+pub struct BackupArgs {
+    /// Storage class to use when storing objects in S3.
+    /// Possible values: DEEP_ARCHIVE, GLACIER, GLACIER_IR, INTELLIGENT_TIERING,
+    ///                  ONEZONE_IA, OUTPOSTS, REDUCED_REDUNDANCY, STANDARD, STANDARD_IA
+    #[arg(short, long, default_value_t = String::from("STANDARD"))]
+    pub s3_storage_class: String,
+
+    /// Choose the storage class per file, e.g. "size<128KiB=STANDARD",
+    /// "mime=image/*,age>365d=DEEP_ARCHIVE" or "age<30d=STANDARD_IA". Conditions are size<, size>=,
+    /// mime= (glob), age> and age< (since last modification in Drive); all of a rule's conditions
+    /// must match. The first matching rule wins; other files get --s3-storage-class.
+    /// Packed archives always get --s3-storage-class.
+    #[arg(long)]
+    pub storage_class_rule: Vec<String>,
+
+    /// Server-side encryption to request for every uploaded object.
+    /// Possible values: AES256, aws:kms, aws:kms:dsse. Defaults to the bucket's default encryption.
+    #[arg(long)]
+    pub sse: Option<String>,
+
+    /// KMS key ID, ARN or alias to use with --sse aws:kms or aws:kms:dsse
+    #[arg(long)]
+    pub sse_kms_key_id: Option<String>,
+
+    /// Use an S3 Bucket Key to reduce KMS request costs (requires --sse aws:kms)
+    #[arg(long)]
+    pub sse_bucket_key_enabled: bool,
+
+    /// Base64-encoded 256-bit key for server-side encryption with customer-provided keys (SSE-C)
+    #[arg(long, env = "SSE_CUSTOMER_KEY", hide_env_values = true)]
+    pub sse_customer_key: Option<String>,
+
+    /// Object Lock mode for every uploaded object: GOVERNANCE or COMPLIANCE.
+    /// Requires --object-lock-retain-for and a bucket with Object Lock enabled.
+    #[arg(long, requires = "object_lock_retain_for")]
+    pub object_lock_mode: Option<String>,
+
+    /// How long uploaded objects are locked, e.g. 90d or 1y
+    #[arg(long, requires = "object_lock_mode", value_parser = humantime::parse_duration)]
+    pub object_lock_retain_for: Option<std::time::Duration>,
+
+    /// Place a legal hold on every uploaded object
+    #[arg(long)]
+    pub object_lock_legal_hold: bool,
+
+    /// Compress compressible files (text, CSV, SVG, JSON, ...) with this algorithm: zstd or gzip.
+    /// Already compressed formats like JPEG, MP4 or ZIP are never compressed.
+    #[arg(long)]
+    pub compression: Option<String>,
+
+    /// Decide per MIME type whether to compress, e.g. "text/*=zstd", "image/svg+xml=gzip" or
+    /// "text/html=none". The first matching rule wins. Replaces the defaults of --compression.
+    #[arg(long)]
+    pub compression_rule: Vec<String>,
+
+    /// Bundle files smaller than this (e.g. 1MiB) into tar archives instead of uploading them one by
+    /// one. This avoids the per-object overhead of storage classes like DEEP_ARCHIVE. An index
+    /// object records which archive each file is in.
+    #[arg(long, value_parser = parse_byte_size)]
+    pub pack_files_smaller_than: Option<u64>,
+
+    /// Maximum size of an archive created by --pack-files-smaller-than
+    #[arg(long, default_value = "4GiB", value_parser = parse_byte_size)]
+    pub max_archive_size: u64,
+
+    /// Encrypt files before uploading them, using the 256-bit key in this file
+    /// (32 raw bytes or base64). Keep this key safe; it is needed for every restore.
+    #[arg(long, conflicts_with = "encryption_passphrase")]
+    pub encryption_key_file: Option<PathBuf>,
+
+    /// Encrypt files before uploading them, using a key derived from this passphrase
+    #[arg(long, env = "ENCRYPTION_PASSPHRASE", hide_env_values = true)]
+    pub encryption_passphrase: Option<String>,
+}
+
+pub fn backup_options_from(args: &BackupArgs, global: &GlobalArgs) -> Result<BackupOptions> {
+    Ok(BackupOptions {
+        storage_class: parse_storage_class(&args.s3_storage_class)?,
+        storage_class_rules: StorageClassRules::parse(&args.storage_class_rule)?,
+        compression: match (args.compression.as_deref(), args.compression_rule.is_empty()) {
+            (_, false) => CompressionRules::parse(&args.compression_rule)?,
+            (Some(algorithm), true) => CompressionRules::with_defaults(algorithm.parse()?),
+            (None, true) => CompressionRules::default(),
+        },
+        encryption_key: create_encryption_key(
+            args.encryption_key_file.as_deref(),
+            args.encryption_passphrase.as_deref(),
+        )?,
+        packing: args.pack_files_smaller_than.map(|max_file_size| PackingOptions {
+            max_file_size,
+            max_archive_size: args.max_archive_size,
+        }),
+        concurrency: global.concurrency,
+    })
+}
+
+/// Which Drive files to back up.
+#[derive(clap::Args, Debug)]
+pub struct FilterArgs {
+    /// Only back up files whose path in the source folder matches one of these globs, e.g. "*.pdf"
+    #[arg(long)]
+    pub include: Vec<String>,
+
+    /// Do not back up files whose path in the source folder matches this glob, e.g. "Cache/*"
+    #[arg(long)]
+    pub exclude: Vec<String>,
+
+    /// Only back up files whose Drive MIME type matches one of these globs, e.g. "image/*" or
+    /// "application/vnd.google-apps.document"
+    #[arg(long)]
+    pub mime_type: Vec<String>,
+
+    /// Do not back up files whose Drive MIME type matches this glob, e.g. "video/*"
+    #[arg(long)]
+    pub exclude_mime_type: Vec<String>,
+
+    /// Only back up files at least this big, e.g. 1KiB. Google documents have no size and pass.
+    #[arg(long, value_parser = parse_byte_size)]
+    pub min_size: Option<u64>,
+
+    /// Only back up files at most this big, e.g. 2GiB. Google documents have no size and pass.
+    #[arg(long, value_parser = parse_byte_size)]
+    pub max_size: Option<u64>,
+
+    /// Only back up files modified at or after this time, e.g. 2024-01-31 or 2024-01-31T12:00:00Z
+    #[arg(long)]
+    pub modified_after: Option<String>,
+
+    /// Only back up files modified before this time
+    #[arg(long)]
+    pub modified_before: Option<String>,
+
+    /// Whose files to back up: anyone, me (owned by the authorized user) or others (shared with it)
+    #[arg(long, default_value = "anyone")]
+    pub owner: String,
+}
+
+pub fn filter_from(args: &FilterArgs) -> Result<FileFilter> {
+    Ok(FileFilter {
+        include: parse_patterns(&args.include)?,
+        exclude: parse_patterns(&args.exclude)?,
+        mime_types: parse_patterns(&args.mime_type)?,
+        exclude_mime_types: parse_patterns(&args.exclude_mime_type)?,
+        min_size: args.min_size,
+        max_size: args.max_size,
+        modified_after: args.modified_after.as_deref().map(parse_time).transpose()?,
+        modified_before: args.modified_before.as_deref().map(parse_time).transpose()?,
+        owner: args.owner.parse()?,
+    })
+}
+
+/// Creates the destinations for `urls`, with `{date}` substituted by the current date.
+pub async fn destinations_from(
+    args: &BackupArgs,
+    global: &GlobalArgs,
+    urls: &[String],
+) -> Result<Vec<Arc<dyn Destination>>> {
+    let encryption = Encryption::new(
+        args.sse.as_deref(),
+        args.sse_kms_key_id.as_deref(),
+        args.sse_bucket_key_enabled,
+        args.sse_customer_key.as_deref(),
+    )?;
+    let object_lock = ObjectLock::new(
+        args.object_lock_mode.as_deref(),
+        args.object_lock_retain_for,
+        args.object_lock_legal_hold,
+    )?;
+    let options = destination_options_from(global, encryption, object_lock).await;
+    let today = chrono::Local::now().date_naive();
+    urls.iter().map(|d| destination_for(&substitute_date(d, today), &options)).collect()
+}
+
+pub async fn destination_options_from(
+    global: &GlobalArgs,
+    encryption: Encryption,
+    object_lock: ObjectLock,
+) -> DestinationOptions {
+    DestinationOptions {
+        s3: create_s3_client(&global.s3_client).await,
+        encryption,
+        object_lock,
+        azure: create_azure_options(&global.azure_client),
+        gcs: create_gcs_options(&global.gcs_client),
+    }
+}
+
+fn parse_byte_size(s: &str) -> std::result::Result<u64, String> {
+    byte_unit::Byte::from_str(s).map(|b| b.get_bytes() as u64).map_err(|e| e.to_string())
+}
+
+fn substitute_date(templated_string: &str, date: chrono::NaiveDate) -> String {
+    templated_string.replace("{date}", date.format("%Y-%m-%d").to_string().as_str())
+}
+
+pub fn create_encryption_key(
+    key_file: Option<&Path>,
+    passphrase: Option<&str>,
+) -> Result<Option<MasterKey>> {
+    match (key_file, passphrase) {
+        (Some(key_file), _) => Ok(Some(MasterKey::from_key_file(key_file)?)),
+        (None, Some(passphrase)) => Ok(Some(MasterKey::from_passphrase(passphrase)?)),
+        (None, None) => Ok(None),
+    }
+}
+
+pub fn set_up_logging(level: log::LevelFilter) {
     env_logger::Builder::new()
         .format(|buf, record| {
             writeln!(
@@ -25,6 +379,17 @@ pub fn set_up_logging() {
                 record.args()
             )
         })
-        .filter_level(log::LevelFilter::Info)
+        .filter_level(level)
         .init();
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::cli_factories::substitute_date;
+
+    #[test]
+    fn it_works() {
+        let date = chrono::NaiveDate::from_ymd_opt(2022, 11, 4).unwrap();
+        assert_eq!(substitute_date("/some/{date}/path", date), "/some/2022-11-04/path");
+    }
+}
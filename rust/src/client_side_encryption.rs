@@ -0,0 +1,336 @@
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use base64::Engine;
+use sha2::{Digest, Sha256};
+
+use crate::errors::{Error, Result, ResultExt};
+use crate::transform::Transform;
+
+/// Object metadata key under which the ID of the master key is recorded.
+pub const KEY_ID_METADATA_KEY: &str = "g2s3-key-id";
+
+/// Every encrypted object starts with this, followed by the master key ID, the passphrase salt and
+/// the wrapped data key.
+const MAGIC: &[u8; 8] = b"G2S3ENC2";
+const KEY_ID_LEN: usize = 8;
+const SALT_LEN: usize = 16;
+const WRAPPED_KEY_LEN: usize = 12 + 32 + 16;
+const HEADER_LEN: usize = MAGIC.len() + KEY_ID_LEN + SALT_LEN + WRAPPED_KEY_LEN;
+
+/// Plaintext is encrypted in chunks of this size, each with its own authentication tag.
+const CHUNK_SIZE: usize = 64 * 1024;
+const TAG_LEN: usize = 16;
+
+/// The key that wraps the random per-object data keys (envelope encryption).
+#[derive(Clone)]
+pub struct MasterKey {
+    key: [u8; 32],
+    id: [u8; KEY_ID_LEN],
+    /// The salt `key` was derived with from the passphrase. Zero for keys from key files.
+    salt: [u8; SALT_LEN],
+    passphrase: Option<Arc<Passphrase>>,
+}
+
+/// Passphrases are stretched with Argon2id and a random salt, which is stored in every object, so
+/// objects can be decrypted with just the passphrase.
+struct Passphrase {
+    passphrase: String,
+    /// Derived keys by salt, since a restore usually reads many objects with the same salt.
+    keys: Mutex<HashMap<Vec<u8>, [u8; 32]>>,
+}
+
+impl Passphrase {
+    fn key_for(&self, salt: &[u8]) -> Result<[u8; 32]> {
+        let mut keys = self.keys.lock().unwrap();
+        if let Some(key) = keys.get(salt) {
+            return Ok(*key);
+        }
+        let mut key = [0; 32];
+        Argon2::default()
+            .hash_password_into(self.passphrase.as_bytes(), salt, &mut key)
+            .map_err(|e| Error::from(format!("Could not derive key from passphrase: {e}")))?;
+        keys.insert(salt.to_vec(), key);
+        Ok(key)
+    }
+}
+
+impl MasterKey {
+    pub fn new(key: [u8; 32]) -> MasterKey {
+        MasterKey { key, id: key_id(&key), salt: [0; SALT_LEN], passphrase: None }
+    }
+
+    /// Reads a key file containing either 32 raw bytes or their base64 encoding.
+    pub fn from_key_file(path: &Path) -> Result<MasterKey> {
+        let content = std::fs::read(path)
+            .chain_err(|| format!("Could not read encryption key file {}", path.display()))?;
+        let raw = if content.len() == 32 {
+            content
+        } else {
+            base64::engine::general_purpose::STANDARD
+                .decode(String::from_utf8_lossy(&content).trim())
+                .chain_err(|| "Encryption key file must contain 32 raw bytes or base64")?
+        };
+        let key: [u8; 32] =
+            raw.try_into().map_err(|_| Error::from("Encryption key must be 256 bits long"))?;
+        Ok(MasterKey::new(key))
+    }
+
+    /// A key derived from `passphrase` with a new random salt, so its ID differs between runs.
+    pub fn from_passphrase(passphrase: &str) -> Result<MasterKey> {
+        let mut salt = [0; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let passphrase =
+            Arc::new(Passphrase { passphrase: passphrase.to_string(), keys: Mutex::default() });
+        let key = passphrase.key_for(&salt)?;
+        Ok(MasterKey { key, id: key_id(&key), salt, passphrase: Some(passphrase) })
+    }
+
+    pub fn id(&self) -> String {
+        hex::encode(self.id)
+    }
+
+    /// The key for objects with `salt` in their header: derived from the same passphrase with that
+    /// salt, or this key itself if it does not come from a passphrase.
+    fn for_salt(&self, salt: &[u8]) -> Result<MasterKey> {
+        match self.passphrase.as_ref() {
+            Some(passphrase) if salt != self.salt => {
+                let key = passphrase.key_for(salt)?;
+                Ok(MasterKey {
+                    key,
+                    id: key_id(&key),
+                    salt: salt.try_into().unwrap(),
+                    passphrase: Some(passphrase.clone()),
+                })
+            }
+            _ => Ok(self.clone()),
+        }
+    }
+
+    pub fn encryptor(&self) -> Result<Encryptor> {
+        let data_key = Aes256Gcm::generate_key(OsRng);
+        let mut wrap_nonce = [0; 12];
+        OsRng.fill_bytes(&mut wrap_nonce);
+        let wrapped_key = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&self.key))
+            .encrypt(
+                Nonce::from_slice(&wrap_nonce),
+                Payload { msg: data_key.as_slice(), aad: &self.header_aad() },
+            )
+            .map_err(|_| Error::from("Could not wrap data key"))?;
+
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&self.id);
+        header.extend_from_slice(&self.salt);
+        header.extend_from_slice(&wrap_nonce);
+        header.extend_from_slice(&wrapped_key);
+
+        Ok(Encryptor { chunks: ChunkCipher::new(&data_key), header: Some(header), buf: vec![] })
+    }
+
+    pub fn decryptor(&self) -> Decryptor {
+        Decryptor { master_key: self.clone(), chunks: None, buf: vec![] }
+    }
+
+    fn header_aad(&self) -> Vec<u8> {
+        [MAGIC.as_slice(), self.id.as_slice(), self.salt.as_slice()].concat()
+    }
+}
+
+fn key_id(key: &[u8; 32]) -> [u8; KEY_ID_LEN] {
+    let digest = Sha256::new().chain_update(b"g2s3 key id").chain_update(key).finalize();
+    let mut id = [0; KEY_ID_LEN];
+    id.copy_from_slice(&digest[..KEY_ID_LEN]);
+    id
+}
+
+/// AES-256-GCM over fixed-size chunks. The nonce is the chunk counter plus a flag marking the last
+/// chunk, so chunks cannot be reordered, dropped or truncated without failing authentication.
+struct ChunkCipher {
+    cipher: Aes256Gcm,
+    counter: u32,
+}
+
+impl ChunkCipher {
+    fn new(data_key: &[u8]) -> ChunkCipher {
+        ChunkCipher { cipher: Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(data_key)), counter: 0 }
+    }
+
+    fn next_nonce(&mut self, last: bool) -> Result<[u8; 12]> {
+        let mut nonce = [0; 12];
+        nonce[7..11].copy_from_slice(&self.counter.to_be_bytes());
+        nonce[11] = last as u8;
+        self.counter =
+            self.counter.checked_add(1).ok_or_else(|| Error::from("Too many chunks to encrypt"))?;
+        Ok(nonce)
+    }
+
+    fn encrypt(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce(last)?;
+        self.cipher
+            .encrypt(Nonce::from_slice(&nonce), chunk)
+            .map_err(|_| Error::from("Could not encrypt chunk"))
+    }
+
+    fn decrypt(&mut self, chunk: &[u8], last: bool) -> Result<Vec<u8>> {
+        let nonce = self.next_nonce(last)?;
+        self.cipher.decrypt(Nonce::from_slice(&nonce), chunk).map_err(|_| {
+            Error::from("Could not decrypt chunk. The data is corrupted or truncated.")
+        })
+    }
+}
+
+pub struct Encryptor {
+    chunks: ChunkCipher,
+    header: Option<Vec<u8>>,
+    buf: Vec<u8>,
+}
+
+impl Transform for Encryptor {
+    fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut out = self.header.take().unwrap_or_default();
+        self.buf.extend_from_slice(data);
+        // Always keep at least one byte back, so the last chunk is only encrypted in finish().
+        while self.buf.len() > CHUNK_SIZE {
+            let rest = self.buf.split_off(CHUNK_SIZE);
+            let chunk = std::mem::replace(&mut self.buf, rest);
+            out.extend(self.chunks.encrypt(&chunk, false)?);
+        }
+        Ok(out)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let mut out = self.header.take().unwrap_or_default();
+        out.extend(self.chunks.encrypt(&std::mem::take(&mut self.buf), true)?);
+        Ok(out)
+    }
+}
+
+pub struct Decryptor {
+    master_key: MasterKey,
+    chunks: Option<ChunkCipher>,
+    buf: Vec<u8>,
+}
+
+impl Decryptor {
+    fn read_header(&mut self) -> Result<()> {
+        let (magic, rest) = self.buf.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(Error::from("Object is not encrypted with client-side encryption"));
+        }
+        let (key_id, rest) = rest.split_at(KEY_ID_LEN);
+        let (salt, rest) = rest.split_at(SALT_LEN);
+        let master_key = self.master_key.for_salt(salt)?;
+        if key_id != master_key.id {
+            return Err(Error::from(format!(
+                "Object was encrypted with key {} but key {} was given",
+                hex::encode(key_id),
+                master_key.id()
+            )));
+        }
+        let (wrap_nonce, wrapped_key) = rest[..WRAPPED_KEY_LEN].split_at(12);
+        let data_key = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&master_key.key))
+            .decrypt(
+                Nonce::from_slice(wrap_nonce),
+                Payload { msg: wrapped_key, aad: &master_key.header_aad() },
+            )
+            .map_err(|_| Error::from("Could not unwrap data key"))?;
+        self.chunks = Some(ChunkCipher::new(&data_key));
+        self.buf.drain(..HEADER_LEN);
+        Ok(())
+    }
+}
+
+impl Transform for Decryptor {
+    fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        self.buf.extend_from_slice(data);
+        if self.chunks.is_none() {
+            if self.buf.len() < HEADER_LEN {
+                return Ok(vec![]);
+            }
+            self.read_header()?;
+        }
+        let mut out = vec![];
+        while self.buf.len() > CHUNK_SIZE + TAG_LEN {
+            let rest = self.buf.split_off(CHUNK_SIZE + TAG_LEN);
+            let chunk = std::mem::replace(&mut self.buf, rest);
+            out.extend(self.chunks.as_mut().unwrap().decrypt(&chunk, false)?);
+        }
+        Ok(out)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        match self.chunks.as_mut() {
+            None => Err(Error::from("Encrypted object is truncated")),
+            Some(chunks) => chunks.decrypt(&std::mem::take(&mut self.buf), true),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_side_encryption::{MasterKey, CHUNK_SIZE, HEADER_LEN};
+    use crate::transform::Transform;
+
+    fn run(transform: &mut dyn Transform, data: &[u8], piece_size: usize) -> Vec<u8> {
+        let mut out = vec![];
+        for piece in data.chunks(piece_size) {
+            out.extend(transform.update(piece).unwrap());
+        }
+        out.extend(transform.finish().unwrap());
+        out
+    }
+
+    #[test]
+    fn encrypted_data_decrypts_to_the_original() {
+        let key = MasterKey::new([7; 32]);
+        for len in [0, 1, CHUNK_SIZE - 1, CHUNK_SIZE, CHUNK_SIZE + 1, 3 * CHUNK_SIZE + 17] {
+            let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+
+            let encrypted = run(&mut key.encryptor().unwrap(), &data, 1000);
+            assert_ne!(encrypted[HEADER_LEN..], data[..]);
+
+            assert_eq!(run(&mut key.decryptor(), &encrypted, 777), data);
+        }
+    }
+
+    #[test]
+    fn decryption_fails_for_tampered_truncated_or_foreign_data() {
+        let key = MasterKey::new([7; 32]);
+        let data = vec![42; 2 * CHUNK_SIZE + 5];
+        let encrypted = run(&mut key.encryptor().unwrap(), &data, 4096);
+
+        let mut tampered = encrypted.clone();
+        tampered[100] ^= 1;
+        let mut decryptor = key.decryptor();
+        assert!(decryptor.update(&tampered).and_then(|_| decryptor.finish()).is_err());
+
+        let truncated = &encrypted[..encrypted.len() - 21];
+        let mut decryptor = key.decryptor();
+        assert!(decryptor.update(truncated).and_then(|_| decryptor.finish()).is_err());
+
+        let mut decryptor = MasterKey::new([8; 32]).decryptor();
+        let err = decryptor.update(&encrypted).unwrap_err();
+        assert!(err.to_string().contains(&key.id()));
+    }
+
+    #[test]
+    fn passphrase_keys_get_a_random_salt_that_decryption_reads_back() {
+        let key = MasterKey::from_passphrase("correct horse battery staple").unwrap();
+        let other_run = MasterKey::from_passphrase("correct horse battery staple").unwrap();
+        let data = b"secret".to_vec();
+        let encrypted = run(&mut key.encryptor().unwrap(), &data, 4);
+
+        assert_ne!(key.salt, other_run.salt);
+        assert_ne!(key.id(), other_run.id());
+        assert_eq!(run(&mut other_run.decryptor(), &encrypted, 5), data);
+
+        let mut decryptor = MasterKey::from_passphrase("wrong").unwrap().decryptor();
+        assert!(decryptor.update(&encrypted).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use flate2::write::{GzDecoder, GzEncoder};
+use glob::Pattern;
+
+use crate::errors::{Error, Result, ResultExt};
+use crate::transform::Transform;
+
+/// Object metadata key under which the compression algorithm is recorded.
+pub const COMPRESSION_METADATA_KEY: &str = "g2s3-compression";
+
+/// MIME types that compress well and are compressed when compression is enabled without rules.
+pub const DEFAULT_COMPRESSIBLE_MIME_TYPES: &[&str] = &[
+    "text/*",
+    "image/svg+xml",
+    "image/bmp",
+    "image/tiff",
+    "application/json",
+    "application/xml",
+    "application/javascript",
+    "application/x-sh",
+    "application/sql",
+    "application/rtf",
+    "message/rfc822",
+    "application/mbox",
+];
+
+/// MIME types that are already compressed. They are never compressed again, whatever the rules say.
+/// Note that the Office formats Google documents get exported to are ZIP files.
+const ALREADY_COMPRESSED_MIME_TYPES: &[&str] = &[
+    "image/jpeg",
+    "image/png",
+    "image/gif",
+    "image/webp",
+    "image/heic",
+    "image/heif",
+    "image/avif",
+    "video/*",
+    "audio/*",
+    "application/zip",
+    "application/gzip",
+    "application/x-gzip",
+    "application/zstd",
+    "application/x-bzip2",
+    "application/x-xz",
+    "application/x-7z-compressed",
+    "application/x-rar-compressed",
+    "application/vnd.rar",
+    "application/pdf",
+    "application/epub+zip",
+    "application/vnd.openxmlformats-officedocument.*",
+    "application/vnd.oasis.opendocument.*",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Algorithm {
+    Zstd,
+    Gzip,
+}
+
+impl Algorithm {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Algorithm::Zstd => "zstd",
+            Algorithm::Gzip => "gzip",
+        }
+    }
+
+    pub fn compressor(&self) -> Result<Box<dyn Transform>> {
+        Ok(match self {
+            Algorithm::Zstd => Box::new(Compressor::Zstd(
+                zstd::stream::write::Encoder::new(vec![], 0)
+                    .chain_err(|| "Could not create zstd encoder")?,
+            )),
+            Algorithm::Gzip => {
+                Box::new(Compressor::Gzip(GzEncoder::new(vec![], flate2::Compression::default())))
+            }
+        })
+    }
+
+    pub fn decompressor(&self) -> Result<Box<dyn Transform>> {
+        Ok(match self {
+            Algorithm::Zstd => Box::new(Decompressor::Zstd(zstd::stream::zio::Writer::new(
+                vec![],
+                zstd::stream::raw::Decoder::new().chain_err(|| "Could not create zstd decoder")?,
+            ))),
+            Algorithm::Gzip => Box::new(Decompressor::Gzip(GzDecoder::new(vec![]))),
+        })
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Algorithm> {
+        match s {
+            "zstd" => Ok(Algorithm::Zstd),
+            "gzip" => Ok(Algorithm::Gzip),
+            _ => Err(Error::from(format!("Unknown compression {s}. Possible values: zstd, gzip"))),
+        }
+    }
+}
+
+/// Decides per MIME type whether and how to compress. The first matching rule wins.
+#[derive(Clone, Debug, Default)]
+pub struct CompressionRules {
+    rules: Vec<(Pattern, Option<Algorithm>)>,
+}
+
+impl CompressionRules {
+    /// Compresses all of `DEFAULT_COMPRESSIBLE_MIME_TYPES` with `algorithm`.
+    pub fn with_defaults(algorithm: Algorithm) -> CompressionRules {
+        CompressionRules {
+            rules: DEFAULT_COMPRESSIBLE_MIME_TYPES
+                .iter()
+                .map(|p| (Pattern::new(p).unwrap(), Some(algorithm)))
+                .collect(),
+        }
+    }
+
+    /// Parses rules in the format `<mime type glob>=<zstd|gzip|none>`, e.g. `text/*=zstd`.
+    pub fn parse(rules: &[String]) -> Result<CompressionRules> {
+        let mut parsed = vec![];
+        for rule in rules {
+            let (pattern, algorithm) = rule.split_once('=').ok_or_else(|| {
+                Error::from(format!(
+                    "Compression rule {rule} must have the format <mime type>=<algorithm>"
+                ))
+            })?;
+            let pattern = Pattern::new(pattern)
+                .chain_err(|| format!("Invalid MIME type pattern in compression rule {rule}"))?;
+            let algorithm = match algorithm {
+                "none" => None,
+                algorithm => Some(algorithm.parse()?),
+            };
+            parsed.push((pattern, algorithm));
+        }
+        Ok(CompressionRules { rules: parsed })
+    }
+
+    pub fn algorithm_for(&self, mime_type: &str) -> Option<Algorithm> {
+        if ALREADY_COMPRESSED_MIME_TYPES.iter().any(|p| Pattern::new(p).unwrap().matches(mime_type))
+        {
+            return None;
+        }
+        self.rules.iter().find(|(pattern, _)| pattern.matches(mime_type)).and_then(|(_, a)| *a)
+    }
+}
+
+enum Compressor {
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+    Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Transform for Compressor {
+    fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compressor::Zstd(e) => {
+                e.write_all(data).chain_err(|| "zstd compression failed")?;
+                std::mem::take(e.get_mut())
+            }
+            Compressor::Gzip(e) => {
+                e.write_all(data).chain_err(|| "gzip compression failed")?;
+                std::mem::take(e.get_mut())
+            }
+        })
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        Ok(match self {
+            Compressor::Zstd(e) => {
+                e.do_finish().chain_err(|| "zstd compression failed")?;
+                std::mem::take(e.get_mut())
+            }
+            Compressor::Gzip(e) => {
+                e.try_finish().chain_err(|| "gzip compression failed")?;
+                std::mem::take(e.get_mut())
+            }
+        })
+    }
+}
+
+enum Decompressor {
+    /// Unlike `write::Decoder`, this writer can be finished, which fails if the last frame is
+    /// incomplete, i.e. the object was truncated.
+    Zstd(zstd::stream::zio::Writer<Vec<u8>, zstd::stream::raw::Decoder<'static>>),
+    Gzip(GzDecoder<Vec<u8>>),
+}
+
+impl Transform for Decompressor {
+    fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        Ok(match self {
+            Decompressor::Zstd(d) => {
+                d.write_all(data).chain_err(|| "zstd decompression failed")?;
+                std::mem::take(d.writer_mut())
+            }
+            Decompressor::Gzip(d) => {
+                d.write_all(data).chain_err(|| "gzip decompression failed")?;
+                std::mem::take(d.get_mut())
+            }
+        })
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        Ok(match self {
+            Decompressor::Zstd(d) => {
+                d.finish().chain_err(|| "zstd decompression failed")?;
+                std::mem::take(d.writer_mut())
+            }
+            Decompressor::Gzip(d) => {
+                d.try_finish().chain_err(|| "gzip decompression failed")?;
+                std::mem::take(d.get_mut())
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compression::{Algorithm, CompressionRules};
+    use crate::transform::Transform;
+
+    fn run(transform: &mut dyn Transform, data: &[u8]) -> Vec<u8> {
+        let mut out = vec![];
+        for piece in data.chunks(1000) {
+            out.extend(transform.update(piece).unwrap());
+        }
+        out.extend(transform.finish().unwrap());
+        out
+    }
+
+    #[test]
+    fn compressed_data_decompresses_to_the_original() {
+        let data = "name,size\nsome-file.txt,1234\n".repeat(1000).into_bytes();
+        for algorithm in [Algorithm::Zstd, Algorithm::Gzip] {
+            let compressed = run(&mut *algorithm.compressor().unwrap(), &data);
+            assert!(compressed.len() < data.len() / 10);
+
+            assert_eq!(run(&mut *algorithm.decompressor().unwrap(), &compressed), data);
+        }
+    }
+
+    #[test]
+    fn truncated_data_fails_to_decompress() {
+        let data = "name,size\nsome-file.txt,1234\n".repeat(1000).into_bytes();
+        for algorithm in [Algorithm::Zstd, Algorithm::Gzip] {
+            let compressed = run(&mut *algorithm.compressor().unwrap(), &data);
+            let mut decompressor = algorithm.decompressor().unwrap();
+
+            decompressor.update(&compressed[..compressed.len() - 10]).unwrap();
+            assert!(decompressor.finish().is_err(), "{algorithm:?} accepted truncated data");
+        }
+    }
+
+    #[test]
+    fn rules_pick_the_first_match_and_skip_compressed_types() {
+        let rules = CompressionRules::parse(&[
+            "text/html=none".to_string(),
+            "text/*=gzip".to_string(),
+            "*=zstd".to_string(),
+        ])
+        .unwrap();
+
+        assert_eq!(rules.algorithm_for("text/html"), None);
+        assert_eq!(rules.algorithm_for("text/csv"), Some(Algorithm::Gzip));
+        assert_eq!(rules.algorithm_for("image/svg+xml"), Some(Algorithm::Zstd));
+        assert_eq!(rules.algorithm_for("image/jpeg"), None);
+        assert_eq!(rules.algorithm_for("video/mp4"), None);
+        assert_eq!(
+            rules
+                .algorithm_for("application/vnd.openxmlformats-officedocument.spreadsheetml.sheet"),
+            None
+        );
+
+        let defaults = CompressionRules::with_defaults(Algorithm::Zstd);
+        assert_eq!(defaults.algorithm_for("text/plain"), Some(Algorithm::Zstd));
+        assert_eq!(defaults.algorithm_for("application/octet-stream"), None);
+
+        assert!(CompressionRules::parse(&["text/*".to_string()]).is_err());
+        assert!(CompressionRules::parse(&["text/*=lz4".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,197 @@
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::errors::{Error, Result, ResultExt};
+
+/// The jobs in `cdk/bin/deployment-config.json`, which the CDK stack turns into AWS Batch jobs and
+/// `g2s3 run` executes locally. Fields only the stack uses, like `google_secrets`, are ignored.
+#[derive(Deserialize, Debug)]
+pub struct Config {
+    pub backup_definitions: Vec<BackupDefinition>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct BackupDefinition {
+    /// Selects the job with `g2s3 run --job`. Defaults to `google_drive_folder`.
+    pub name: Option<String>,
+    pub google_drive_folder: String,
+    /// Destination, which can use {date}.
+    pub s3_url: String,
+    pub storage_class: Option<String>,
+    #[serde(default)]
+    pub storage_class_rules: Vec<String>,
+    pub sse: Option<String>,
+    pub sse_kms_key_id: Option<String>,
+    #[serde(default)]
+    pub sse_bucket_key_enabled: bool,
+    pub object_lock_mode: Option<String>,
+    pub object_lock_retain_for: Option<String>,
+    #[serde(default)]
+    pub object_lock_legal_hold: bool,
+    /// Filters, like the options of the same name.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+    #[serde(default)]
+    pub exclude_mime_types: Vec<String>,
+    pub min_size: Option<String>,
+    pub max_size: Option<String>,
+    pub modified_after: Option<String>,
+    pub modified_before: Option<String>,
+    pub owner: Option<String>,
+    pub schedule: Option<Schedule>,
+}
+
+/// The CDK's `CronOptions`; fields that are not set mean "every".
+#[derive(Deserialize, Debug, Default)]
+pub struct Schedule {
+    pub minute: Option<String>,
+    pub hour: Option<String>,
+    pub day: Option<String>,
+    pub month: Option<String>,
+    pub year: Option<String>,
+    #[serde(rename = "weekDay")]
+    pub week_day: Option<String>,
+}
+
+impl Config {
+    /// Reads a TOML file if the name ends in .toml, JSON otherwise.
+    pub fn load(path: &Path) -> Result<Config> {
+        let text = std::fs::read_to_string(path)
+            .chain_err(|| format!("Could not read {}", path.display()))?;
+        let config = match path.extension().and_then(|e| e.to_str()) {
+            Some("toml") => toml::from_str(&text).map_err(|e| Error::from(e.to_string())),
+            _ => serde_json::from_str(&text).map_err(|e| Error::from(e.to_string())),
+        };
+        config.chain_err(|| format!("Invalid config {}", path.display()))
+    }
+
+    /// All jobs, or only the one called `name`.
+    pub fn jobs(&self, name: Option<&str>) -> Result<Vec<&BackupDefinition>> {
+        let jobs: Vec<_> = self
+            .backup_definitions
+            .iter()
+            .filter(|job| name.is_none_or(|name| job.name() == name))
+            .collect();
+        match (jobs.is_empty(), name) {
+            (true, Some(name)) => Err(Error::from(format!("There is no job called {name}"))),
+            _ => Ok(jobs),
+        }
+    }
+}
+
+impl BackupDefinition {
+    pub fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(&self.google_drive_folder)
+    }
+
+    /// The `g2s3 backup drive` arguments for this job, the same the CDK stack passes to the
+    /// container.
+    pub fn to_args(&self) -> Vec<String> {
+        let mut args = vec![self.google_drive_folder.clone(), self.s3_url.clone()];
+        let mut push = |name: &str, value: Option<&String>| {
+            if let Some(value) = value {
+                args.extend([name.to_string(), value.clone()]);
+            }
+        };
+        push("--s3-storage-class", self.storage_class.as_ref());
+        for rule in &self.storage_class_rules {
+            push("--storage-class-rule", Some(rule));
+        }
+        push("--sse", self.sse.as_ref());
+        push("--sse-kms-key-id", self.sse_kms_key_id.as_ref());
+        push("--object-lock-mode", self.object_lock_mode.as_ref());
+        push("--object-lock-retain-for", self.object_lock_retain_for.as_ref());
+        for (name, values) in [
+            ("--include", &self.include),
+            ("--exclude", &self.exclude),
+            ("--mime-type", &self.mime_types),
+            ("--exclude-mime-type", &self.exclude_mime_types),
+        ] {
+            for value in values {
+                push(name, Some(value));
+            }
+        }
+        push("--min-size", self.min_size.as_ref());
+        push("--max-size", self.max_size.as_ref());
+        push("--modified-after", self.modified_after.as_ref());
+        push("--modified-before", self.modified_before.as_ref());
+        push("--owner", self.owner.as_ref());
+        for (flag, set) in [
+            ("--sse-bucket-key-enabled", self.sse_bucket_key_enabled),
+            ("--object-lock-legal-hold", self.object_lock_legal_hold),
+        ] {
+            if set {
+                args.push(flag.to_string());
+            }
+        }
+        args
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Config;
+
+    #[test]
+    fn toml_and_json_configs_become_backup_arguments() {
+        let json: Config = serde_json::from_str(
+            r#"{
+              "email": "me@example.com",
+              "backup_definitions": [
+                {
+                  "google_drive_folder": "Documents",
+                  "s3_url": "s3://bucket/{date}/Documents",
+                  "should_create_bucket": false,
+                  "storage_class": "DEEP_ARCHIVE",
+                  "storage_class_rules": ["size<128KiB=STANDARD"],
+                  "object_lock_legal_hold": true,
+                  "exclude_mime_types": ["video/*"],
+                  "google_secrets": [{"name": "CLIENT_ID", "valueFrom": "arn:..."}],
+                  "schedule": {"minute": "0", "hour": "0", "day": "1", "month": "*/4"}
+                },
+                {"name": "pics", "google_drive_folder": "Pictures", "s3_url": "file:///b"}
+              ]
+            }"#,
+        )
+        .unwrap();
+        let toml: Config = toml::from_str(
+            r#"
+            [[backup_definitions]]
+            google_drive_folder = "Documents"
+            s3_url = "s3://bucket/{date}/Documents"
+            storage_class = "DEEP_ARCHIVE"
+            storage_class_rules = ["size<128KiB=STANDARD"]
+            object_lock_legal_hold = true
+            exclude_mime_types = ["video/*"]
+            schedule = { minute = "0", hour = "0", day = "1", month = "*/4" }
+            "#,
+        )
+        .unwrap();
+
+        let expected = vec![
+            "Documents",
+            "s3://bucket/{date}/Documents",
+            "--s3-storage-class",
+            "DEEP_ARCHIVE",
+            "--storage-class-rule",
+            "size<128KiB=STANDARD",
+            "--exclude-mime-type",
+            "video/*",
+            "--object-lock-legal-hold",
+        ];
+        assert_eq!(json.backup_definitions[0].to_args(), expected);
+        assert_eq!(toml.backup_definitions[0].to_args(), expected);
+        assert_eq!(
+            json.backup_definitions[0].schedule.as_ref().unwrap().month.as_deref(),
+            Some("*/4")
+        );
+        assert_eq!(json.jobs(None).unwrap().len(), 2);
+        assert_eq!(json.jobs(Some("pics")).unwrap()[0].to_args(), vec!["Pictures", "file:///b"]);
+        assert!(json.jobs(Some("Pictures")).is_err());
+    }
+}
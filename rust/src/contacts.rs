@@ -0,0 +1,434 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use chrono::Utc;
+use futures::stream;
+use google_drive3::api::File;
+use google_drive3::oauth2::authorized_user::AuthorizedUserSecret;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::sync::mpsc;
+
+use crate::calendar::{escape, fold};
+use crate::destination::Destination;
+use crate::errors::{Result, ResultExt};
+use crate::google_api::GoogleApi;
+use crate::{check_destinations, copy_file, finish_back_up, BackupOptions, Content};
+
+const API: &str = "https://people.googleapis.com/v1";
+
+const SCOPE: &str = "https://www.googleapis.com/auth/contacts.readonly";
+
+/// All person fields, so the JSON dump is complete.
+const PERSON_FIELDS: &str = "addresses,ageRanges,biographies,birthdays,calendarUrls,clientData,\
+    coverPhotos,emailAddresses,events,externalIds,genders,imClients,interests,locales,locations,\
+    memberships,metadata,miscKeywords,names,nicknames,occupations,organizations,phoneNumbers,\
+    photos,relations,sipAddresses,skills,urls,userDefined";
+
+const GROUP_FIELDS: &str = "clientData,groupType,memberCount,metadata,name";
+
+/// Client for the Google People API.
+pub struct People {
+    api: GoogleApi,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Person {
+    pub resource_name: String,
+    pub names: Vec<Name>,
+    pub nicknames: Vec<TypedValue>,
+    pub email_addresses: Vec<TypedValue>,
+    pub phone_numbers: Vec<TypedValue>,
+    pub addresses: Vec<Address>,
+    pub organizations: Vec<Organization>,
+    pub birthdays: Vec<Birthday>,
+    pub urls: Vec<TypedValue>,
+    pub biographies: Vec<TypedValue>,
+    pub photos: Vec<Photo>,
+    pub memberships: Vec<Membership>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Name {
+    pub display_name: Option<String>,
+    pub family_name: Option<String>,
+    pub given_name: Option<String>,
+    pub middle_name: Option<String>,
+    pub honorific_prefix: Option<String>,
+    pub honorific_suffix: Option<String>,
+}
+
+/// An email address, phone number, URL, nickname or biography.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct TypedValue {
+    pub value: Option<String>,
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Address {
+    #[serde(rename = "type")]
+    pub kind: Option<String>,
+    pub po_box: Option<String>,
+    pub extended_address: Option<String>,
+    pub street_address: Option<String>,
+    pub city: Option<String>,
+    pub region: Option<String>,
+    pub postal_code: Option<String>,
+    pub country: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Organization {
+    pub name: Option<String>,
+    pub department: Option<String>,
+    pub title: Option<String>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Birthday {
+    pub date: Option<Date>,
+    pub text: Option<String>,
+}
+
+/// A date where 0 means the year, month or day is not known.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Date {
+    pub year: u32,
+    pub month: u32,
+    pub day: u32,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(default)]
+pub struct Photo {
+    pub url: String,
+    /// Set for the generated placeholder photo.
+    pub default: bool,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Membership {
+    pub contact_group_membership: Option<ContactGroupMembership>,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ContactGroupMembership {
+    pub contact_group_resource_name: String,
+}
+
+#[derive(Deserialize, Default, Debug)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ContactGroup {
+    pub resource_name: String,
+    pub formatted_name: String,
+    /// USER_CONTACT_GROUP or SYSTEM_CONTACT_GROUP, e.g. "myContacts" or "starred".
+    pub group_type: String,
+}
+
+impl People {
+    pub async fn new(aus: AuthorizedUserSecret) -> Result<People> {
+        Ok(People { api: GoogleApi::new(aus, &[SCOPE]).await? })
+    }
+
+    /// Lists all contacts as the API returns them.
+    pub async fn list_connections(&self) -> Result<Vec<Value>> {
+        self.list(
+            "people/me/connections",
+            &[("personFields", PERSON_FIELDS), ("pageSize", "1000")],
+            "connections",
+        )
+        .await
+        .chain_err(|| "Could not list contacts")
+    }
+
+    /// Lists all contact groups as the API returns them.
+    pub async fn list_contact_groups(&self) -> Result<Vec<Value>> {
+        self.list(
+            "contactGroups",
+            &[("groupFields", GROUP_FIELDS), ("pageSize", "1000")],
+            "contactGroups",
+        )
+        .await
+        .chain_err(|| "Could not list contact groups")
+    }
+
+    async fn list(&self, path: &str, query: &[(&str, &str)], items: &str) -> Result<Vec<Value>> {
+        let mut values = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut request = self.api.http().get(format!("{API}/{path}")).query(query);
+            if let Some(page_token) = page_token.as_ref() {
+                request = request.query(&[("pageToken", page_token)]);
+            }
+            let mut page: Value = self.api.send_json(request).await?;
+            if let Some(Value::Array(page_values)) = page.get_mut(items).map(Value::take) {
+                values.extend(page_values);
+            }
+            match page.get("nextPageToken").and_then(Value::as_str) {
+                Some(next_page_token) => page_token = Some(next_page_token.to_string()),
+                None => break,
+            }
+        }
+        Ok(values)
+    }
+}
+
+/// Writes all contacts and contact groups to `contacts/contacts.vcf` as vCard 4.0, and the API
+/// responses to `contacts/contacts.json`, which keeps the fields vCard has no property for.
+pub async fn back_up_contacts(
+    people: Arc<People>,
+    destinations: Vec<Arc<dyn Destination>>,
+    options: &BackupOptions,
+) -> Result<()> {
+    check_destinations(&destinations).await?;
+
+    let connections = people.list_connections().await?;
+    let contact_groups = people.list_contact_groups().await?;
+    log::info!("Found {} contacts in {} groups", connections.len(), contact_groups.len());
+    let persons = connections
+        .iter()
+        .map(|c| Person::deserialize(c).chain_err(|| "Invalid contact"))
+        .collect::<Result<Vec<Person>>>()?;
+    let groups = contact_groups
+        .iter()
+        .map(|g| ContactGroup::deserialize(g).chain_err(|| "Invalid contact group"))
+        .collect::<Result<Vec<ContactGroup>>>()?;
+
+    let vcards = to_vcards(&persons, &groups).into_bytes();
+    let dump = serde_json::to_vec_pretty(
+        &json!({"connections": connections, "contactGroups": contact_groups}),
+    )
+    .unwrap();
+    let (tx, rx) = mpsc::unbounded_channel();
+    for (name, mime_type, content) in
+        [("contacts.vcf", "text/vcard", vcards), ("contacts.json", "application/json", dump)]
+    {
+        let file = File {
+            id: Some(name.to_string()),
+            name: Some(name.to_string()),
+            mime_type: Some(mime_type.to_string()),
+            size: Some(content.len().to_string()),
+            modified_time: Some(Utc::now().to_rfc3339()),
+            ..Default::default()
+        };
+        let content: Content = Box::pin(stream::iter([Ok(content)]));
+        let key = format!("contacts/{name}");
+        let results =
+            copy_file(&destinations, &file, key, mime_type, async { Ok(content) }, options).await;
+        for (i, result) in results.into_iter().enumerate() {
+            tx.send((i, result)).unwrap();
+        }
+    }
+    finish_back_up(&destinations, rx, options).await
+}
+
+/// Formats contacts and user contact groups as vCard 4.0 (RFC 6350). Contacts list the groups they
+/// are in as CATEGORIES, and groups list their members as MEMBER of KIND group.
+fn to_vcards(persons: &[Person], groups: &[ContactGroup]) -> String {
+    let user_groups: HashMap<&str, &str> = groups
+        .iter()
+        .filter(|g| g.group_type == "USER_CONTACT_GROUP")
+        .map(|g| (g.resource_name.as_str(), g.formatted_name.as_str()))
+        .collect();
+    let mut lines = vec![];
+    for person in persons {
+        lines.extend(person_lines(person, &user_groups));
+    }
+    for group in groups.iter().filter(|g| user_groups.contains_key(g.resource_name.as_str())) {
+        lines.extend([
+            "BEGIN:VCARD".to_string(),
+            "VERSION:4.0".to_string(),
+            "KIND:group".to_string(),
+            format!("UID:{}", group.resource_name),
+            format!("FN:{}", escape(&group.formatted_name)),
+        ]);
+        for person in persons.iter().filter(|p| group_names(p).any(|g| g == group.resource_name)) {
+            lines.push(format!("MEMBER:{}", person.resource_name));
+        }
+        lines.push("END:VCARD".to_string());
+    }
+    lines.iter().map(|l| fold(l)).collect()
+}
+
+fn person_lines(person: &Person, user_groups: &HashMap<&str, &str>) -> Vec<String> {
+    let mut lines = vec![
+        "BEGIN:VCARD".to_string(),
+        "VERSION:4.0".to_string(),
+        format!("UID:{}", person.resource_name),
+    ];
+    let name = person.names.first();
+    let formatted_name = name
+        .and_then(|n| n.display_name.as_ref())
+        .or_else(|| person.organizations.iter().find_map(|o| o.name.as_ref()))
+        .or_else(|| person.email_addresses.iter().find_map(|e| e.value.as_ref()))
+        .unwrap_or(&person.resource_name);
+    lines.push(format!("FN:{}", escape(formatted_name)));
+    if let Some(name) = name {
+        lines.push(format!(
+            "N:{}",
+            components(&[
+                &name.family_name,
+                &name.given_name,
+                &name.middle_name,
+                &name.honorific_prefix,
+                &name.honorific_suffix,
+            ])
+        ));
+    }
+    for (property, values, phone) in [
+        ("NICKNAME", &person.nicknames, false),
+        ("EMAIL", &person.email_addresses, false),
+        ("TEL", &person.phone_numbers, true),
+        ("URL", &person.urls, false),
+        ("NOTE", &person.biographies, false),
+    ] {
+        for value in values {
+            if let Some(text) = value.value.as_ref() {
+                lines.push(format!(
+                    "{property}{}:{}",
+                    type_param(&value.kind, phone),
+                    escape(text)
+                ));
+            }
+        }
+    }
+    for address in &person.addresses {
+        lines.push(format!(
+            "ADR{}:{}",
+            type_param(&address.kind, false),
+            components(&[
+                &address.po_box,
+                &address.extended_address,
+                &address.street_address,
+                &address.city,
+                &address.region,
+                &address.postal_code,
+                &address.country,
+            ])
+        ));
+    }
+    for organization in &person.organizations {
+        if organization.name.is_some() || organization.department.is_some() {
+            lines.push(format!(
+                "ORG:{}",
+                components(&[&organization.name, &organization.department])
+            ));
+        }
+        if let Some(title) = organization.title.as_ref() {
+            lines.push(format!("TITLE:{}", escape(title)));
+        }
+    }
+    if let Some(birthday) = person.birthdays.first() {
+        match (birthday.date.as_ref(), birthday.text.as_ref()) {
+            (Some(date), _) if date.month > 0 && date.day > 0 => match date.year {
+                0 => lines.push(format!("BDAY:--{:02}{:02}", date.month, date.day)),
+                year => lines.push(format!("BDAY:{year:04}{:02}{:02}", date.month, date.day)),
+            },
+            (_, Some(text)) => lines.push(format!("BDAY;VALUE=text:{}", escape(text))),
+            _ => {}
+        }
+    }
+    for photo in person.photos.iter().filter(|p| !p.default) {
+        lines.push(format!("PHOTO:{}", photo.url));
+    }
+    let categories: Vec<String> =
+        group_names(person).filter_map(|g| user_groups.get(g)).map(|name| escape(name)).collect();
+    if !categories.is_empty() {
+        lines.push(format!("CATEGORIES:{}", categories.join(",")));
+    }
+    lines.push("END:VCARD".to_string());
+    lines
+}
+
+fn group_names(person: &Person) -> impl Iterator<Item = &str> {
+    person
+        .memberships
+        .iter()
+        .filter_map(|m| m.contact_group_membership.as_ref())
+        .map(|m| m.contact_group_resource_name.as_str())
+}
+
+/// A structured value like N or ADR, with its components separated by semicolons.
+fn components(values: &[&Option<String>]) -> String {
+    values
+        .iter()
+        .map(|v| v.as_deref().map(escape).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// The TYPE parameter for the types vCard has an equivalent for. Google also allows custom types.
+fn type_param(kind: &Option<String>, phone: bool) -> &'static str {
+    match (kind.as_deref(), phone) {
+        (Some("home"), _) => ";TYPE=home",
+        (Some("work"), _) => ";TYPE=work",
+        (Some("mobile"), true) => ";TYPE=cell",
+        (Some("homeFax"), true) => ";TYPE=home,fax",
+        (Some("workFax"), true) => ";TYPE=work,fax",
+        (Some("pager"), true) => ";TYPE=pager",
+        _ => "",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+    use serde_json::json;
+
+    use crate::contacts::{to_vcards, ContactGroup, Person};
+
+    #[test]
+    fn contacts_and_groups_are_written_as_vcards() {
+        let person = Person::deserialize(json!({
+            "resourceName": "people/c1",
+            "etag": "%Eg",
+            "names": [{"displayName": "Ann Smith", "familyName": "Smith", "givenName": "Ann"}],
+            "emailAddresses": [{"value": "ann@example.com", "type": "work"}],
+            "phoneNumbers": [{"value": "+49 30 1234", "type": "mobile"}],
+            "addresses": [{"streetAddress": "Main St. 1", "city": "Berlin", "type": "home"}],
+            "organizations": [{"name": "Example, Inc.", "title": "CTO"}],
+            "birthdays": [{"date": {"month": 4, "day": 1}}],
+            "biographies": [{"value": "Met at a conference;\nlikes tea"}],
+            "photos": [{"url": "https://lh3.googleusercontent.com/a", "default": true}],
+            "memberships": [
+                {"contactGroupMembership": {"contactGroupResourceName": "contactGroups/myContacts"}},
+                {"contactGroupMembership": {"contactGroupResourceName": "contactGroups/1a"}}
+            ]
+        }))
+        .unwrap();
+        let groups = vec![
+            ContactGroup {
+                resource_name: "contactGroups/myContacts".to_string(),
+                formatted_name: "My Contacts".to_string(),
+                group_type: "SYSTEM_CONTACT_GROUP".to_string(),
+            },
+            ContactGroup {
+                resource_name: "contactGroups/1a".to_string(),
+                formatted_name: "Family".to_string(),
+                group_type: "USER_CONTACT_GROUP".to_string(),
+            },
+        ];
+
+        assert_eq!(
+            to_vcards(&[person], &groups),
+            "BEGIN:VCARD\r\nVERSION:4.0\r\nUID:people/c1\r\nFN:Ann Smith\r\nN:Smith;Ann;;;\r\n\
+             EMAIL;TYPE=work:ann@example.com\r\nTEL;TYPE=cell:+49 30 1234\r\n\
+             NOTE:Met at a conference\\;\\nlikes tea\r\nADR;TYPE=home:;;Main St. 1;Berlin;;;\r\n\
+             ORG:Example\\, Inc.;\r\nTITLE:CTO\r\nBDAY:--0401\r\nCATEGORIES:Family\r\n\
+             END:VCARD\r\n\
+             BEGIN:VCARD\r\nVERSION:4.0\r\nKIND:group\r\nUID:contactGroups/1a\r\nFN:Family\r\n\
+             MEMBER:people/c1\r\nEND:VCARD\r\n"
+        );
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::BTreeSet;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDate, TimeZone, Timelike, Utc};
+
+use crate::config::Schedule;
+use crate::errors::{Error, Result};
+
+const MONTHS: [&str; 12] =
+    ["JAN", "FEB", "MAR", "APR", "MAY", "JUN", "JUL", "AUG", "SEP", "OCT", "NOV", "DEC"];
+const WEEK_DAYS: [&str; 7] = ["SUN", "MON", "TUE", "WED", "THU", "FRI", "SAT"];
+
+/// A cron expression in the format of EventBridge (and the CDK's `CronOptions`), evaluated in UTC.
+/// Fields take `*`, `?`, values, ranges `a-b`, lists `a,b` and steps `*/n` or `a/n`; months and
+/// week days can also be names like JAN or MON. Week days count from SUN=1 to SAT=7.
+#[derive(Debug, Clone)]
+pub struct Cron {
+    minutes: BTreeSet<u32>,
+    hours: BTreeSet<u32>,
+    days: Option<BTreeSet<u32>>,
+    months: BTreeSet<u32>,
+    week_days: Option<BTreeSet<u32>>,
+    years: BTreeSet<u32>,
+}
+
+impl Cron {
+    /// Builds the expression the CDK's `Schedule.cron` would: unset fields mean "every", and a day
+    /// of the month and a week day cannot both be given.
+    pub fn from_schedule(schedule: &Schedule) -> Result<Cron> {
+        let or_every = |field: &Option<String>| field.clone().unwrap_or_else(|| "*".to_string());
+        let day = match (&schedule.day, &schedule.week_day) {
+            (Some(_), Some(_)) => {
+                return Err(Error::from("Cannot supply both day and weekDay, use at most one"))
+            }
+            (None, Some(_)) => "?".to_string(),
+            _ => or_every(&schedule.day),
+        };
+        Cron::new(
+            &or_every(&schedule.minute),
+            &or_every(&schedule.hour),
+            &day,
+            &or_every(&schedule.month),
+            schedule.week_day.as_deref().unwrap_or("?"),
+            &or_every(&schedule.year),
+        )
+    }
+
+    pub fn new(
+        minute: &str,
+        hour: &str,
+        day: &str,
+        month: &str,
+        week_day: &str,
+        year: &str,
+    ) -> Result<Cron> {
+        let any = |field: &str| field == "*" || field == "?";
+        if !any(day) && !any(week_day) {
+            return Err(Error::from(format!(
+                "Cannot restrict both the day {day} and the week day {week_day}, one must be ?"
+            )));
+        }
+        Ok(Cron {
+            minutes: parse_field(minute, 0, 59, &[])?,
+            hours: parse_field(hour, 0, 23, &[])?,
+            days: (!any(day)).then(|| parse_field(day, 1, 31, &[])).transpose()?,
+            months: parse_field(month, 1, 12, &MONTHS)?,
+            week_days: (!any(week_day))
+                .then(|| parse_field(week_day, 1, 7, &WEEK_DAYS))
+                .transpose()?,
+            years: parse_field(year, 1970, 2199, &[])?,
+        })
+    }
+
+    /// The first time this expression matches strictly after `time`, if there is one.
+    pub fn next_after(&self, time: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = time.with_second(0)?.with_nanosecond(0)? + Duration::minutes(1);
+        let last_year = *self.years.last()? as i32;
+        let mut date = start.date_naive();
+        while date.year() <= last_year {
+            if self.matches(date) {
+                for hour in &self.hours {
+                    for minute in &self.minutes {
+                        let candidate =
+                            Utc.from_utc_datetime(&date.and_hms_opt(*hour, *minute, 0)?);
+                        if candidate >= start {
+                            return Some(candidate);
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+
+    fn matches(&self, date: NaiveDate) -> bool {
+        let day = self.days.as_ref().map(|days| days.contains(&date.day()));
+        let week_day = self
+            .week_days
+            .as_ref()
+            .map(|days| days.contains(&(date.weekday().num_days_from_sunday() + 1)));
+        day.or(week_day).unwrap_or(true)
+            && self.months.contains(&date.month())
+            && self.years.contains(&(date.year() as u32))
+    }
+}
+
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<BTreeSet<u32>> {
+    let value = |s: &str| -> Result<u32> {
+        let named = names.iter().position(|name| name.eq_ignore_ascii_case(s));
+        let value = match named {
+            Some(i) => min + i as u32,
+            None => {
+                s.parse().map_err(|_| Error::from(format!("Invalid cron value {s} in {field}")))?
+            }
+        };
+        match (min..=max).contains(&value) {
+            true => Ok(value),
+            false => Err(Error::from(format!("{value} in {field} is not in {min}-{max}"))),
+        }
+    };
+    let mut values = BTreeSet::new();
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, Some(step)),
+            None => (part, None),
+        };
+        let (first, last) = match range.split_once('-') {
+            _ if range == "*" || range == "?" => (min, max),
+            Some((first, last)) => (value(first)?, value(last)?),
+            None if step.is_some() => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        let step = match step.map(str::parse::<usize>) {
+            None => 1,
+            Some(Ok(step)) if step > 0 => step,
+            Some(_) => return Err(Error::from(format!("Invalid cron step in {field}"))),
+        };
+        values.extend((first..=last).step_by(step));
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+
+    use crate::config::Schedule;
+    use crate::cron::Cron;
+
+    #[test]
+    fn next_run_follows_the_cdk_schedule_semantics() {
+        let every_four_months = Cron::from_schedule(&Schedule {
+            minute: Some("0".to_string()),
+            hour: Some("0".to_string()),
+            day: Some("1".to_string()),
+            month: Some("*/4".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let workdays = Cron::from_schedule(&Schedule {
+            minute: Some("30".to_string()),
+            hour: Some("9".to_string()),
+            week_day: Some("MON-FRI".to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+        let saturday = Utc.with_ymd_and_hms(2024, 2, 17, 12, 0, 0).unwrap();
+
+        assert_eq!(
+            every_four_months.next_after(saturday),
+            Some(Utc.with_ymd_and_hms(2024, 5, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(
+            workdays.next_after(saturday),
+            Some(Utc.with_ymd_and_hms(2024, 2, 19, 9, 30, 0).unwrap())
+        );
+        assert_eq!(
+            workdays.next_after(Utc.with_ymd_and_hms(2024, 2, 19, 9, 30, 0).unwrap()),
+            Some(Utc.with_ymd_and_hms(2024, 2, 20, 9, 30, 0).unwrap())
+        );
+        assert!(Cron::from_schedule(&Schedule {
+            day: Some("1".to_string()),
+            week_day: Some("MON".to_string()),
+            ..Default::default()
+        })
+        .is_err());
+        assert!(Cron::new("0", "0", "1", "*", "MON", "*").is_err());
+        assert!(Cron::new("0", "0", "L", "*", "?", "*").is_err());
+        assert!(Cron::new("60", "0", "*", "*", "?", "*").is_err());
+        assert_eq!(Cron::new("0", "0", "31", "2", "?", "*").unwrap().next_after(saturday), None);
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use error_chain::ChainedError;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::cli::DriveArgs;
+use crate::cli_factories::GlobalArgs;
+use crate::cron::Cron;
+use crate::errors::{Error, Result, ResultExt};
+
+/// Wake up at least this often, so a suspended machine or a changed clock delays runs by no more.
+const MAX_SLEEP: Duration = Duration::from_secs(60);
+
+/// When every job last ran successfully, kept in a file so the daemon can catch up on runs it
+/// missed while it was down.
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct DaemonState {
+    pub last_runs: BTreeMap<String, DateTime<Utc>>,
+}
+
+impl DaemonState {
+    /// Reads the state, or starts from scratch if `path` does not exist yet.
+    pub fn load(path: &Path) -> Result<DaemonState> {
+        if !path.exists() {
+            return Ok(DaemonState::default());
+        }
+        let text = std::fs::read_to_string(path)
+            .chain_err(|| format!("Could not read {}", path.display()))?;
+        serde_json::from_str(&text).chain_err(|| format!("Invalid daemon state {}", path.display()))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, serde_json::to_vec_pretty(self).unwrap())
+            .and_then(|_| std::fs::rename(&temp, path))
+            .chain_err(|| format!("Could not write {}", path.display()))
+    }
+}
+
+/// Decides which jobs are due. A job is due when its schedule matched since its last run, so runs
+/// missed while the daemon was down are caught up once, not once per missed time. Jobs that never
+/// ran are first due at their next scheduled time after the daemon started.
+pub struct Scheduler {
+    jobs: Vec<(String, Cron)>,
+    state: DaemonState,
+    started: HashMap<String, DateTime<Utc>>,
+    running: HashSet<String>,
+    since: DateTime<Utc>,
+}
+
+impl Scheduler {
+    pub fn new(jobs: Vec<(String, Cron)>, state: DaemonState, now: DateTime<Utc>) -> Scheduler {
+        Scheduler { jobs, state, started: HashMap::new(), running: HashSet::new(), since: now }
+    }
+
+    pub fn state(&self) -> &DaemonState {
+        &self.state
+    }
+
+    fn next_run(&self, name: &str, cron: &Cron) -> Option<DateTime<Utc>> {
+        let last = self.started.get(name).or_else(|| self.state.last_runs.get(name));
+        cron.next_after(*last.unwrap_or(&self.since))
+    }
+
+    /// The jobs to start at `now`. A job whose previous run has not finished yet skips this run.
+    pub fn start_due(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let due: Vec<(String, DateTime<Utc>)> = self
+            .jobs
+            .iter()
+            .filter_map(|(name, cron)| Some((name.clone(), self.next_run(name, cron)?)))
+            .filter(|(_, next)| *next <= now)
+            .collect();
+        let mut start = vec![];
+        for (name, scheduled) in due {
+            self.started.insert(name.clone(), now);
+            if self.running.contains(&name) {
+                warn!("Skipping {name} scheduled for {scheduled}, the previous run is still going");
+            } else {
+                info!("Starting {name} scheduled for {scheduled}");
+                self.running.insert(name.clone());
+                start.push(name);
+            }
+        }
+        start
+    }
+
+    /// Records the end of the run of `name` that started at `started`. Only successful runs count
+    /// as last runs, so a daemon restarted after a failure tries again.
+    pub fn finished(&mut self, name: &str, started: DateTime<Utc>, succeeded: bool) {
+        self.running.remove(name);
+        if succeeded {
+            self.state.last_runs.insert(name.to_string(), started);
+        }
+    }
+
+    /// When the next job is due.
+    pub fn next_wake(&self) -> Option<DateTime<Utc>> {
+        self.jobs.iter().filter_map(|(name, cron)| self.next_run(name, cron)).min()
+    }
+}
+
+/// Runs `jobs` on their schedules until the process is stopped, keeping the time of every
+/// successful run in `state_file`. Different jobs may run at the same time. Every run is a task of
+/// its own, so a run that panics counts as failed instead of ending the daemon.
+pub async fn run_daemon(
+    jobs: Vec<(String, Cron, DriveArgs)>,
+    global: &GlobalArgs,
+    state_file: &Path,
+) -> Result<()> {
+    let schedules = jobs.iter().map(|(name, cron, _)| (name.clone(), cron.clone())).collect();
+    let mut scheduler = Scheduler::new(schedules, DaemonState::load(state_file)?, Utc::now());
+    let jobs: Vec<(String, Arc<DriveArgs>)> =
+        jobs.into_iter().map(|(name, _, args)| (name, Arc::new(args))).collect();
+    let global = Arc::new(global.clone());
+    let mut running = FuturesUnordered::new();
+    loop {
+        let now = Utc::now();
+        for name in scheduler.start_due(now) {
+            let (_, args) = jobs.iter().find(|(job, _)| *job == name).unwrap();
+            let (args, global) = (args.clone(), global.clone());
+            let task = tokio::spawn(async move { args.run(&global).await });
+            running.push(async move {
+                let result = task
+                    .await
+                    .unwrap_or_else(|e| Err(Error::from(format!("The run ended abruptly: {e}"))));
+                (name, now, result)
+            });
+        }
+        let sleep = scheduler
+            .next_wake()
+            .map(|next| (next - Utc::now()).to_std().unwrap_or_default())
+            .map_or(MAX_SLEEP, |sleep| sleep.min(MAX_SLEEP));
+        tokio::select! {
+            Some((name, started, result)) = running.next(), if !running.is_empty() => {
+                match &result {
+                    Ok(()) => info!("Finished {name}"),
+                    Err(e) => error!("{name} failed: {}", e.display_chain()),
+                }
+                scheduler.finished(&name, started, result.is_ok());
+                if let Err(e) = scheduler.state().save(state_file) {
+                    error!("Could not save the daemon state: {}", e.display_chain());
+                }
+            }
+            _ = tokio::time::sleep(sleep) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::cron::Cron;
+    use crate::daemon::{DaemonState, Scheduler};
+
+    #[test]
+    fn missed_runs_are_caught_up_once_and_overlapping_runs_are_skipped() {
+        let monthly = Cron::new("0", "0", "1", "*", "?", "*").unwrap();
+        let hourly = Cron::new("0", "*", "*", "*", "?", "*").unwrap();
+        let state = DaemonState {
+            last_runs: BTreeMap::from([(
+                "monthly".to_string(),
+                Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap(),
+            )]),
+        };
+        let now = Utc.with_ymd_and_hms(2024, 4, 10, 10, 0, 0).unwrap();
+        let mut scheduler = Scheduler::new(
+            vec![("monthly".to_string(), monthly), ("hourly".to_string(), hourly)],
+            state,
+            now,
+        );
+
+        assert_eq!(scheduler.start_due(now), vec!["monthly"]);
+        assert!(scheduler.start_due(now + Duration::minutes(1)).is_empty());
+        assert_eq!(scheduler.next_wake(), Some(now + Duration::minutes(60)));
+        assert_eq!(scheduler.start_due(now + Duration::minutes(60)), vec!["hourly"]);
+        assert!(scheduler.start_due(now + Duration::minutes(120)).is_empty());
+
+        scheduler.finished("hourly", now + Duration::minutes(60), true);
+        scheduler.finished("monthly", now, false);
+        assert_eq!(scheduler.start_due(now + Duration::minutes(180)), vec!["hourly"]);
+        assert_eq!(
+            scheduler.state().last_runs["monthly"],
+            Utc.with_ymd_and_hms(2024, 1, 1, 0, 0, 0).unwrap()
+        );
+        assert_eq!(scheduler.state().last_runs["hourly"], now + Duration::minutes(60));
+    }
+}
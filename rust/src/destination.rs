@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::path::{Component, Path};
+use std::pin::Pin;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use aws_sdk_s3::types::StorageClass;
+use aws_sdk_s3::Client;
+use futures::Stream;
+use url::Url;
+
+use crate::azure_blob::{AzureBlobDestination, AzureOptions};
+use crate::errors::{Error, Result, ResultExt};
+use crate::gcs::{GcsDestination, GcsOptions};
+use crate::local::LocalDestination;
+use crate::s3::{Encryption, ObjectLock, S3Destination};
+
+/// Where backups are written to and restored from. Objects are addressed by names relative to the
+/// destination's folder, e.g. `report.pdf` for `s3://bucket/some/folder/report.pdf`.
+#[async_trait]
+pub trait Destination: Send + Sync {
+    /// The URL the destination was created from, for log and error messages.
+    fn url(&self) -> &str;
+
+    /// Fails unless objects can be written to the destination. Called before any work starts.
+    async fn check_writable(&self) -> Result<()>;
+
+    /// Starts writing the object `name`. Nothing is visible under `name` until the writer is
+    /// finished. `storage_class` is ignored by destinations without storage classes.
+    async fn create(
+        &self,
+        name: &str,
+        storage_class: &StorageClass,
+        metadata: HashMap<String, String>,
+    ) -> Result<Box<dyn ObjectWriter>>;
+
+    /// Returns the metadata of the object `name`, or `None` if there is no such object.
+    async fn metadata(&self, name: &str) -> Result<Option<HashMap<String, String>>>;
+
+    async fn read(&self, name: &str) -> Result<ObjectReader>;
+
+    /// Lists all objects under the destination's folder, including those in subfolders.
+    async fn list(&self) -> Result<Vec<ObjectInfo>>;
+
+    async fn delete(&self, name: &str) -> Result<()>;
+}
+
+#[async_trait]
+pub trait ObjectWriter: Send {
+    async fn write(&mut self, data: &[u8]) -> Result<()>;
+
+    /// Makes the object visible. Returns the number of parts it was written in. If this fails,
+    /// nothing is left behind.
+    async fn finish(self: Box<Self>) -> Result<usize>;
+
+    /// Discards everything written so far.
+    async fn abort(self: Box<Self>);
+}
+
+pub struct ObjectReader {
+    pub metadata: HashMap<String, String>,
+    pub content: Pin<Box<dyn Stream<Item = Result<Vec<u8>>> + Send>>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObjectInfo {
+    pub name: String,
+    pub size: u64,
+}
+
+/// Settings for the destinations that support them.
+#[derive(Clone)]
+pub struct DestinationOptions {
+    pub s3: Client,
+    pub encryption: Encryption,
+    pub object_lock: ObjectLock,
+    pub azure: AzureOptions,
+    pub gcs: GcsOptions,
+}
+
+/// Creates the destination for `url`, chosen by its scheme: `s3://bucket/some/folder`,
+/// `azblob://container/some/folder`, `gs://bucket/some/folder` or `file:///some/local/folder`.
+pub fn destination_for(url: &str, options: &DestinationOptions) -> Result<Arc<dyn Destination>> {
+    let parsed = Url::parse(url).chain_err(|| format!("{url} is not a valid URL"))?;
+    match parsed.scheme() {
+        "s3" => Ok(Arc::new(S3Destination::new(
+            options.s3.clone(),
+            url,
+            options.encryption.clone(),
+            options.object_lock.clone(),
+        )?)),
+        "azblob" => {
+            reject_s3_only_options(options)?;
+            Ok(Arc::new(AzureBlobDestination::new(url, &options.azure)?))
+        }
+        "gs" => {
+            reject_s3_only_options(options)?;
+            Ok(Arc::new(GcsDestination::new(url, &options.gcs)?))
+        }
+        "file" => {
+            reject_s3_only_options(options)?;
+            let path = parsed
+                .to_file_path()
+                .map_err(|_| Error::from(format!("{url} is not a valid local path")))?;
+            Ok(Arc::new(LocalDestination::new(url, path)))
+        }
+        scheme => Err(Error::from(format!(
+            "Unsupported destination {url} with scheme {scheme}. Supported schemes: s3, azblob, gs, file"
+        ))),
+    }
+}
+
+fn reject_s3_only_options(options: &DestinationOptions) -> Result<()> {
+    if options.encryption.is_enabled() || options.object_lock.is_enabled() {
+        return Err(Error::from(
+            "Server-side encryption and Object Lock are only supported for s3:// destinations",
+        ));
+    }
+    Ok(())
+}
+
+/// The path as a relative `/`-separated key, or `None` if it is absolute or contains `..`. Object
+/// names and archive entries must pass this before they are joined to a local directory.
+pub(crate) fn safe_path(path: &Path) -> Option<String> {
+    let mut parts = vec![];
+    for component in path.components() {
+        match component {
+            Component::Normal(part) => parts.push(part.to_str()?),
+            Component::CurDir => {}
+            _ => return None,
+        }
+    }
+    (!parts.is_empty()).then(|| parts.join("/"))
+}
@@ -1,4 +1,6 @@
+use crate::drive_query::Query;
 use crate::errors::{Error, Result};
+use crate::filter::FileFilter;
 use crate::ResultExt;
 use async_trait::async_trait;
 use google_drive3::api::File;
@@ -6,7 +8,11 @@ use google_drive3::hyper::{client::HttpConnector, Body, Response};
 use google_drive3::hyper_rustls::HttpsConnector;
 use google_drive3::{api::FileList, hyper, hyper_rustls, DriveHub};
 use google_drive3::{oauth2, oauth2::authorized_user::AuthorizedUserSecret};
-use std::path::Path;
+use std::collections::VecDeque;
+use std::path::{Path, PathBuf};
+use url::Url;
+
+const FOLDER_MIME_TYPE: &str = "application/vnd.google-apps.folder";
 
 pub struct Drive {
     hub: DriveHub<HttpsConnector<HttpConnector>>,
@@ -19,69 +25,112 @@ impl Drive {
 
     pub async fn list_files_in_folder(&self, folder: &String) -> Result<Vec<File>> {
         let folder_id = self.folder_id_from_folder_name(folder, "root").await?;
+        self.list_files_in_folder_id(&folder_id)
+            .await
+            .chain_err(|| format!("Could not list files in {folder} folder in drive."))
+    }
 
-        let mut files = vec![];
+    /// Lists the files directly in the folder.
+    pub async fn list_files_in_folder_id(&self, folder_id: &str) -> Result<Vec<File>> {
+        self.files_in(folder_id, &Query::new()).await
+    }
 
-        let mut page_token: Option<String> = None;
-        loop {
-            let file_list_response = self
-                .list_files_in_folder_id_per_page(&folder_id, &mut page_token)
-                .await
-                .chain_err(|| format!("Could not list files in {folder} folder in drive."))?
-                .1;
-            for file in file_list_response.files.as_ref().unwrap() {
-                files.push(file.clone());
-            }
-            if file_list_response.next_page_token.is_none() {
-                break;
-            }
-            page_token = file_list_response.next_page_token;
-        }
-        Ok(files)
+    /// Lists the files in the folder and all its subfolders that `filter` matches. Every file is
+    /// named by its path relative to the folder, e.g. `Reports/2024/q1.pdf`, which is what the
+    /// include and exclude globs match and what the backup uses as the object key.
+    pub async fn list_matching_files_in_folder_id(
+        &self,
+        folder_id: &str,
+        filter: &FileFilter,
+    ) -> Result<Vec<File>> {
+        list_matching_files(folder_id, filter, self).await
     }
 
     pub async fn get_file_from(&self, path: &Path) -> Result<File> {
         get_file_from(path, self).await
     }
 
+    /// Resolves a backup source: a folder ID, a drive.google.com folder URL, an absolute path like
+    /// /Some/Folder, or the name of a folder directly in My Drive. Returns the folder ID.
+    pub async fn resolve_folder(&self, source: &str) -> Result<String> {
+        let folder = match DriveSource::parse(source)? {
+            DriveSource::Id(id) => self.get_file_by_id(&id).await?,
+            DriveSource::Path(path) => self.get_file_from(&path).await?,
+            DriveSource::Name(name) => {
+                let folders = self.folders_named(&name, "root").await?;
+                if folders.is_empty() && looks_like_id(&name) {
+                    self.get_file_by_id(&name).await?
+                } else {
+                    single(folders, &format!("folder named {name} in My Drive"))?
+                }
+            }
+        };
+        if folder.mime_type.as_deref() != Some(FOLDER_MIME_TYPE) {
+            return Err(Error::from(format!("{source} is not a folder.")));
+        }
+        Ok(folder.id.unwrap_or_default())
+    }
+
     pub async fn folder_id_from_folder_name(
         &self,
         folder: &String,
         parent: &str,
     ) -> Result<String> {
+        let folders = self.folders_named(folder, parent).await?;
+        Ok(single(folders, &format!("folder named {folder}"))?.id.unwrap_or_default())
+    }
+
+    async fn folders_named(&self, folder: &str, parent: &str) -> Result<Vec<File>> {
         let file_list_response = self
             .hub
             .files()
             .list()
-            .q(&*format!(
-                "name = '{folder}' and \
-                 mimeType = 'application/vnd.google-apps.folder' and \
-                 '{parent}' in parents"
-            ))
+            .q(&Query::new()
+                .eq("name", folder)
+                .eq("mimeType", FOLDER_MIME_TYPE)
+                .has("parents", parent)
+                .to_string())
             .param("fields", "files(id,name,parents,size,mimeType)")
             .doit()
             .await
             .chain_err(|| format!("Could not find {folder} folder in drive."))?
             .1;
-        let folders = file_list_response.files.as_ref().unwrap();
-        assert_eq!(folders.len(), 1);
-        Ok(folders.first().as_ref().unwrap().id.as_ref().unwrap().clone())
+        Ok(file_list_response.files.unwrap_or_default())
+    }
+
+    async fn get_file_by_id(&self, id: &str) -> Result<File> {
+        let (_, file) = self
+            .hub
+            .files()
+            .get(id)
+            .param("fields", "id,name,parents,mimeType")
+            .doit()
+            .await
+            .chain_err(|| format!("There is no file with ID {id} in drive."))?;
+        Ok(file)
     }
 
+    /// `conditions` are added to the query, see `FileFilter::query`.
     pub async fn list_files_in_folder_id_per_page(
         &self,
-        folder_id: &String,
+        folder_id: &str,
+        conditions: &Query,
         page_token: &mut Option<String>,
     ) -> google_drive3::Result<(Response<Body>, FileList)> {
         let mut list_query = self
             .hub
             .files()
             .list()
-            .q(format!(
-                "'{folder_id}' in parents and mimeType != 'application/vnd.google-apps.folder'"
-            )
-            .as_str())
-            .param("fields", "nextPageToken,files(id,name,parents,md5Checksum,size,mimeType)");
+            .q(&Query::new()
+                .has("parents", folder_id)
+                .ne("mimeType", FOLDER_MIME_TYPE)
+                .and(conditions.clone())
+                .to_string())
+            .param(
+                "fields",
+                "nextPageToken,\
+                 files(id,name,parents,md5Checksum,size,mimeType,modifiedTime,ownedByMe)",
+            );
         if page_token.is_some() {
             list_query = list_query.page_token(page_token.as_ref().unwrap().as_str());
         }
@@ -128,24 +177,27 @@ impl Drive {
 
         Ok(file_content)
     }
-    pub async fn trash_file(&self, file_id: &String) -> Result<()> {
+    pub async fn trash_file(&self, file_id: &str) -> Result<()> {
         let resp = self
             .hub
             .files()
-            .update(
-                {
-                    let mut d = File::default();
-                    d.trashed = Some(true);
-                    d
-                },
-                file_id,
-            )
+            .update(File { trashed: Some(true), ..Default::default() }, file_id)
             .doit_without_upload()
             .await?;
         assert!(resp.0.status().is_success());
         Ok(())
     }
 
+    /// The MIME type of the content `get_content_for` returns, i.e. the export format for Google
+    /// documents and the original MIME type for everything else.
+    pub fn content_mime_type_for(file: &File) -> &str {
+        let mime_type = file.mime_type.as_deref().unwrap_or_default();
+        match Drive::export_mime_types_from(mime_type) {
+            "" => mime_type,
+            export_mime_type => export_mime_type,
+        }
+    }
+
     fn export_mime_types_from(k: &str) -> &str {
         match k {
             "application/vnd.google-apps.spreadsheet" => {
@@ -181,15 +233,12 @@ pub async fn create_drive_hub(
 
 #[async_trait]
 impl GetFileFor for Drive {
-    async fn call(&self, folder_id: &String, filename: &String) -> Result<File> {
+    async fn call(&self, folder_id: &str, filename: &str) -> Result<Vec<File>> {
         let resp = self
             .hub
             .files()
             .list()
-            .q(&*format!(
-                "name = '{filename}' and \
-                 '{folder_id}' in parents"
-            ))
+            .q(&Query::new().eq("name", filename).has("parents", folder_id).to_string())
             .param("fields", "files(id,name,parents,size,mimeType)")
             .doit()
             .await
@@ -197,38 +246,181 @@ impl GetFileFor for Drive {
                 format!("Could not find {filename} folder in drive in folder_id {folder_id}.")
             })?;
         assert!(resp.0.status().is_success());
-        let files = resp.1.files.as_ref().unwrap();
-        assert_eq!(files.len(), 1);
-        Ok(files[0].clone())
+        Ok(resp.1.files.unwrap_or_default())
     }
 }
 
+#[async_trait]
+impl ListFolder for Drive {
+    async fn files_in(&self, folder_id: &str, conditions: &Query) -> Result<Vec<File>> {
+        let mut files = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let file_list_response = self
+                .list_files_in_folder_id_per_page(folder_id, conditions, &mut page_token)
+                .await
+                .chain_err(|| format!("Could not list files in folder {folder_id} in drive."))?
+                .1;
+            files.extend(file_list_response.files.unwrap_or_default());
+            if file_list_response.next_page_token.is_none() {
+                break;
+            }
+            page_token = file_list_response.next_page_token;
+        }
+        Ok(files)
+    }
+
+    async fn folders_in(&self, folder_id: &str) -> Result<Vec<File>> {
+        let mut folders = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut list_query = self
+                .hub
+                .files()
+                .list()
+                .q(&Query::new()
+                    .has("parents", folder_id)
+                    .eq("mimeType", FOLDER_MIME_TYPE)
+                    .to_string())
+                .param("fields", "nextPageToken,files(id,name)");
+            if let Some(page_token) = page_token.as_ref() {
+                list_query = list_query.page_token(page_token);
+            }
+            let file_list_response = list_query
+                .doit()
+                .await
+                .chain_err(|| format!("Could not list folders in folder {folder_id} in drive."))?
+                .1;
+            folders.extend(file_list_response.files.unwrap_or_default());
+            if file_list_response.next_page_token.is_none() {
+                break;
+            }
+            page_token = file_list_response.next_page_token;
+        }
+        Ok(folders)
+    }
+}
+
+#[async_trait]
+trait ListFolder {
+    /// All files but folders directly in the folder that match `conditions`.
+    async fn files_in(&self, folder_id: &str, conditions: &Query) -> Result<Vec<File>>;
+    /// All folders directly in the folder.
+    async fn folders_in(&self, folder_id: &str) -> Result<Vec<File>>;
+}
+
+async fn list_matching_files(
+    folder_id: &str,
+    filter: &FileFilter,
+    list_folder: &impl ListFolder,
+) -> Result<Vec<File>> {
+    let conditions = filter.query();
+    let mut files = vec![];
+    let mut folders = VecDeque::from([(folder_id.to_string(), String::new())]);
+    while let Some((folder_id, prefix)) = folders.pop_front() {
+        for file in list_folder.files_in(&folder_id, &conditions).await? {
+            let path = format!("{prefix}{}", file.name.as_deref().unwrap_or_default());
+            if filter.matches(&file, &path) {
+                files.push(File { name: Some(path), ..file });
+            }
+        }
+        for folder in list_folder.folders_in(&folder_id).await? {
+            let path = format!("{prefix}{}/", folder.name.unwrap_or_default());
+            folders.push_back((folder.id.unwrap_or_default(), path));
+        }
+    }
+    Ok(files)
+}
+
 #[async_trait]
 trait GetFileFor {
-    async fn call(&self, folder_id: &String, filename: &String) -> Result<File>;
+    /// All files called `filename` in the folder.
+    async fn call(&self, folder_id: &str, filename: &str) -> Result<Vec<File>>;
 }
 
 async fn get_file_from(path: &Path, get_file_for: &impl GetFileFor) -> Result<File> {
     if !path.is_absolute() {
         return Err(Error::from("Drive folder path muist be absolute (start with /)"));
     }
-    let mut file = File::default();
-    file.id = Some(String::from("root"));
+    let mut file = File { id: Some(String::from("root")), ..Default::default() };
+    let mut current = PathBuf::from("/");
     for part in path.strip_prefix("/").unwrap() {
-        file = get_file_for
-            .call(file.id.as_ref().unwrap(), &part.to_str().unwrap().to_string())
-            .await?;
+        current.push(part);
+        let files = get_file_for.call(file.id.as_ref().unwrap(), part.to_str().unwrap()).await?;
+        file = single(files, &current.display().to_string())?;
     }
     Ok(file)
 }
 
+/// The one file in `files`, or an error that names `description` and, if it is ambiguous, the IDs
+/// of all candidates.
+fn single(mut files: Vec<File>, description: &str) -> Result<File> {
+    match files.len() {
+        1 => Ok(files.remove(0)),
+        0 => Err(Error::from(format!("There is no {description} in drive."))),
+        n => {
+            let ids: Vec<&str> =
+                files.iter().map(|f| f.id.as_deref().unwrap_or_default()).collect();
+            Err(Error::from(format!(
+                "There are {n} files for {description} in drive. Use one of their IDs as the \
+                 source instead: {}",
+                ids.join(", ")
+            )))
+        }
+    }
+}
+
+/// The ways to name a backup source.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DriveSource {
+    Id(String),
+    Path(PathBuf),
+    Name(String),
+}
+
+impl DriveSource {
+    /// Parses https://drive.google.com/drive/folders/<id> URLs (including /u/<n>/ and ?id=<id>
+    /// variants), absolute paths and names. Anything else is a name, which may be a bare ID.
+    pub fn parse(source: &str) -> Result<DriveSource> {
+        if source.starts_with("https://") || source.starts_with("http://") {
+            let url = Url::parse(source).chain_err(|| format!("{source} is not a valid URL"))?;
+            if url.host_str() != Some("drive.google.com") {
+                return Err(Error::from(format!("{source} is not a Google Drive URL")));
+            }
+            let segments: Vec<&str> =
+                url.path_segments().map(Iterator::collect).unwrap_or_default();
+            let id = segments
+                .windows(2)
+                .find(|w| w[0] == "folders" || w[0] == "d")
+                .map(|w| w[1].to_string())
+                .or_else(|| url.query_pairs().find(|(k, _)| k == "id").map(|(_, v)| v.into_owned()))
+                .filter(|id| !id.is_empty())
+                .ok_or_else(|| Error::from(format!("{source} does not contain a folder ID")))?;
+            return Ok(DriveSource::Id(id));
+        }
+        if source.starts_with('/') {
+            return Ok(DriveSource::Path(PathBuf::from(source)));
+        }
+        Ok(DriveSource::Name(source.to_string()))
+    }
+}
+
+/// Drive IDs are long strings of letters, digits, - and _.
+fn looks_like_id(s: &str) -> bool {
+    s.len() >= 19 && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::drive::{get_file_from, GetFileFor};
+    use crate::drive::{
+        get_file_from, list_matching_files, looks_like_id, DriveSource, GetFileFor, ListFolder,
+    };
+    use crate::drive_query::Query;
     use crate::errors::{Error, Result};
+    use crate::filter::{parse_patterns, FileFilter};
     use async_trait::async_trait;
     use google_drive3::api::File;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
 
     #[derive(Debug, Clone)]
     struct Node {
@@ -238,8 +430,8 @@ mod tests {
     }
 
     impl Node {
-        fn find_node(&self, id: &String) -> Option<&Node> {
-            if &self.id == id {
+        fn find_node(&self, id: &str) -> Option<&Node> {
+            if self.id == id {
                 return Some(self);
             }
             for c in self.children.iter() {
@@ -258,21 +450,107 @@ mod tests {
 
     #[async_trait]
     impl GetFileFor for TestDrive {
-        async fn call(&self, folder_id: &String, filename: &String) -> Result<File> {
-            for c in self.tree.find_node(folder_id).unwrap().children.iter() {
-                if &c.name == filename {
-                    return Ok({
-                        let mut f = File::default();
-                        f.name = Some(c.name.clone());
-                        f.id = Some(c.id.clone());
-                        f
-                    });
-                }
-            }
-            Err(Error::from("NOT FOUND"))
+        async fn call(&self, folder_id: &str, filename: &str) -> Result<Vec<File>> {
+            let node = self.tree.find_node(folder_id).ok_or_else(|| Error::from("NOT FOUND"))?;
+            Ok(node
+                .children
+                .iter()
+                .filter(|c| c.name == filename)
+                .map(|c| File {
+                    name: Some(c.name.clone()),
+                    id: Some(c.id.clone()),
+                    ..Default::default()
+                })
+                .collect())
+        }
+    }
+
+    /// Nodes without children are files, the others folders.
+    #[async_trait]
+    impl ListFolder for TestDrive {
+        async fn files_in(&self, folder_id: &str, _conditions: &Query) -> Result<Vec<File>> {
+            let node = self.tree.find_node(folder_id).ok_or_else(|| Error::from("NOT FOUND"))?;
+            Ok(node
+                .children
+                .iter()
+                .filter(|c| c.children.is_empty())
+                .map(|c| File {
+                    name: Some(c.name.clone()),
+                    id: Some(c.id.clone()),
+                    ..Default::default()
+                })
+                .collect())
+        }
+
+        async fn folders_in(&self, folder_id: &str) -> Result<Vec<File>> {
+            let node = self.tree.find_node(folder_id).ok_or_else(|| Error::from("NOT FOUND"))?;
+            Ok(node
+                .children
+                .iter()
+                .filter(|c| !c.children.is_empty())
+                .map(|c| File {
+                    name: Some(c.name.clone()),
+                    id: Some(c.id.clone()),
+                    ..Default::default()
+                })
+                .collect())
         }
     }
 
+    #[tokio::test]
+    async fn files_in_subfolders_are_listed_and_filtered_by_their_relative_path() {
+        let node = |id: &str, name: &str, children: Vec<Node>| Node {
+            id: id.to_string(),
+            name: name.to_string(),
+            children,
+        };
+        let tree = node(
+            "root",
+            "",
+            vec![
+                node("1", "a.pdf", vec![]),
+                node(
+                    "2",
+                    "Reports",
+                    vec![
+                        node("3", "q1.pdf", vec![]),
+                        node("4", "Cache", vec![node("5", "q1.pdf", vec![])]),
+                    ],
+                ),
+                node("6", "Cache", vec![node("7", "b.pdf", vec![]), node("8", "c.txt", vec![])]),
+            ],
+        );
+        let drive = TestDrive { tree };
+        let paths = |files: Vec<File>| -> Vec<(String, String)> {
+            files.into_iter().map(|f| (f.id.unwrap(), f.name.unwrap())).collect()
+        };
+
+        let all = list_matching_files("root", &FileFilter::default(), &drive).await.unwrap();
+        assert_eq!(
+            paths(all),
+            [
+                ("1", "a.pdf"),
+                ("3", "Reports/q1.pdf"),
+                ("7", "Cache/b.pdf"),
+                ("8", "Cache/c.txt"),
+                ("5", "Reports/Cache/q1.pdf")
+            ]
+            .map(|(id, path)| (id.to_string(), path.to_string()))
+        );
+
+        let filter = FileFilter {
+            include: parse_patterns(&["*.pdf".to_string()]).unwrap(),
+            exclude: parse_patterns(&["Cache/*".to_string()]).unwrap(),
+            ..Default::default()
+        };
+        let pdfs = list_matching_files("root", &filter, &drive).await.unwrap();
+        assert_eq!(
+            paths(pdfs),
+            [("1", "a.pdf"), ("3", "Reports/q1.pdf"), ("5", "Reports/Cache/q1.pdf")]
+                .map(|(id, path)| (id.to_string(), path.to_string()))
+        );
+    }
+
     #[tokio::test]
     async fn it_works() {
         let tree = Node {
@@ -294,15 +572,45 @@ mod tests {
                     }],
                 },
                 Node { id: "3".to_string(), name: "three".to_string(), children: vec![] },
+                Node { id: "6".to_string(), name: "three".to_string(), children: vec![] },
             ],
         };
 
-        let a = get_file_from(&Path::new("/two//four/"), &TestDrive { tree: tree.clone() })
+        let a = get_file_from(Path::new("/two//four/"), &TestDrive { tree: tree.clone() })
             .await
             .unwrap();
         assert_eq!(a.id.unwrap(), "4");
-        let a = get_file_from(&Path::new("two/four"), &TestDrive { tree: tree.clone() }).await;
+        let a = get_file_from(Path::new("two/four"), &TestDrive { tree: tree.clone() }).await;
         assert!(a.is_err());
         assert!(a.unwrap_err().to_string().contains("muist be absolute"));
+        let a = get_file_from(Path::new("/three"), &TestDrive { tree: tree.clone() }).await;
+        assert_eq!(
+            a.unwrap_err().to_string(),
+            "There are 2 files for /three in drive. Use one of their IDs as the source instead: \
+             3, 6"
+        );
+        let a = get_file_from(Path::new("/two/six"), &TestDrive { tree: tree.clone() }).await;
+        assert_eq!(a.unwrap_err().to_string(), "There is no /two/six in drive.");
+    }
+
+    #[test]
+    fn sources_can_be_ids_urls_paths_or_names() {
+        let id = "1AbC-dEf_GhIjKlMnOpQrStUvWxYz0123";
+        for url in [
+            format!("https://drive.google.com/drive/folders/{id}"),
+            format!("https://drive.google.com/drive/u/1/folders/{id}?usp=sharing"),
+            format!("https://drive.google.com/open?id={id}"),
+        ] {
+            assert_eq!(DriveSource::parse(&url).unwrap(), DriveSource::Id(id.to_string()));
+        }
+        assert_eq!(
+            DriveSource::parse("/My Drive/Photos").unwrap(),
+            DriveSource::Path(PathBuf::from("/My Drive/Photos"))
+        );
+        assert_eq!(DriveSource::parse(id).unwrap(), DriveSource::Name(id.to_string()));
+        assert!(looks_like_id(id));
+        assert!(!looks_like_id("Photos"));
+        assert!(DriveSource::parse("https://example.com/drive/folders/x").is_err());
+        assert!(DriveSource::parse("https://drive.google.com/drive/my-drive").is_err());
     }
 }
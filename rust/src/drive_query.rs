@@ -0,0 +1,119 @@
+use std::fmt;
+
+/// A Drive search query, the `q` parameter of `files.list`. Terms are joined with `and`. Field
+/// names come from the code, values are always quoted and escaped, so names like "Peter's Files"
+/// cannot break or change the query.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Query {
+    terms: Vec<String>,
+}
+
+impl Query {
+    pub fn new() -> Query {
+        Query::default()
+    }
+
+    /// `field = 'value'`
+    pub fn eq(self, field: &str, value: &str) -> Query {
+        self.term(format!("{field} = {}", quote(value)))
+    }
+
+    /// `field != 'value'`
+    pub fn ne(self, field: &str, value: &str) -> Query {
+        self.term(format!("{field} != {}", quote(value)))
+    }
+
+    /// `field contains 'value'`
+    pub fn contains(self, field: &str, value: &str) -> Query {
+        self.term(format!("{field} contains {}", quote(value)))
+    }
+
+    /// `field >= 'value'`, e.g. for RFC 3339 times.
+    pub fn ge(self, field: &str, value: &str) -> Query {
+        self.term(format!("{field} >= {}", quote(value)))
+    }
+
+    /// `field < 'value'`
+    pub fn lt(self, field: &str, value: &str) -> Query {
+        self.term(format!("{field} < {}", quote(value)))
+    }
+
+    /// `'value' in field`, for collections like `parents` and `owners`.
+    pub fn has(self, field: &str, value: &str) -> Query {
+        self.term(format!("{} in {field}", quote(value)))
+    }
+
+    /// Matches when `query` does not.
+    pub fn not(self, query: Query) -> Query {
+        match query.terms.is_empty() {
+            true => self,
+            false => self.term(format!("not {}", query.grouped())),
+        }
+    }
+
+    /// Matches when any of `alternatives` does.
+    pub fn any(self, alternatives: impl IntoIterator<Item = Query>) -> Query {
+        let alternatives: Vec<String> = alternatives.into_iter().map(|q| q.grouped()).collect();
+        match alternatives.len() {
+            0 => self,
+            1 => self.term(alternatives.into_iter().next().unwrap()),
+            _ => self.term(format!("({})", alternatives.join(" or "))),
+        }
+    }
+
+    /// Matches when this query and `other` do.
+    pub fn and(mut self, other: Query) -> Query {
+        self.terms.extend(other.terms);
+        self
+    }
+
+    fn term(mut self, term: String) -> Query {
+        self.terms.push(term);
+        self
+    }
+
+    /// The query as a single term.
+    fn grouped(&self) -> String {
+        match self.terms.len() {
+            1 => self.terms[0].clone(),
+            _ => format!("({self})"),
+        }
+    }
+}
+
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.terms.join(" and "))
+    }
+}
+
+/// A string literal in a Drive query: in single quotes, with `'` and `\` escaped by a backslash.
+pub fn quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::drive_query::{quote, Query};
+
+    #[test]
+    fn values_are_quoted_and_escaped() {
+        assert_eq!(quote("Peter's Files"), r"'Peter\'s Files'");
+        assert_eq!(quote(r"C:\Backups\"), r"'C:\\Backups\\'");
+        assert_eq!(quote(r"\' or name != '"), r"'\\\' or name != \''");
+        assert_eq!(quote("Fotos – Ära 日本"), "'Fotos – Ära 日本'");
+
+        let query = Query::new()
+            .eq("name", "Peter's Files")
+            .has("parents", "root")
+            .any([Query::new().contains("mimeType", "image/"), Query::new().eq("mimeType", "a/b")])
+            .not(Query::new().has("owners", "me"))
+            .and(Query::new().lt("modifiedTime", "2024-01-01T00:00:00Z"));
+        assert_eq!(
+            query.to_string(),
+            "name = 'Peter\\'s Files' and 'root' in parents and (mimeType contains 'image/' or \
+             mimeType = 'a/b') and not 'me' in owners and modifiedTime < '2024-01-01T00:00:00Z'"
+        );
+        assert_eq!(Query::new().any([]).not(Query::new()).to_string(), "");
+    }
+}
@@ -0,0 +1,326 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use aws_sdk_s3::types::StorageClass;
+use futures::future::join_all;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::destination::{Destination, ObjectWriter};
+use crate::errors::{Error, Result, ResultExt};
+
+/// Chunks buffered for each destination. Only once a destination is this far behind does `write`
+/// wait for it.
+const BUFFERED_CHUNKS: usize = 8;
+
+/// Writes the same object to several destinations at once. Every destination is written by its own
+/// task, so a slow one does not hold up the others until its buffer is full. A destination that
+/// fails is aborted and dropped, while the others carry on, so one broken or slow target cannot
+/// corrupt the others.
+pub struct FanOut {
+    name: String,
+    lanes: Vec<std::result::Result<Lane, Error>>,
+}
+
+/// The task writing to one destination. Without a `sender`, the task has failed and ended.
+struct Lane {
+    sender: Option<mpsc::Sender<Message>>,
+    task: JoinHandle<Result<usize>>,
+}
+
+enum Message {
+    Data(Arc<Vec<u8>>),
+    Finish,
+}
+
+impl Lane {
+    fn spawn(mut writer: Box<dyn ObjectWriter>) -> Lane {
+        let (sender, mut receiver) = mpsc::channel(BUFFERED_CHUNKS);
+        let task = tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                match message {
+                    Message::Data(data) => {
+                        if let Err(e) = writer.write(&data).await {
+                            writer.abort().await;
+                            return Err(e);
+                        }
+                    }
+                    Message::Finish => return writer.finish().await,
+                }
+            }
+            // The FanOut was aborted or dropped before the object was finished.
+            writer.abort().await;
+            Err(Error::from("Aborted"))
+        });
+        Lane { sender: Some(sender), task }
+    }
+
+    async fn join(self) -> Result<usize> {
+        self.task.await.map_err(|e| Error::from(format!("Upload task failed: {e}")))?
+    }
+}
+
+impl FanOut {
+    pub async fn create(
+        destinations: &[Arc<dyn Destination>],
+        name: &str,
+        storage_class: &StorageClass,
+        metadata: HashMap<String, String>,
+    ) -> FanOut {
+        let lanes =
+            join_all(destinations.iter().map(|d| d.create(name, storage_class, metadata.clone())))
+                .await
+                .into_iter()
+                .map(|result| result.map(Lane::spawn))
+                .collect();
+        FanOut { name: name.to_string(), lanes }
+    }
+
+    /// Hands `data` to all destinations that have not failed yet. Fails only once every
+    /// destination has failed, since there is no point in producing more data then.
+    pub async fn write(&mut self, data: &[u8]) -> Result<()> {
+        let data = Arc::new(data.to_vec());
+        for lane in self.lanes.iter_mut().flatten() {
+            if let Some(sender) = lane.sender.as_ref() {
+                if sender.send(Message::Data(data.clone())).await.is_err() {
+                    lane.sender = None;
+                }
+            }
+        }
+        if self.lanes.iter().flatten().all(|lane| lane.sender.is_none()) {
+            return Err(Error::from(format!("Could not write {} to any destination", self.name)));
+        }
+        Ok(())
+    }
+
+    /// Finishes the object in every destination, waiting for all of them to write what they have
+    /// buffered. Returns one result per destination, in the order of the destinations given to
+    /// `create`, with the number of parts written on success.
+    pub async fn finish(self) -> Vec<Result<usize>> {
+        let name = self.name;
+        join_all(self.lanes.into_iter().map(|lane| async {
+            let mut lane = lane?;
+            if let Some(sender) = lane.sender.take() {
+                // If the task failed meanwhile, its error is what join returns.
+                let _ = sender.send(Message::Finish).await;
+            }
+            lane.join().await
+        }))
+        .await
+        .into_iter()
+        .map(|r| r.chain_err(|| format!("Could not write {name}")))
+        .collect()
+    }
+
+    /// Aborts the object in every destination and returns `error` once per destination, or the
+    /// error of the destinations that had already failed.
+    pub async fn abort(self, error: &str) -> Vec<Result<usize>> {
+        let name = self.name;
+        join_all(self.lanes.into_iter().map(|lane| async {
+            let e = match lane {
+                Err(e) => e,
+                Ok(mut lane) => {
+                    // Dropping the sender makes the task abort the object.
+                    let failed = lane.sender.take().is_none();
+                    match lane.join().await {
+                        Err(e) if failed => e,
+                        _ => Error::from(error),
+                    }
+                }
+            };
+            Err(Error::from(format!("Could not write {name}: {e}")))
+        }))
+        .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use aws_sdk_s3::types::StorageClass;
+    use tokio::sync::Semaphore;
+
+    use crate::destination::{Destination, ObjectInfo, ObjectReader, ObjectWriter};
+    use crate::errors::{Error, Result};
+    use crate::fan_out::FanOut;
+    use crate::local::LocalDestination;
+
+    /// Accepts the first write and fails on every following one.
+    struct FailingDestination;
+
+    struct FailingWriter {
+        writes: usize,
+    }
+
+    #[async_trait]
+    impl Destination for FailingDestination {
+        fn url(&self) -> &str {
+            "failing://"
+        }
+        async fn check_writable(&self) -> Result<()> {
+            Ok(())
+        }
+        async fn create(
+            &self,
+            _: &str,
+            _: &StorageClass,
+            _: HashMap<String, String>,
+        ) -> Result<Box<dyn ObjectWriter>> {
+            Ok(Box::new(FailingWriter { writes: 0 }))
+        }
+        async fn metadata(&self, _: &str) -> Result<Option<HashMap<String, String>>> {
+            Ok(None)
+        }
+        async fn read(&self, _: &str) -> Result<ObjectReader> {
+            Err(Error::from("not readable"))
+        }
+        async fn list(&self) -> Result<Vec<ObjectInfo>> {
+            Ok(vec![])
+        }
+        async fn delete(&self, _: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl ObjectWriter for FailingWriter {
+        async fn write(&mut self, _: &[u8]) -> Result<()> {
+            self.writes += 1;
+            if self.writes > 1 {
+                return Err(Error::from("disk full"));
+            }
+            Ok(())
+        }
+        async fn finish(self: Box<Self>) -> Result<usize> {
+            Ok(1)
+        }
+        async fn abort(self: Box<Self>) {}
+    }
+
+    /// Records what is written, each write waiting for a permit of `gate` if there is one.
+    struct RecordingDestination {
+        gate: Option<Arc<Semaphore>>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    struct RecordingWriter {
+        gate: Option<Arc<Semaphore>>,
+        written: Arc<Mutex<Vec<u8>>>,
+    }
+
+    #[async_trait]
+    impl Destination for RecordingDestination {
+        fn url(&self) -> &str {
+            "recording://"
+        }
+        async fn check_writable(&self) -> Result<()> {
+            Ok(())
+        }
+        async fn create(
+            &self,
+            _: &str,
+            _: &StorageClass,
+            _: HashMap<String, String>,
+        ) -> Result<Box<dyn ObjectWriter>> {
+            Ok(Box::new(RecordingWriter { gate: self.gate.clone(), written: self.written.clone() }))
+        }
+        async fn metadata(&self, _: &str) -> Result<Option<HashMap<String, String>>> {
+            Ok(None)
+        }
+        async fn read(&self, _: &str) -> Result<ObjectReader> {
+            Err(Error::from("not readable"))
+        }
+        async fn list(&self) -> Result<Vec<ObjectInfo>> {
+            Ok(vec![])
+        }
+        async fn delete(&self, _: &str) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    #[async_trait]
+    impl ObjectWriter for RecordingWriter {
+        async fn write(&mut self, data: &[u8]) -> Result<()> {
+            if let Some(gate) = self.gate.as_ref() {
+                gate.acquire().await.unwrap().forget();
+            }
+            self.written.lock().unwrap().extend_from_slice(data);
+            Ok(())
+        }
+        async fn finish(self: Box<Self>) -> Result<usize> {
+            Ok(1)
+        }
+        async fn abort(self: Box<Self>) {}
+    }
+
+    #[tokio::test]
+    async fn a_slow_destination_does_not_hold_up_the_others() {
+        let gate = Arc::new(Semaphore::new(0));
+        let slow = Arc::new(Mutex::new(vec![]));
+        let fast = Arc::new(Mutex::new(vec![]));
+        let destinations: Vec<Arc<dyn Destination>> = vec![
+            Arc::new(RecordingDestination { gate: Some(gate.clone()), written: slow.clone() }),
+            Arc::new(RecordingDestination { gate: None, written: fast.clone() }),
+        ];
+
+        let mut fan_out =
+            FanOut::create(&destinations, "a.txt", &StorageClass::Standard, HashMap::new()).await;
+        for chunk in [b"a", b"b", b"c"] {
+            fan_out.write(chunk).await.unwrap();
+        }
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while fast.lock().unwrap().len() < 3 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(slow.lock().unwrap().is_empty());
+
+        gate.add_permits(3);
+        let results = fan_out.finish().await;
+
+        assert!(results.iter().all(Result::is_ok));
+        assert_eq!(*slow.lock().unwrap(), b"abc");
+        assert_eq!(*fast.lock().unwrap(), b"abc");
+    }
+
+    #[tokio::test]
+    async fn a_failing_destination_does_not_affect_the_others() {
+        let dir = std::env::temp_dir().join(format!("g2s3-fan-out-test-{}", std::process::id()));
+        let destinations: Vec<Arc<dyn Destination>> = vec![
+            Arc::new(FailingDestination),
+            Arc::new(LocalDestination::new("file:///test", dir.clone())),
+        ];
+
+        let mut fan_out =
+            FanOut::create(&destinations, "a.txt", &StorageClass::Standard, HashMap::new()).await;
+        fan_out.write(b"hello ").await.unwrap();
+        fan_out.write(b"world").await.unwrap();
+        let results = fan_out.finish().await;
+
+        assert!(results[0].as_ref().unwrap_err().to_string().contains("a.txt"));
+        assert_eq!(results[1].as_ref().unwrap(), &1);
+        assert_eq!(std::fs::read(dir.join("a.txt")).unwrap(), b"hello world");
+
+        let mut fan_out =
+            FanOut::create(&destinations[..1], "b.txt", &StorageClass::Standard, HashMap::new())
+                .await;
+        fan_out.write(b"hello ").await.unwrap();
+        fan_out.write(b"world").await.unwrap();
+        // The failure surfaces in write once the destination's task has run into it.
+        let failed = tokio::time::timeout(Duration::from_secs(5), async {
+            while fan_out.write(b"!").await.is_ok() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await;
+        assert!(failed.is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
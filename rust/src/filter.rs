@@ -0,0 +1,183 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use glob::Pattern;
+use google_drive3::api::File;
+
+use crate::drive_query::Query;
+use crate::errors::{Error, Result, ResultExt};
+
+/// Whose files to back up.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Owner {
+    #[default]
+    Anyone,
+    /// Files owned by the authorized user.
+    Me,
+    /// Files others own and share with the authorized user.
+    Others,
+}
+
+impl FromStr for Owner {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Owner> {
+        match s {
+            "anyone" => Ok(Owner::Anyone),
+            "me" => Ok(Owner::Me),
+            "others" => Ok(Owner::Others),
+            _ => {
+                Err(Error::from(format!("Unknown owner {s}. Possible values: anyone, me, others")))
+            }
+        }
+    }
+}
+
+/// Selects the Drive files to back up. Everything that the Drive `q` query can express is pushed
+/// down into it, so excluded files are not even listed; the rest is checked by `matches`.
+#[derive(Clone, Debug, Default)]
+pub struct FileFilter {
+    /// Globs on the path of a file relative to the source folder, e.g. `*.pdf` or `Cache/*`; if any
+    /// are given, one must match. `*` also matches `/`, so `*.pdf` matches PDFs in subfolders too.
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+    /// Globs on the Drive MIME type, e.g. `image/*`; if any are given, one must match.
+    pub mime_types: Vec<Pattern>,
+    pub exclude_mime_types: Vec<Pattern>,
+    /// Files of unknown size, i.e. Google documents, pass the size limits.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub modified_after: Option<DateTime<Utc>>,
+    pub modified_before: Option<DateTime<Utc>>,
+    pub owner: Owner,
+}
+
+impl FileFilter {
+    /// The conditions for the Drive query. They select a superset of the files `matches` accepts.
+    pub fn query(&self) -> Query {
+        let mut query = Query::new();
+        // Drive can only match MIME types exactly or by substring, so patterns are pushed down as
+        // "contains" their literal prefix, which is looser than the glob. Exclusions can only be
+        // pushed down when they are exact, since a looser one would exclude too much.
+        let literal = |pattern: &Pattern| {
+            let s = pattern.as_str();
+            s[..s.find(['*', '?', '[']).unwrap_or(s.len())].to_string()
+        };
+        if self.mime_types.iter().all(|p| !literal(p).is_empty()) {
+            query = query.any(self.mime_types.iter().map(|pattern| match literal(pattern) {
+                prefix if prefix == pattern.as_str() => Query::new().eq("mimeType", &prefix),
+                prefix => Query::new().contains("mimeType", &prefix),
+            }));
+        }
+        for pattern in &self.exclude_mime_types {
+            if literal(pattern) == pattern.as_str() {
+                query = query.ne("mimeType", pattern.as_str());
+            }
+        }
+        let time = |time: &DateTime<Utc>| time.format("%Y-%m-%dT%H:%M:%SZ").to_string();
+        if let Some(after) = &self.modified_after {
+            query = query.ge("modifiedTime", &time(after));
+        }
+        if let Some(before) = &self.modified_before {
+            query = query.lt("modifiedTime", &time(before));
+        }
+        match self.owner {
+            Owner::Anyone => query,
+            Owner::Me => query.has("owners", "me"),
+            Owner::Others => query.not(Query::new().has("owners", "me")),
+        }
+    }
+
+    /// Whether `file`, at `path` relative to the source folder, is backed up.
+    pub fn matches(&self, file: &File, path: &str) -> bool {
+        let mime_type = file.mime_type.as_deref().unwrap_or_default();
+        let size = file.size.as_ref().and_then(|s| s.parse::<u64>().ok());
+        let modified = file
+            .modified_time
+            .as_ref()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .map(|t| t.with_timezone(&Utc));
+        let any = |patterns: &[Pattern], s: &str| patterns.iter().any(|p| p.matches(s));
+
+        (self.include.is_empty() || any(&self.include, path))
+            && !any(&self.exclude, path)
+            && (self.mime_types.is_empty() || any(&self.mime_types, mime_type))
+            && !any(&self.exclude_mime_types, mime_type)
+            && size.is_none_or(|size| self.min_size.is_none_or(|min| size >= min))
+            && size.is_none_or(|size| self.max_size.is_none_or(|max| size <= max))
+            && self.modified_after.is_none_or(|after| modified.is_some_and(|m| m >= after))
+            && self.modified_before.is_none_or(|before| modified.is_some_and(|m| m < before))
+            && match self.owner {
+                Owner::Anyone => true,
+                Owner::Me => file.owned_by_me == Some(true),
+                Owner::Others => file.owned_by_me == Some(false),
+            }
+    }
+}
+
+pub fn parse_patterns(patterns: &[String]) -> Result<Vec<Pattern>> {
+    patterns.iter().map(|p| Pattern::new(p).chain_err(|| format!("Invalid pattern {p}"))).collect()
+}
+
+/// Parses an RFC 3339 time or a date, which means midnight UTC.
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .chain_err(|| format!("Invalid time {s}. Use e.g. 2024-01-31 or 2024-01-31T12:00:00Z"))
+}
+
+#[cfg(test)]
+mod tests {
+    use google_drive3::api::File;
+
+    use crate::filter::{parse_patterns, parse_time, FileFilter, Owner};
+
+    fn file(mime_type: &str, size: Option<u64>, modified: &str, owned_by_me: bool) -> File {
+        File {
+            mime_type: Some(mime_type.to_string()),
+            size: size.map(|s| s.to_string()),
+            modified_time: Some(modified.to_string()),
+            owned_by_me: Some(owned_by_me),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn filters_match_locally_and_push_down_what_drive_can_query() {
+        let filter = FileFilter {
+            include: vec![],
+            exclude: parse_patterns(&["cache-*".to_string(), "*.tmp".to_string()]).unwrap(),
+            mime_types: parse_patterns(&["image/*".to_string(), "application/pdf".to_string()])
+                .unwrap(),
+            exclude_mime_types: parse_patterns(&["image/x-*".to_string(), "image/gif".to_string()])
+                .unwrap(),
+            min_size: None,
+            max_size: Some(1000),
+            modified_after: Some(parse_time("2024-01-01").unwrap()),
+            modified_before: None,
+            owner: Owner::Me,
+        };
+        let photo = file("image/jpeg", Some(500), "2024-03-01T10:00:00.000Z", true);
+
+        assert_eq!(
+            filter.query().to_string(),
+            "(mimeType contains 'image/' or mimeType = 'application/pdf') \
+             and mimeType != 'image/gif' and modifiedTime >= '2024-01-01T00:00:00Z' \
+             and 'me' in owners"
+        );
+        assert!(filter.matches(&photo, "photo.jpg"));
+        assert!(!filter.matches(&photo, "cache-photo.jpg"));
+        assert!(!filter.matches(&photo, "photo.tmp"));
+        assert!(!filter.matches(&photo, "Trips/photo.tmp"));
+        assert!(filter.matches(&photo, "Trips/cache-photo.jpg"));
+        assert!(!filter.matches(&file("image/x-icon", Some(5), "2024-03-01T10:00:00Z", true), "i"));
+        assert!(!filter.matches(&file("video/mp4", Some(5), "2024-03-01T10:00:00Z", true), "v"));
+        assert!(!filter.matches(&file("image/png", Some(5000), "2024-03-01T10:00:00Z", true), "p"));
+        assert!(!filter.matches(&file("image/png", Some(5), "2023-12-31T23:59:59Z", true), "p"));
+        assert!(!filter.matches(&file("image/png", Some(5), "2024-03-01T10:00:00Z", false), "p"));
+        assert!(parse_time("yesterday").is_err());
+    }
+}
@@ -0,0 +1,612 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use aws_sdk_s3::types::StorageClass;
+use futures::stream;
+use google_drive3::hyper::client::HttpConnector;
+use google_drive3::hyper_rustls::HttpsConnector;
+use google_drive3::oauth2;
+use google_drive3::oauth2::authenticator::Authenticator;
+use percent_encoding::{utf8_percent_encode, NON_ALPHANUMERIC};
+use reqwest::{Method, RequestBuilder, StatusCode};
+use serde::Deserialize;
+use tokio::sync::OnceCell;
+use url::Url;
+
+use crate::destination::{Destination, ObjectInfo, ObjectReader, ObjectWriter};
+use crate::errors::{Error, Result, ResultExt};
+
+/// Resumable uploads are sent in chunks of this size, which must be a multiple of 256 KiB.
+const CHUNK_SIZE: usize = 16 * 1024 * 1024;
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+/// Credentials and endpoint for Google Cloud Storage.
+#[derive(Clone, Debug, Default)]
+pub struct GcsOptions {
+    /// Service account key file. Without one, requests are sent unauthenticated, which only works
+    /// with emulators like fake-gcs-server.
+    pub credentials_file: Option<PathBuf>,
+    /// Defaults to https://storage.googleapis.com.
+    pub endpoint: Option<String>,
+}
+
+/// Objects under a `gs://bucket/some/folder` URL, written with resumable uploads through the
+/// JSON API.
+pub struct GcsDestination {
+    url: String,
+    client: Arc<GcsClient>,
+    bucket: String,
+    prefix: String,
+}
+
+impl GcsDestination {
+    pub fn new(url: &str, options: &GcsOptions) -> Result<GcsDestination> {
+        let parsed = Url::parse(url).chain_err(|| format!("{url} is not a valid URL"))?;
+        let bucket = parsed
+            .host_str()
+            .filter(|h| !h.is_empty())
+            .ok_or_else(|| Error::from(format!("{url} does not contain a bucket name")))?
+            .to_string();
+        let prefix = parsed.path().trim_matches('/').to_string();
+        Ok(GcsDestination {
+            url: url.to_string(),
+            client: Arc::new(GcsClient::new(options)?),
+            bucket,
+            prefix,
+        })
+    }
+
+    fn object_name(&self, name: &str) -> String {
+        match self.prefix.as_str() {
+            "" => name.to_string(),
+            prefix => format!("{prefix}/{name}"),
+        }
+    }
+
+    fn object_url(&self, name: &str) -> String {
+        format!(
+            "{}/storage/v1/b/{}/o/{}",
+            self.client.endpoint,
+            self.bucket,
+            utf8_percent_encode(&self.object_name(name), NON_ALPHANUMERIC)
+        )
+    }
+
+    async fn object(&self, name: &str) -> Result<Option<GcsObject>> {
+        let resp = self
+            .client
+            .request(Method::GET, &self.object_url(name))
+            .await?
+            .send()
+            .await
+            .chain_err(|| format!("Could not get metadata of {name}"))?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let body = check_status(resp)
+            .await
+            .chain_err(|| format!("Could not get metadata of {name}"))?
+            .bytes()
+            .await
+            .chain_err(|| format!("Could not get metadata of {name}"))?;
+        let object =
+            serde_json::from_slice(&body).chain_err(|| format!("Invalid metadata of {name}"))?;
+        Ok(Some(object))
+    }
+}
+
+#[async_trait]
+impl Destination for GcsDestination {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn check_writable(&self) -> Result<()> {
+        let mut writer =
+            self.create(".g2s3-preflight", &StorageClass::Standard, HashMap::new()).await?;
+        writer.write(b"g2s3 preflight check").await?;
+        writer.finish().await?;
+        self.delete(".g2s3-preflight").await
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        storage_class: &StorageClass,
+        metadata: HashMap<String, String>,
+    ) -> Result<Box<dyn ObjectWriter>> {
+        let object_name = self.object_name(name);
+        let mut body = serde_json::json!({ "name": object_name, "metadata": metadata });
+        if let Some(storage_class) = gcs_storage_class_for(storage_class) {
+            body["storageClass"] = storage_class.into();
+        }
+        let resp = self
+            .client
+            .request(
+                Method::POST,
+                &format!(
+                    "{}/upload/storage/v1/b/{}/o?uploadType=resumable",
+                    self.client.endpoint, self.bucket
+                ),
+            )
+            .await?
+            .header(reqwest::header::CONTENT_TYPE, "application/json; charset=UTF-8")
+            .body(body.to_string())
+            .send()
+            .await
+            .chain_err(|| format!("Could not start upload of {object_name}"))?;
+        let resp = check_status(resp)
+            .await
+            .chain_err(|| format!("Could not start upload of {object_name}"))?;
+        let session_url = resp
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .ok_or_else(|| Error::from(format!("No upload session for {object_name}")))?
+            .to_string();
+        Ok(Box::new(ResumableUpload {
+            client: self.client.clone(),
+            name: object_name,
+            session_url,
+            offset: 0,
+            chunks: 0,
+            buf: Vec::with_capacity(CHUNK_SIZE),
+        }))
+    }
+
+    async fn metadata(&self, name: &str) -> Result<Option<HashMap<String, String>>> {
+        Ok(self.object(name).await?.map(|o| o.metadata))
+    }
+
+    async fn read(&self, name: &str) -> Result<ObjectReader> {
+        let metadata = self
+            .object(name)
+            .await?
+            .ok_or_else(|| Error::from(format!("{name} does not exist in {}", self.url)))?
+            .metadata;
+        let resp = self
+            .client
+            .request(Method::GET, &format!("{}?alt=media", self.object_url(name)))
+            .await?
+            .send()
+            .await
+            .chain_err(|| format!("Could not download {name}"))?;
+        let resp = check_status(resp).await.chain_err(|| format!("Could not download {name}"))?;
+        let name = name.to_string();
+        let content = stream::unfold(resp, move |mut resp| {
+            let name = name.clone();
+            async move {
+                match resp.chunk().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk.to_vec()), resp)),
+                    Ok(None) => None,
+                    Err(e) => {
+                        Some((Err(Error::from(format!("Download error for {name}: {e}"))), resp))
+                    }
+                }
+            }
+        });
+        Ok(ObjectReader { metadata, content: Box::pin(content) })
+    }
+
+    async fn list(&self) -> Result<Vec<ObjectInfo>> {
+        let prefix = match self.prefix.as_str() {
+            "" => String::new(),
+            prefix => format!("{prefix}/"),
+        };
+        let mut objects = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut url =
+                Url::parse(&format!("{}/storage/v1/b/{}/o", self.client.endpoint, self.bucket))
+                    .chain_err(|| format!("Invalid endpoint {}", self.client.endpoint))?;
+            url.query_pairs_mut().append_pair("prefix", &prefix);
+            if let Some(page_token) = page_token.as_ref() {
+                url.query_pairs_mut().append_pair("pageToken", page_token);
+            }
+            let resp = self
+                .client
+                .request(Method::GET, url.as_str())
+                .await?
+                .send()
+                .await
+                .chain_err(|| format!("Could not list objects in {}", self.url))?;
+            let body = check_status(resp)
+                .await
+                .chain_err(|| format!("Could not list objects in {}", self.url))?
+                .bytes()
+                .await
+                .chain_err(|| format!("Could not list objects in {}", self.url))?;
+            let list: GcsObjectList = serde_json::from_slice(&body)
+                .chain_err(|| format!("Invalid object list for {}", self.url))?;
+            for item in list.items {
+                let Some(name) = item.name.strip_prefix(&prefix) else { continue };
+                objects.push(ObjectInfo {
+                    name: name.to_string(),
+                    size: item
+                        .size
+                        .parse()
+                        .chain_err(|| format!("Invalid size of {}", item.name))?,
+                });
+            }
+            match list.next_page_token {
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => break,
+            }
+        }
+        Ok(objects)
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let resp = self
+            .client
+            .request(Method::DELETE, &self.object_url(name))
+            .await?
+            .send()
+            .await
+            .chain_err(|| format!("Could not delete {name}"))?;
+        check_status(resp).await.chain_err(|| format!("Could not delete {name}"))?;
+        Ok(())
+    }
+}
+
+/// A resumable upload session. Data is sent in `CHUNK_SIZE` chunks as it arrives; the last chunk
+/// tells GCS the total size, which completes the object.
+struct ResumableUpload {
+    client: Arc<GcsClient>,
+    name: String,
+    session_url: String,
+    /// Bytes sent so far.
+    offset: u64,
+    chunks: usize,
+    buf: Vec<u8>,
+}
+
+impl ResumableUpload {
+    async fn put_chunk(&mut self, mut data: Vec<u8>, last: bool) -> Result<()> {
+        let total = if last { Some(self.offset + data.len() as u64) } else { None };
+        loop {
+            let len = data.len() as u64;
+            let resp = self
+                .client
+                .request(Method::PUT, &self.session_url)
+                .await?
+                .header(reqwest::header::CONTENT_RANGE, content_range(self.offset, len, total))
+                .body(data.clone())
+                .send()
+                .await
+                .chain_err(|| format!("Could not upload chunk {} of {}", self.chunks, self.name))?;
+            if resp.status() != StatusCode::PERMANENT_REDIRECT {
+                check_status(resp).await.chain_err(|| {
+                    format!("Could not upload chunk {} of {}", self.chunks, self.name)
+                })?;
+                self.offset += len;
+                break;
+            }
+            // 308 "Resume Incomplete" tells in its Range how much of the object GCS has
+            // persisted, which can be less than was sent; the rest has to be sent again.
+            let range = resp.headers().get(reqwest::header::RANGE).and_then(|r| r.to_str().ok());
+            let persisted = persisted_bytes(range)
+                .filter(|persisted| (self.offset..=self.offset + len).contains(persisted))
+                .ok_or_else(|| {
+                    Error::from(format!(
+                        "Invalid Range {} in the answer to chunk {} of {}",
+                        range.unwrap_or("(missing)"),
+                        self.chunks,
+                        self.name
+                    ))
+                })?;
+            let acknowledged = (persisted - self.offset) as usize;
+            if acknowledged == 0 {
+                return Err(Error::from(format!(
+                    "GCS persisted nothing of chunk {} of {}",
+                    self.chunks, self.name
+                )));
+            }
+            self.offset = persisted;
+            data.drain(..acknowledged);
+            // The last chunk is only done once GCS completed the object, if need be after an empty
+            // request with the total size.
+            if data.is_empty() && !last {
+                break;
+            }
+        }
+        self.chunks += 1;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ObjectWriter for ResumableUpload {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.buf.extend_from_slice(data);
+        // Keep the remainder buffered, so the last chunk is never empty unless the object is.
+        while self.buf.len() > CHUNK_SIZE {
+            let rest = self.buf.split_off(CHUNK_SIZE);
+            let chunk = std::mem::replace(&mut self.buf, rest);
+            self.put_chunk(chunk, false).await?;
+        }
+        Ok(())
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<usize> {
+        let chunk = std::mem::take(&mut self.buf);
+        if let Err(e) = self.put_chunk(chunk, true).await {
+            self.abort().await;
+            return Err(e);
+        }
+        Ok(self.chunks)
+    }
+
+    async fn abort(self: Box<Self>) {
+        // GCS answers a cancelled session with 499, so the response is ignored.
+        if let Ok(request) = self.client.request(Method::DELETE, &self.session_url).await {
+            if let Err(e) = request.send().await {
+                log::warn!("Could not cancel upload of {}: {e}", self.name);
+            }
+        }
+    }
+}
+
+/// The `Content-Range` of a chunk starting at `start`. `total` is only known for the last chunk.
+fn content_range(start: u64, len: u64, total: Option<u64>) -> String {
+    let total = total.map_or("*".to_string(), |t| t.to_string());
+    match len {
+        0 => format!("bytes */{total}"),
+        _ => format!("bytes {start}-{}/{total}", start + len - 1),
+    }
+}
+
+/// The size of the persisted part of an upload, from the `Range: bytes=0-N` of a 308 response.
+fn persisted_bytes(range: Option<&str>) -> Option<u64> {
+    range?.strip_prefix("bytes=0-")?.parse::<u64>().ok().map(|end| end + 1)
+}
+
+/// Maps S3 storage classes to the GCS storage class with the closest cost profile, so the same
+/// storage class options work for both. `None` means the bucket's default storage class.
+fn gcs_storage_class_for(storage_class: &StorageClass) -> Option<&'static str> {
+    match storage_class {
+        StorageClass::Standard | StorageClass::ReducedRedundancy => Some("STANDARD"),
+        StorageClass::StandardIa | StorageClass::OnezoneIa => Some("NEARLINE"),
+        StorageClass::GlacierIr | StorageClass::Glacier => Some("COLDLINE"),
+        StorageClass::DeepArchive => Some("ARCHIVE"),
+        _ => None,
+    }
+}
+
+async fn check_status(resp: reqwest::Response) -> Result<reqwest::Response> {
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let message = resp.text().await.unwrap_or_default();
+        return Err(Error::from(format!("Request failed with {status}: {message}")));
+    }
+    Ok(resp)
+}
+
+#[derive(Deserialize)]
+struct GcsObject {
+    #[serde(default)]
+    metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct GcsObjectList {
+    #[serde(default)]
+    items: Vec<GcsObjectListItem>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct GcsObjectListItem {
+    name: String,
+    /// The JSON API returns sizes as strings.
+    size: String,
+}
+
+struct GcsClient {
+    http: reqwest::Client,
+    endpoint: String,
+    credentials_file: Option<PathBuf>,
+    authenticator: OnceCell<Authenticator<HttpsConnector<HttpConnector>>>,
+}
+
+impl GcsClient {
+    fn new(options: &GcsOptions) -> Result<GcsClient> {
+        let http = reqwest::Client::builder()
+            // Resumable uploads answer with 308 without being a redirect.
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .chain_err(|| "Could not create HTTP client")?;
+        Ok(GcsClient {
+            http,
+            endpoint: options
+                .endpoint
+                .as_deref()
+                .unwrap_or(DEFAULT_ENDPOINT)
+                .trim_end_matches('/')
+                .to_string(),
+            credentials_file: options.credentials_file.clone(),
+            authenticator: OnceCell::new(),
+        })
+    }
+
+    /// Starts a request, authenticated with a token of the service account if there is one.
+    async fn request(&self, method: Method, url: &str) -> Result<RequestBuilder> {
+        let request = self.http.request(method, url);
+        let credentials_file = match self.credentials_file.as_ref() {
+            Some(credentials_file) => credentials_file,
+            None => return Ok(request),
+        };
+        let authenticator = self
+            .authenticator
+            .get_or_try_init(|| async {
+                let key =
+                    oauth2::read_service_account_key(credentials_file).await.chain_err(|| {
+                        format!("Could not read service account key {}", credentials_file.display())
+                    })?;
+                oauth2::ServiceAccountAuthenticator::builder(key)
+                    .build()
+                    .await
+                    .chain_err(|| "Could not create GCS authenticator")
+            })
+            .await?;
+        let token = authenticator
+            .token(&[SCOPE])
+            .await
+            .chain_err(|| "Could not get an access token for GCS")?;
+        Ok(request.bearer_auth(token.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    use aws_sdk_s3::types::StorageClass;
+    use futures::TryStreamExt;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use crate::destination::{Destination, ObjectInfo};
+    use crate::gcs::{
+        content_range, gcs_storage_class_for, GcsClient, GcsDestination, GcsOptions,
+        ResumableUpload, CHUNK_SIZE,
+    };
+
+    /// A server that answers the requests it gets with `responses` in turn, and the Content-Range
+    /// and body size of the requests it got.
+    async fn mock_server(
+        responses: Vec<&'static str>,
+    ) -> (String, Arc<Mutex<Vec<(String, usize)>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/upload", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(vec![]));
+        let received = requests.clone();
+        tokio::spawn(async move {
+            let mut responses = responses.into_iter();
+            loop {
+                let (socket, _) = listener.accept().await.unwrap();
+                let mut socket = BufReader::new(socket);
+                loop {
+                    let (mut content_range, mut content_length) = (String::new(), 0);
+                    let mut line = String::new();
+                    while socket.read_line(&mut line).await.unwrap() > 0 && line != "\r\n" {
+                        let (name, value) = line.split_once(':').unwrap_or_default();
+                        match name.to_lowercase().as_str() {
+                            "content-range" => content_range = value.trim().to_string(),
+                            "content-length" => content_length = value.trim().parse().unwrap(),
+                            _ => {}
+                        }
+                        line.clear();
+                    }
+                    if line.is_empty() {
+                        break;
+                    }
+                    let mut body = vec![0; content_length];
+                    socket.read_exact(&mut body).await.unwrap();
+                    received.lock().unwrap().push((content_range, content_length));
+                    let response = responses.next().unwrap();
+                    socket.write_all(response.as_bytes()).await.unwrap();
+                }
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn content_range_leaves_total_open_until_the_last_chunk() {
+        assert_eq!(content_range(0, 262144, None), "bytes 0-262143/*");
+        assert_eq!(content_range(262144, 10, Some(262154)), "bytes 262144-262153/262154");
+        assert_eq!(content_range(0, 0, Some(0)), "bytes */0");
+    }
+
+    #[test]
+    fn storage_classes_map_to_gcs_storage_classes() {
+        assert_eq!(gcs_storage_class_for(&StorageClass::Standard), Some("STANDARD"));
+        assert_eq!(gcs_storage_class_for(&StorageClass::StandardIa), Some("NEARLINE"));
+        assert_eq!(gcs_storage_class_for(&StorageClass::Glacier), Some("COLDLINE"));
+        assert_eq!(gcs_storage_class_for(&StorageClass::DeepArchive), Some("ARCHIVE"));
+        assert_eq!(gcs_storage_class_for(&StorageClass::IntelligentTiering), None);
+    }
+
+    #[tokio::test]
+    async fn chunks_are_resent_from_where_gcs_stopped_persisting_them() {
+        let (url, requests) = mock_server(vec![
+            "HTTP/1.1 308 Resume Incomplete\r\nRange: bytes=0-399\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 308 Resume Incomplete\r\nRange: bytes=0-999\r\nContent-Length: 0\r\n\r\n",
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\n{}",
+            "HTTP/1.1 308 Resume Incomplete\r\nContent-Length: 0\r\n\r\n",
+        ])
+        .await;
+        let options = GcsOptions { credentials_file: None, endpoint: None };
+        let mut upload = ResumableUpload {
+            client: Arc::new(GcsClient::new(&options).unwrap()),
+            name: "a.bin".to_string(),
+            session_url: url,
+            offset: 0,
+            chunks: 0,
+            buf: vec![],
+        };
+
+        upload.put_chunk(vec![1; 1000], false).await.unwrap();
+        upload.put_chunk(vec![2; 10], true).await.unwrap();
+        assert_eq!(upload.offset, 1010);
+        assert_eq!(upload.chunks, 2);
+        assert!(upload.put_chunk(vec![3; 10], false).await.is_err());
+
+        assert_eq!(
+            *requests.lock().unwrap(),
+            vec![
+                ("bytes 0-999/*".to_string(), 1000),
+                ("bytes 400-999/*".to_string(), 600),
+                ("bytes 1000-1009/1010".to_string(), 10),
+                ("bytes 1010-1019/*".to_string(), 10),
+            ]
+        );
+    }
+
+    /// Runs against fake-gcs-server, started with `./scripts/start-fake-gcs-server.sh`.
+    #[tokio::test]
+    #[ignore]
+    async fn objects_can_be_written_read_listed_and_deleted_in_fake_gcs_server() {
+        let destination = GcsDestination::new(
+            "gs://g2s3-test/some/folder",
+            &GcsOptions {
+                credentials_file: None,
+                endpoint: Some("http://localhost:4443".to_string()),
+            },
+        )
+        .unwrap();
+        destination.check_writable().await.unwrap();
+
+        let content: Vec<u8> = (0..CHUNK_SIZE + 1000).map(|i| i as u8).collect();
+        let metadata = HashMap::from([("g2s3-compression".to_string(), "zstd".to_string())]);
+        let mut writer = destination
+            .create("a b.bin", &StorageClass::StandardIa, metadata.clone())
+            .await
+            .unwrap();
+        for chunk in content.chunks(1 << 20) {
+            writer.write(chunk).await.unwrap();
+        }
+        assert_eq!(writer.finish().await.unwrap(), 2);
+
+        assert!(destination
+            .list()
+            .await
+            .unwrap()
+            .contains(&ObjectInfo { name: "a b.bin".to_string(), size: content.len() as u64 }));
+        let reader = destination.read("a b.bin").await.unwrap();
+        assert_eq!(reader.metadata, metadata);
+        let read: Vec<Vec<u8>> = reader.content.try_collect().await.unwrap();
+        assert_eq!(read.concat(), content);
+
+        destination.delete("a b.bin").await.unwrap();
+        assert_eq!(destination.metadata("a b.bin").await.unwrap(), None);
+    }
+}
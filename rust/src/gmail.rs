@@ -0,0 +1,602 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use base64::engine::general_purpose::{GeneralPurpose, GeneralPurposeConfig};
+use base64::engine::DecodePaddingMode;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use google_drive3::api::File;
+use google_drive3::oauth2::authorized_user::AuthorizedUserSecret;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+use crate::destination::Destination;
+use crate::errors::{Error, Result, ResultExt};
+use crate::google_api::GoogleApi;
+use crate::manifest::ManifestEntry;
+use crate::sync_state::{read_states, write_state};
+use crate::{
+    check_destinations, combine_results, copy_file, upload_manifests, BackupOptions, Content,
+    PipelineUpload,
+};
+
+const API: &str = "https://gmail.googleapis.com/gmail/v1/users/me";
+
+const SCOPE: &str = "https://www.googleapis.com/auth/gmail.readonly";
+
+/// The state incremental runs continue from, stored in every destination.
+pub const STATE_NAME: &str = "g2s3-gmail-state.json";
+
+/// Gmail encodes raw messages as URL-safe base64, with or without padding.
+const RAW_ENCODING: GeneralPurpose = GeneralPurpose::new(
+    &base64::alphabet::URL_SAFE,
+    GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+/// Client for the Gmail API.
+pub struct Gmail {
+    api: GoogleApi,
+}
+
+#[derive(Deserialize)]
+struct MessageRef {
+    id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MessageList {
+    #[serde(default)]
+    messages: Vec<MessageRef>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Message {
+    pub id: String,
+    #[serde(default)]
+    pub label_ids: Vec<String>,
+    /// Milliseconds since the epoch, as a string.
+    pub internal_date: String,
+    /// Base64url-encoded RFC 822 message. Only present when requested with `format=raw`.
+    pub raw: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Profile {
+    email_address: String,
+    history_id: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryList {
+    #[serde(default)]
+    history: Vec<History>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct History {
+    #[serde(default)]
+    messages_added: Vec<MessageAdded>,
+}
+
+#[derive(Deserialize)]
+struct MessageAdded {
+    message: MessageRef,
+}
+
+#[derive(Deserialize)]
+struct LabelList {
+    #[serde(default)]
+    labels: Vec<Label>,
+}
+
+#[derive(Deserialize)]
+struct Label {
+    id: String,
+    name: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct GmailState {
+    pub email_address: String,
+    pub history_id: String,
+}
+
+impl Message {
+    pub fn date(&self) -> DateTime<Utc> {
+        self.internal_date
+            .parse()
+            .ok()
+            .and_then(DateTime::from_timestamp_millis)
+            .unwrap_or_default()
+    }
+
+    fn content(&self) -> Result<Vec<u8>> {
+        let raw = self.raw.as_ref().ok_or_else(|| {
+            Error::from(format!("Message {} was fetched without content", self.id))
+        })?;
+        RAW_ENCODING.decode(raw).chain_err(|| format!("Invalid content of message {}", self.id))
+    }
+
+    /// Describes the message like a Drive file, so storage class rules and the manifest treat both
+    /// alike.
+    fn as_file(&self, size: usize) -> File {
+        File {
+            id: Some(self.id.clone()),
+            name: Some(format!("{}.eml", self.id)),
+            mime_type: Some("message/rfc822".to_string()),
+            size: Some(size.to_string()),
+            modified_time: Some(self.date().to_rfc3339()),
+            ..Default::default()
+        }
+    }
+}
+
+impl Gmail {
+    pub async fn new(aus: AuthorizedUserSecret) -> Result<Gmail> {
+        Ok(Gmail { api: GoogleApi::new(aus, &[SCOPE]).await? })
+    }
+
+    async fn profile(&self) -> Result<Profile> {
+        self.api
+            .send_json(self.api.http().get(format!("{API}/profile")))
+            .await
+            .chain_err(|| "Could not get Gmail profile")
+    }
+
+    /// Maps label IDs to label names.
+    async fn labels(&self) -> Result<HashMap<String, String>> {
+        let list: LabelList = self
+            .api
+            .send_json(self.api.http().get(format!("{API}/labels")))
+            .await
+            .chain_err(|| "Could not list Gmail labels")?;
+        Ok(list.labels.into_iter().map(|l| (l.id, l.name)).collect())
+    }
+
+    async fn list_message_ids(&self) -> Result<Vec<String>> {
+        let mut ids = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut request =
+                self.api.http().get(format!("{API}/messages")).query(&[("maxResults", "500")]);
+            if let Some(page_token) = page_token.as_ref() {
+                request = request.query(&[("pageToken", page_token)]);
+            }
+            let list: MessageList =
+                self.api.send_json(request).await.chain_err(|| "Could not list messages")?;
+            ids.extend(list.messages.into_iter().map(|m| m.id));
+            match list.next_page_token {
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => break,
+            }
+        }
+        Ok(ids)
+    }
+
+    /// Lists the messages added since `history_id`, or returns `None` if Gmail no longer has the
+    /// history that far back.
+    async fn list_message_ids_added_since(&self, history_id: &str) -> Result<Option<Vec<String>>> {
+        let mut ids = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut request = self.api.http().get(format!("{API}/history")).query(&[
+                ("startHistoryId", history_id),
+                ("historyTypes", "messageAdded"),
+                ("maxResults", "500"),
+            ]);
+            if let Some(page_token) = page_token.as_ref() {
+                request = request.query(&[("pageToken", page_token)]);
+            }
+            let resp = self.api.send(request).await.chain_err(|| "Could not list history")?;
+            if resp.status() == StatusCode::NOT_FOUND {
+                return Ok(None);
+            }
+            if !resp.status().is_success() {
+                return Err(Error::from(format!("Could not list history: {}", resp.status())));
+            }
+            let body = resp.bytes().await.chain_err(|| "Could not list history")?;
+            let list: HistoryList =
+                serde_json::from_slice(&body).chain_err(|| "Invalid history list")?;
+            for history in list.history {
+                ids.extend(history.messages_added.into_iter().map(|m| m.message.id));
+            }
+            match list.next_page_token {
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => break,
+            }
+        }
+        let mut seen = HashSet::new();
+        ids.retain(|id| seen.insert(id.clone()));
+        Ok(Some(ids))
+    }
+
+    /// Gets a message, with its content if `format` is `raw`, or without if it is `minimal`.
+    /// Returns `None` if the message was deleted in the meantime.
+    async fn message(&self, id: &str, format: &str) -> Result<Option<Message>> {
+        let request =
+            self.api.http().get(format!("{API}/messages/{id}")).query(&[("format", format)]);
+        let resp =
+            self.api.send(request).await.chain_err(|| format!("Could not get message {id}"))?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            log::info!("Message {id} was deleted, skipping it");
+            return Ok(None);
+        }
+        if !resp.status().is_success() {
+            return Err(Error::from(format!("Could not get message {id}: {}", resp.status())));
+        }
+        let body = resp.bytes().await.chain_err(|| format!("Could not get message {id}"))?;
+        serde_json::from_slice(&body).chain_err(|| format!("Invalid message {id}"))
+    }
+
+    /// Gets a message with its decoded content, or `None` if it was deleted in the meantime.
+    async fn message_with_content(&self, id: &str) -> Result<Option<(Message, Vec<u8>)>> {
+        match self.message(id, "raw").await? {
+            Some(message) => {
+                let content = message.content()?;
+                Ok(Some((message, content)))
+            }
+            None => Ok(None),
+        }
+    }
+}
+
+/// How messages are stored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GmailFormat {
+    /// One `messages/<year>/<month>/<id>.eml` object per message.
+    Eml,
+    /// One `mbox/<year>-<month>.mbox` archive per month.
+    Mbox,
+}
+
+impl FromStr for GmailFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<GmailFormat> {
+        match s {
+            "eml" => Ok(GmailFormat::Eml),
+            "mbox" => Ok(GmailFormat::Mbox),
+            _ => Err(Error::from(format!("Unknown format {s}. Possible values: eml, mbox"))),
+        }
+    }
+}
+
+/// Backs up the mailbox to every destination. With `incremental`, only messages added since the
+/// state stored by the previous run are copied; without a usable state, everything is.
+pub async fn back_up_gmail(
+    gmail: Arc<Gmail>,
+    destinations: Vec<Arc<dyn Destination>>,
+    format: GmailFormat,
+    incremental: bool,
+    options: &BackupOptions,
+) -> Result<()> {
+    check_destinations(&destinations).await?;
+
+    let profile = gmail.profile().await?;
+    let state = GmailState { email_address: profile.email_address, history_id: profile.history_id };
+    let previous = match incremental {
+        true => previous_history_id(&destinations, &state.email_address).await?,
+        false => None,
+    };
+    let ids = match previous.as_ref() {
+        Some(history_id) => match gmail.list_message_ids_added_since(history_id).await? {
+            Some(ids) => ids,
+            None => {
+                log::warn!("History since {history_id} is no longer available, copying everything");
+                gmail.list_message_ids().await?
+            }
+        },
+        None => gmail.list_message_ids().await?,
+    };
+    log::info!("Backing up {} messages of {}", ids.len(), state.email_address);
+    let labels = gmail.labels().await?;
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    match format {
+        GmailFormat::Eml => {
+            stream::iter(ids)
+                .for_each_concurrent(options.concurrency, |id| {
+                    let (gmail, destinations, labels, tx) =
+                        (gmail.clone(), &destinations, &labels, tx.clone());
+                    async move {
+                        let results =
+                            copy_message(&gmail, destinations, &id, labels, options).await;
+                        for (i, result) in results.into_iter().enumerate() {
+                            tx.send((i, result)).unwrap();
+                        }
+                    }
+                })
+                .await;
+        }
+        GmailFormat::Mbox => {
+            // Incremental runs add archives instead of replacing those of earlier runs.
+            let suffix = previous.as_ref().map(|_| state.history_id.as_str());
+            write_mboxes(&gmail, &destinations, ids, &labels, suffix, options, &tx).await;
+        }
+    }
+    let mut results = upload_manifests(&destinations, rx, options).await;
+    for (destination, result) in destinations.iter().zip(results.iter_mut()) {
+        if result.is_ok() {
+            *result = write_state(destination.as_ref(), STATE_NAME, &state).await;
+        }
+    }
+    combine_results(&destinations, results)
+}
+
+/// The history ID all destinations are up to date with, if they all have a state for `email`.
+async fn previous_history_id(
+    destinations: &[Arc<dyn Destination>],
+    email_address: &str,
+) -> Result<Option<String>> {
+    let states: Vec<Option<GmailState>> = read_states(destinations, STATE_NAME).await?;
+    let mut oldest: Option<(u64, String)> = None;
+    for state in states {
+        let state = match state {
+            Some(state) if state.email_address == email_address => state,
+            _ => return Ok(None),
+        };
+        let id = state.history_id.parse().chain_err(|| "Invalid history ID in Gmail state")?;
+        if oldest.as_ref().is_none_or(|(oldest, _)| id < *oldest) {
+            oldest = Some((id, state.history_id));
+        }
+    }
+    Ok(oldest.map(|(_, history_id)| history_id))
+}
+
+async fn copy_message(
+    gmail: &Gmail,
+    destinations: &[Arc<dyn Destination>],
+    id: &str,
+    labels: &HashMap<String, String>,
+    options: &BackupOptions,
+) -> Vec<Result<ManifestEntry>> {
+    let (message, content) = match gmail.message_with_content(id).await {
+        Ok(Some(message)) => message,
+        Ok(None) => return vec![],
+        Err(e) => {
+            return destinations
+                .iter()
+                .map(|_| Err(Error::from(format!("Could not copy message {id}: {e}"))))
+                .collect()
+        }
+    };
+    let file = message.as_file(content.len());
+    let key = format!("messages/{}/{id}.eml", message.date().format("%Y/%m"));
+    let content: Content = Box::pin(stream::iter([Ok(content)]));
+    copy_file(destinations, &file, key, "message/rfc822", async { Ok(content) }, options)
+        .await
+        .into_iter()
+        .map(|r| r.map(|entry| with_labels(entry, &message, labels)))
+        .collect()
+}
+
+/// Writes the messages into one mbox archive per month, named after `suffix` if given.
+async fn write_mboxes(
+    gmail: &Gmail,
+    destinations: &[Arc<dyn Destination>],
+    ids: Vec<String>,
+    labels: &HashMap<String, String>,
+    suffix: Option<&str>,
+    options: &BackupOptions,
+    tx: &UnboundedSender<(usize, Result<ManifestEntry>)>,
+) {
+    let send_error = |e: Error| {
+        for i in 0..destinations.len() {
+            tx.send((i, Err(Error::from(e.to_string())))).unwrap();
+        }
+    };
+
+    // The message list is not strictly ordered by date, so the dates are fetched first.
+    let mut months: BTreeMap<String, Vec<Message>> = BTreeMap::new();
+    let mut minimal = stream::iter(ids)
+        .map(|id| async move { gmail.message(&id, "minimal").await })
+        .buffered(options.concurrency);
+    while let Some(message) = minimal.next().await {
+        match message {
+            Ok(Some(message)) => {
+                months.entry(message.date().format("%Y-%m").to_string()).or_default().push(message)
+            }
+            Ok(None) => {}
+            Err(e) => send_error(e),
+        }
+    }
+
+    for (month, mut messages) in months {
+        messages.sort_by_key(Message::date);
+        let key = match suffix {
+            Some(suffix) => format!("mbox/{month}-{suffix}.mbox"),
+            None => format!("mbox/{month}.mbox"),
+        };
+        log::info!("Writing {} messages to {key}", messages.len());
+        let storage_class = &options.storage_class;
+        let mut upload = match PipelineUpload::create(
+            destinations,
+            &key,
+            "application/mbox",
+            storage_class,
+            options,
+            HashMap::new(),
+        )
+        .await
+        {
+            Ok(upload) => upload,
+            Err(e) => {
+                send_error(Error::with_chain(e, format!("Could not create {key}")));
+                continue;
+            }
+        };
+
+        let mut entries = vec![];
+        let mut failed = None;
+        let mut raw = stream::iter(messages)
+            .map(|m| async move { gmail.message_with_content(&m.id).await })
+            .buffered(options.concurrency);
+        while let Some(message) = raw.next().await {
+            let (message, content) = match message {
+                Ok(Some(message)) => message,
+                Ok(None) => continue,
+                Err(e) => {
+                    send_error(e);
+                    continue;
+                }
+            };
+            if let Err(e) = upload.write(Ok(mbox_entry(&content, message.date()))).await {
+                failed = Some(e);
+                break;
+            }
+            let file = message.as_file(content.len());
+            let entry = ManifestEntry::new(&file, key.clone(), true, storage_class);
+            entries.push(with_labels(entry, &message, labels));
+        }
+
+        let results = match failed {
+            Some(e) => upload.abort(&e.to_string()).await,
+            None => upload.finish().await,
+        };
+        for (i, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(_) => {
+                    for entry in &entries {
+                        tx.send((i, Ok(entry.clone()))).unwrap();
+                    }
+                }
+                Err(e) => tx.send((i, Err(e))).unwrap(),
+            }
+        }
+    }
+}
+
+fn with_labels(
+    mut entry: ManifestEntry,
+    message: &Message,
+    labels: &HashMap<String, String>,
+) -> ManifestEntry {
+    entry.labels = message
+        .label_ids
+        .iter()
+        .map(|id| labels.get(id).cloned().unwrap_or_else(|| id.clone()))
+        .collect();
+    entry
+}
+
+/// Formats a message as an mbox entry in the mboxrd flavor: a `From ` separator line, the message
+/// with LF line endings and `>` added to lines starting with any number of `>` followed by `From `,
+/// and an empty line.
+fn mbox_entry(content: &[u8], date: DateTime<Utc>) -> Vec<u8> {
+    let mut entry =
+        format!("From MAILER-DAEMON {}\n", date.format("%a %b %e %H:%M:%S %Y")).into_bytes();
+    let content = content.strip_suffix(b"\n").unwrap_or(content);
+    for line in content.split(|b| *b == b'\n') {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        if line.iter().position(|b| *b != b'>').is_some_and(|i| line[i..].starts_with(b"From ")) {
+            entry.push(b'>');
+        }
+        entry.extend_from_slice(line);
+        entry.push(b'\n');
+    }
+    entry.push(b'\n');
+    entry
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    use aws_sdk_s3::types::StorageClass;
+    use chrono::{TimeZone, Utc};
+    use tokio::sync::mpsc;
+
+    use crate::compression::{Algorithm, CompressionRules};
+    use crate::destination::Destination;
+    use crate::gmail::{mbox_entry, with_labels, Message, RAW_ENCODING};
+    use crate::local::LocalDestination;
+    use crate::manifest::ManifestEntry;
+    use crate::restore::RestoreOptions;
+    use crate::storage_class::StorageClassRules;
+    use crate::verify::read_manifest;
+    use crate::{upload_manifests, BackupOptions};
+    use base64::Engine;
+
+    #[test]
+    fn mbox_entries_escape_from_lines() {
+        let content = b"From: a@example.com\r\nSubject: Hi\r\n\r\nFrom here on\r\n>From there\r\n";
+
+        let entry = mbox_entry(content, Utc.with_ymd_and_hms(2024, 5, 1, 8, 30, 0).unwrap());
+
+        assert_eq!(
+            String::from_utf8(entry).unwrap(),
+            "From MAILER-DAEMON Wed May  1 08:30:00 2024\n\
+             From: a@example.com\nSubject: Hi\n\n>From here on\n>>From there\n\n"
+        );
+    }
+
+    #[test]
+    fn raw_content_and_date_are_decoded() {
+        let message = Message {
+            id: "18f".to_string(),
+            label_ids: vec!["INBOX".to_string()],
+            internal_date: "1714552200000".to_string(),
+            raw: Some(RAW_ENCODING.encode("Subject: ?>\r\n\r\nHi")),
+        };
+
+        assert_eq!(message.content().unwrap(), b"Subject: ?>\r\n\r\nHi");
+        assert_eq!(message.date(), Utc.with_ymd_and_hms(2024, 5, 1, 8, 30, 0).unwrap());
+        let unpadded = RAW_ENCODING.encode("ab").trim_end_matches('=').to_string();
+        assert_eq!(RAW_ENCODING.decode(unpadded).unwrap(), b"ab");
+    }
+
+    #[tokio::test]
+    async fn incremental_runs_keep_the_labels_of_earlier_runs_in_the_manifest() {
+        let dir = std::env::temp_dir().join(format!("g2s3-gmail-test-{}", std::process::id()));
+        let destinations: Vec<Arc<dyn Destination>> =
+            vec![Arc::new(LocalDestination::new("file:///backup", dir.clone()))];
+        let options = BackupOptions {
+            storage_class: StorageClass::Standard,
+            storage_class_rules: StorageClassRules::default(),
+            compression: CompressionRules::with_defaults(Algorithm::Zstd),
+            encryption_key: None,
+            packing: None,
+            concurrency: 1,
+        };
+        let labels = HashMap::from([("Label_1".to_string(), "Receipts".to_string())]);
+        let message = |id: &str, label: &str| Message {
+            id: id.to_string(),
+            label_ids: vec![label.to_string()],
+            internal_date: "1714552200000".to_string(),
+            raw: None,
+        };
+
+        // Every run only copies the messages added since the one before.
+        for message in [message("18a", "Label_1"), message("18b", "INBOX")] {
+            let key = format!("messages/2024/05/{}.eml", message.id);
+            let entry = ManifestEntry::new(&message.as_file(1), key, false, &options.storage_class);
+            let (tx, rx) = mpsc::unbounded_channel();
+            tx.send((0, Ok(with_labels(entry, &message, &labels)))).unwrap();
+            let results = upload_manifests(&destinations, rx, &options).await;
+            assert!(results.iter().all(Result::is_ok));
+        }
+
+        let restore_options = RestoreOptions { encryption_key: None, concurrency: 1 };
+        let manifest = read_manifest(destinations[0].as_ref(), &restore_options).await.unwrap();
+        let labels: Vec<(&str, Vec<String>)> =
+            manifest.files.iter().map(|e| (e.id.as_str(), e.labels.clone())).collect();
+        assert_eq!(
+            labels,
+            vec![("18a", vec!["Receipts".to_string()]), ("18b", vec!["INBOX".to_string()])]
+        );
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,64 @@
+use google_drive3::hyper::client::HttpConnector;
+use google_drive3::hyper_rustls::HttpsConnector;
+use google_drive3::oauth2;
+use google_drive3::oauth2::authenticator::Authenticator;
+use google_drive3::oauth2::authorized_user::AuthorizedUserSecret;
+use serde::de::DeserializeOwned;
+
+use crate::errors::{Error, Result, ResultExt};
+
+/// Authenticated requests to the Google APIs that have no client library here (Photos, Gmail,
+/// Calendar, People), using the same authorized user secret as Drive.
+pub struct GoogleApi {
+    http: reqwest::Client,
+    authenticator: Authenticator<HttpsConnector<HttpConnector>>,
+    scopes: &'static [&'static str],
+}
+
+impl GoogleApi {
+    pub async fn new(
+        aus: AuthorizedUserSecret,
+        scopes: &'static [&'static str],
+    ) -> Result<GoogleApi> {
+        Ok(GoogleApi {
+            http: reqwest::Client::new(),
+            authenticator: oauth2::AuthorizedUserAuthenticator::builder(aus)
+                .build()
+                .await
+                .chain_err(|| "Could not create Google authenticator")?,
+            scopes,
+        })
+    }
+
+    pub fn http(&self) -> &reqwest::Client {
+        &self.http
+    }
+
+    /// Sends `request` with an access token. The response status is not checked.
+    pub async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response> {
+        let token = self
+            .authenticator
+            .token(self.scopes)
+            .await
+            .chain_err(|| "Could not get an access token for Google")?;
+        request.bearer_auth(token.as_str()).send().await.chain_err(|| "Request failed")
+    }
+
+    /// Sends `request` with an access token and parses the JSON response. Fails unless the
+    /// response status is a success.
+    pub async fn send_json<T: DeserializeOwned>(
+        &self,
+        request: reqwest::RequestBuilder,
+    ) -> Result<T> {
+        let resp = self.send(request).await?;
+        let status = resp.status();
+        let body = resp.bytes().await.chain_err(|| "Could not read response")?;
+        if !status.is_success() {
+            return Err(Error::from(format!(
+                "Request failed with {status}: {}",
+                String::from_utf8_lossy(&body)
+            )));
+        }
+        serde_json::from_slice(&body).chain_err(|| "Invalid response")
+    }
+}
@@ -1,174 +1,449 @@
 extern crate core;
 
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
 use std::sync::Arc;
 use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
-use aws_sdk_s3::Client;
-use aws_sdk_s3::types::{CompletedMultipartUpload, CompletedPart, StorageClass};
+use aws_sdk_s3::types::StorageClass;
 use byte_unit::{Byte, ByteUnit::B};
-use futures::{stream, StreamExt};
+use chrono::Utc;
+use futures::future::join_all;
+use futures::{stream, Stream, StreamExt};
 use google_drive3::hyper::body::HttpBody;
+use serde::Serialize;
 use std::path::PathBuf;
 use tokio::sync::mpsc;
 use url::Url;
 
+use crate::client_side_encryption::{MasterKey, KEY_ID_METADATA_KEY};
+use crate::compression::{CompressionRules, COMPRESSION_METADATA_KEY};
+use crate::destination::Destination;
 use crate::drive::Drive;
-use errors::{Result, ResultExt};
+use crate::fan_out::FanOut;
+use crate::filter::FileFilter;
+use crate::manifest::{Manifest, ManifestEntry, MANIFEST_METADATA_KEY, MANIFEST_NAME};
+use crate::packing::{pack_files, PackingOptions};
+use crate::restore::RestoreOptions;
+use crate::storage_class::StorageClassRules;
+use crate::transform::{Pipeline, Transform};
+use crate::verify::read_manifest;
+use errors::{Error, Result, ResultExt};
 
+pub mod auth;
+pub mod azure_blob;
+pub mod calendar;
+pub mod cli;
 pub mod cli_factories;
+pub mod client_side_encryption;
+pub mod compression;
+pub mod config;
+pub mod contacts;
+pub mod cron;
+pub mod daemon;
+pub mod destination;
 pub mod drive;
+pub mod drive_query;
 pub mod errors;
+pub mod fan_out;
+pub mod filter;
+pub mod gcs;
+pub mod gmail;
+pub mod google_api;
+pub mod local;
+pub mod manifest;
+pub mod packing;
+pub mod photos;
+pub mod plan;
+pub mod restore;
+pub mod s3;
+pub mod storage_class;
+pub mod sync_state;
+pub mod takeout;
+pub mod transform;
+pub mod verify;
 
+/// Object metadata keys recording the Drive version of a copied file.
+pub const SOURCE_MD5_METADATA_KEY: &str = "g2s3-source-md5";
+pub const SOURCE_MODIFIED_TIME_METADATA_KEY: &str = "g2s3-source-modified-time";
+
+pub struct BackupOptions {
+    /// Storage class for files that match none of the `storage_class_rules`.
+    pub storage_class: StorageClass,
+    pub storage_class_rules: StorageClassRules,
+    pub compression: CompressionRules,
+    /// When set, files are encrypted before they leave this machine.
+    pub encryption_key: Option<MasterKey>,
+    /// When set, small files are bundled into tar archives instead of being uploaded one by one.
+    pub packing: Option<PackingOptions>,
+    /// How many files are copied at the same time.
+    pub concurrency: usize,
+}
+
+/// Copies the files of the Drive folder `source` that `filter` matches to every destination. Each
+/// file is downloaded once and written to all destinations at the same time. Every destination gets
+/// its own manifest of the files that made it there, and a failing destination does not stop the
+/// others. Files a destination already holds in their current version are not copied there again;
+/// packed files always are.
 pub async fn back_up(
     drive: Arc<drive::Drive>,
-    s3: Arc<aws_sdk_s3::Client>,
+    destinations: Vec<Arc<dyn Destination>>,
     source: &str,
-    destination: &str,
-    s3_storage_class: &str,
+    filter: &FileFilter,
+    options: &BackupOptions,
 ) -> Result<()> {
-    let (tx, mut rx) = mpsc::unbounded_channel();
+    let folder_id = preflight(&drive, &destinations, source).await?;
+
+    let (tx, rx) = mpsc::unbounded_channel();
 
-    stream::iter(drive.list_files_in_folder(&String::from(source)).await?)
-        .for_each_concurrent(4, |file| {
-            let (drive, s3, tx) = (drive.clone(), s3.clone(), tx.clone());
+    let mut files = drive.list_matching_files_in_folder_id(&folder_id, filter).await?;
+    if let Some(packing) = options.packing.as_ref() {
+        let (small_files, other_files) = files.into_iter().partition(|f| packing.should_pack(f));
+        files = other_files;
+        if let Err(e) =
+            pack_files(&drive, &destinations, small_files, options, Utc::now(), &tx).await
+        {
+            for i in 0..destinations.len() {
+                tx.send((i, Err(Error::from(format!("Could not pack files: {e}"))))).unwrap();
+            }
+        }
+    }
+
+    stream::iter(files)
+        .for_each_concurrent(options.concurrency, |file| {
+            let (drive, destinations, tx) = (drive.clone(), &destinations, tx.clone());
             async move {
-                let result =
-                    copy_file(&*drive, &*s3, file.clone(), destination, s3_storage_class).await;
-                tx.send(result).unwrap();
+                let key = file.name.clone().unwrap_or_default();
+                // Destinations that already have this version keep it and their manifest entry.
+                let changed: Vec<usize> = unchanged_in(destinations, &file, &key)
+                    .await
+                    .into_iter()
+                    .enumerate()
+                    .filter_map(|(i, unchanged)| (!unchanged).then_some(i))
+                    .collect();
+                if changed.is_empty() {
+                    log::info!("Skipping {key}, it is unchanged in every destination");
+                    return;
+                }
+                let targets: Vec<Arc<dyn Destination>> =
+                    changed.iter().map(|i| destinations[*i].clone()).collect();
+                let mime_type = Drive::content_mime_type_for(&file);
+                let content = drive_content(&drive, &file);
+                let results = copy_file(&targets, &file, key, mime_type, content, options).await;
+                for (i, result) in changed.into_iter().zip(results) {
+                    tx.send((i, result)).unwrap();
+                }
             }
         })
         .await;
 
+    finish_back_up(&destinations, rx, options).await
+}
+
+/// Collects the manifest entries and errors sent to `rx` per destination, uploads every
+/// destination's manifest and fails if any destination is incomplete.
+pub(crate) async fn finish_back_up(
+    destinations: &[Arc<dyn Destination>],
+    rx: mpsc::UnboundedReceiver<(usize, Result<ManifestEntry>)>,
+    options: &BackupOptions,
+) -> Result<()> {
+    let results = upload_manifests(destinations, rx, options).await;
+    combine_results(destinations, results)
+}
+
+/// Like `finish_back_up`, but returns whether each destination is complete.
+pub(crate) async fn upload_manifests(
+    destinations: &[Arc<dyn Destination>],
+    mut rx: mpsc::UnboundedReceiver<(usize, Result<ManifestEntry>)>,
+    options: &BackupOptions,
+) -> Vec<Result<()>> {
     rx.close();
 
-    let mut result = Ok(());
-    while let Some(r) = rx.recv().await {
+    let mut manifests: Vec<Manifest> = destinations.iter().map(|_| Manifest::default()).collect();
+    let mut results: Vec<Result<()>> = destinations.iter().map(|_| Ok(())).collect();
+    while let Some((i, r)) = rx.recv().await {
         match r {
-            Ok(_) => {}
+            Ok(entry) => manifests[i].files.push(entry),
             Err(e) => {
-                log::error!("Error during copy: {}", e);
-                result = Err(e);
+                log::error!("Error during copy to {}: {}", destinations[i].url(), e);
+                results[i] = Err(e);
             }
         }
     }
-    result
+
+    let mut complete = vec![];
+    for ((destination, manifest), r) in destinations.iter().zip(manifests).zip(results) {
+        let copied = manifest.files.len();
+        let uploaded =
+            match merge_with_existing_manifest(destination.as_ref(), manifest, options).await {
+                Ok(manifest) => {
+                    upload_json(
+                        destination.as_ref(),
+                        MANIFEST_NAME,
+                        &manifest,
+                        options,
+                        (MANIFEST_METADATA_KEY, "true"),
+                    )
+                    .await
+                }
+                Err(e) => Err(e),
+            };
+        let r = r.and(uploaded);
+        if r.is_ok() {
+            log::info!("Copied {copied} files to {}", destination.url());
+        }
+        complete.push(r);
+    }
+    complete
 }
 
-async fn upload_part(
-    s3: &Client,
-    bucket: &str,
-    key: &str,
-    upload_id: &str,
-    part_number: i32,
-    data: Vec<u8>,
-) -> Result<CompletedPart> {
-    let resp = s3
-        .upload_part()
-        .bucket(bucket)
-        .key(key)
-        .upload_id(upload_id)
-        .part_number(part_number)
-        .body(aws_sdk_s3::primitives::ByteStream::from(data))
-        .send()
+/// Merges the entries of this run into the manifest earlier runs left in `destination`, if any.
+/// A manifest that cannot be read is not replaced, since that would lose what it records.
+async fn merge_with_existing_manifest(
+    destination: &dyn Destination,
+    manifest: Manifest,
+    options: &BackupOptions,
+) -> Result<Manifest> {
+    if destination.metadata(MANIFEST_NAME).await?.is_none() {
+        return Ok(manifest);
+    }
+    let restore_options =
+        RestoreOptions { encryption_key: options.encryption_key.clone(), concurrency: 1 };
+    let mut existing = read_manifest(destination, &restore_options)
         .await
-        .chain_err(|| format!("upload_part failed for part {part_number}"))?;
+        .chain_err(|| format!("Could not add to the manifest of {}", destination.url()))?;
+    existing.merge(manifest);
+    Ok(existing)
+}
 
-    Ok(CompletedPart::builder()
-        .part_number(part_number)
-        .e_tag(resp.e_tag().unwrap_or_default())
-        .build())
+/// Fails if any destination is incomplete.
+pub(crate) fn combine_results(
+    destinations: &[Arc<dyn Destination>],
+    results: Vec<Result<()>>,
+) -> Result<()> {
+    let mut result = Ok(());
+    for (destination, r) in destinations.iter().zip(results) {
+        if let Err(e) = r {
+            log::error!("Backup to {} is incomplete: {}", destination.url(), e);
+            result = Err(e).chain_err(|| format!("Backup to {} is incomplete", destination.url()));
+        }
+    }
+    result
 }
 
-async fn copy_file(
+/// Checks everything that can be checked before downloading anything, so a misconfigured run ends
+/// with one clear error instead of one error per file. Returns the ID of the source folder.
+async fn preflight(
     drive: &Drive,
-    s3: &Client,
-    file: google_drive3::api::File,
-    destination: &str,
-    storage_class: &str,
-) -> Result<()> {
+    destinations: &[Arc<dyn Destination>],
+    source: &str,
+) -> Result<String> {
+    check_destinations(destinations).await?;
+    let folder_id = drive
+        .resolve_folder(source)
+        .await
+        .chain_err(|| format!("Could not resolve source {source}."))?;
+    Ok(folder_id)
+}
+
+/// Checks that there is a destination and that every destination is writable. The options were
+/// already validated when they were parsed.
+pub(crate) async fn check_destinations(destinations: &[Arc<dyn Destination>]) -> Result<()> {
+    if destinations.is_empty() {
+        return Err(Error::from("At least one destination is needed."));
+    }
+    for destination in destinations {
+        destination
+            .check_writable()
+            .await
+            .chain_err(|| format!("Destination {} is not writable.", destination.url()))?;
+    }
+    Ok(())
+}
+
+/// Content of a file as it is downloaded.
+pub(crate) type Content = Pin<Box<dyn Stream<Item = Result<Vec<u8>>> + Send>>;
+
+async fn drive_content(drive: &Drive, file: &google_drive3::api::File) -> Result<Content> {
+    let filename = file.name.clone().unwrap_or_default();
+    let body = drive.get_content_for(file).await?.into_body();
+    Ok(Box::pin(stream::unfold(body, move |mut body| {
+        let filename = filename.clone();
+        async move {
+            let chunk = body.data().await?.map(|chunk| chunk.to_vec());
+            Some((
+                chunk.map_err(|e| Error::from(format!("Download error for {filename}: {e}"))),
+                body,
+            ))
+        }
+    })))
+}
+
+/// Copies the content of `file`, once `download` provides it, to all destinations as `key` and
+/// returns one result per destination. `mime_type` is the type of the downloaded content.
+pub(crate) async fn copy_file(
+    destinations: &[Arc<dyn Destination>],
+    file: &google_drive3::api::File,
+    key: String,
+    mime_type: &str,
+    download: impl Future<Output = Result<Content>>,
+    options: &BackupOptions,
+) -> Vec<Result<ManifestEntry>> {
     let filename = file.name.as_ref().unwrap();
     log::info!("Copying file {filename} (mime type: {})", file.mime_type.as_ref().unwrap());
     let start_time = Instant::now();
 
-    let (bucket_name, folder_name) =
-        parse_s3_url(destination).chain_err(|| format!("Could not parse S3 URL {destination}."))?;
-    let key = folder_name.join(filename).to_str().unwrap().to_string();
-    let sc: StorageClass = storage_class.into();
+    let storage_class = options
+        .storage_class_rules
+        .storage_class_for(file, Utc::now())
+        .unwrap_or_else(|| options.storage_class.clone());
 
-    let response = drive.get_content_for(&file).await?;
-    let mut body = response.into_body();
+    let written = match download.await {
+        Ok(content) => {
+            let metadata = source_metadata(file);
+            write_content(destinations, &key, mime_type, content, &storage_class, options, metadata)
+                .await
+        }
+        Err(e) => Err(e),
+    };
+    let results = match written {
+        Ok(results) => results,
+        Err(e) => destinations
+            .iter()
+            .map(|_| Err(Error::from(format!("Could not copy {filename}: {e}"))))
+            .collect(),
+    };
+    for (destination, result) in destinations.iter().zip(&results) {
+        if let Ok(parts) = result {
+            log::info!(
+                "Uploaded {filename} to {} in {parts} parts with storage class {storage_class}",
+                destination.url()
+            );
+        }
+    }
+    if results.iter().any(Result::is_ok) {
+        log_throughput(file, start_time);
+    }
+    results
+        .into_iter()
+        .map(|r| r.map(|_| ManifestEntry::new(file, key.clone(), false, &storage_class)))
+        .collect()
+}
 
-    // Use multipart upload: stream in chunks of 64MB
-    const PART_SIZE: usize = 64 * 1024 * 1024;
+/// Streams `content` to all destinations as `key`, with `metadata` added to the object metadata.
+/// Fails only if nothing could be written anywhere. Otherwise returns the number of parts written,
+/// or the error, per destination.
+async fn write_content(
+    destinations: &[Arc<dyn Destination>],
+    key: &str,
+    mime_type: &str,
+    mut content: Content,
+    storage_class: &StorageClass,
+    options: &BackupOptions,
+    metadata: HashMap<String, String>,
+) -> Result<Vec<Result<usize>>> {
+    let mut upload =
+        PipelineUpload::create(destinations, key, mime_type, storage_class, options, metadata)
+            .await?;
+    while let Some(chunk) = content.next().await {
+        if let Err(e) = upload.write(chunk).await {
+            return Ok(upload.abort(&e.to_string()).await);
+        }
+    }
+    Ok(upload.finish().await)
+}
 
-    let create_resp = s3
-        .create_multipart_upload()
-        .bucket(&bucket_name)
-        .key(&key)
-        .storage_class(sc)
-        .send()
-        .await
-        .chain_err(|| format!("Could not create multipart upload for {filename}"))?;
-
-    let upload_id = create_resp.upload_id().unwrap().to_string();
-    let mut parts: Vec<CompletedPart> = Vec::new();
-    let mut part_number: i32 = 1;
-    let mut buf = Vec::with_capacity(PART_SIZE);
-
-    loop {
-        match body.data().await {
-            Some(Ok(chunk)) => {
-                buf.extend_from_slice(&chunk);
-                if buf.len() >= PART_SIZE {
-                    let part = upload_part(s3, &bucket_name, &key, &upload_id, part_number, std::mem::replace(&mut buf, Vec::with_capacity(PART_SIZE))).await;
-                    if let Err(e) = part {
-                        let _ = s3.abort_multipart_upload()
-                            .bucket(&bucket_name).key(&key).upload_id(&upload_id)
-                            .send().await;
-                        return Err(e).chain_err(|| format!("Could not upload part {part_number} for {filename}"));
-                    }
-                    parts.push(part.unwrap());
-                    part_number += 1;
-                }
-            }
-            Some(Err(e)) => {
-                let _ = s3.abort_multipart_upload()
-                    .bucket(&bucket_name).key(&key).upload_id(&upload_id)
-                    .send().await;
-                return Err(errors::Error::from(format!("Download error for {filename}: {e}")));
-            }
-            None => break,
+/// An object being written to all destinations through the compression and encryption pipeline.
+pub(crate) struct PipelineUpload {
+    pipeline: Pipeline,
+    upload: FanOut,
+}
+
+impl PipelineUpload {
+    /// `extra_metadata` is added to the object metadata the pipeline needs.
+    pub(crate) async fn create(
+        destinations: &[Arc<dyn Destination>],
+        key: &str,
+        mime_type: &str,
+        storage_class: &StorageClass,
+        options: &BackupOptions,
+        extra_metadata: HashMap<String, String>,
+    ) -> Result<PipelineUpload> {
+        let (pipeline, mut metadata) = pipeline_for(options, mime_type)?;
+        metadata.extend(extra_metadata);
+        let upload = FanOut::create(destinations, key, storage_class, metadata).await;
+        Ok(PipelineUpload { pipeline, upload })
+    }
+
+    /// Writes a chunk of content, or fails with the download error `chunk` holds.
+    pub(crate) async fn write(&mut self, chunk: Result<Vec<u8>>) -> Result<()> {
+        let data = chunk.and_then(|chunk| self.pipeline.update(&chunk))?;
+        self.upload.write(&data).await
+    }
+
+    /// Returns the number of parts written, or the error, per destination.
+    pub(crate) async fn finish(mut self) -> Vec<Result<usize>> {
+        let written = match self.pipeline.finish() {
+            Ok(data) => self.upload.write(&data).await,
+            Err(e) => Err(e),
+        };
+        match written {
+            Ok(()) => self.upload.finish().await,
+            Err(e) => self.upload.abort(&e.to_string()).await,
         }
     }
 
-    // Upload remaining data
-    if !buf.is_empty() || parts.is_empty() {
-        let part = upload_part(s3, &bucket_name, &key, &upload_id, part_number, buf).await;
-        if let Err(e) = part {
-            let _ = s3.abort_multipart_upload()
-                .bucket(&bucket_name).key(&key).upload_id(&upload_id)
-                .send().await;
-            return Err(e).chain_err(|| format!("Could not upload final part for {filename}"));
+    pub(crate) async fn abort(self, error: &str) -> Vec<Result<usize>> {
+        self.upload.abort(error).await
+    }
+}
+
+/// Records which version of `file` an object holds, so later runs can tell whether it changed.
+fn source_metadata(file: &google_drive3::api::File) -> HashMap<String, String> {
+    let mut metadata = HashMap::new();
+    if let Some(md5_checksum) = file.md5_checksum.as_ref() {
+        metadata.insert(SOURCE_MD5_METADATA_KEY.to_string(), md5_checksum.clone());
+    }
+    if let Some(modified_time) = file.modified_time.as_ref() {
+        metadata.insert(SOURCE_MODIFIED_TIME_METADATA_KEY.to_string(), modified_time.clone());
+    }
+    metadata
+}
+
+/// Whether an object with `metadata` holds the current version of `file`. Files are compared by
+/// their MD5 checksum, Google documents, which have none, by their modification time.
+fn is_unchanged(file: &google_drive3::api::File, metadata: &HashMap<String, String>) -> bool {
+    match (file.md5_checksum.as_ref(), file.modified_time.as_ref()) {
+        (Some(md5_checksum), _) => metadata.get(SOURCE_MD5_METADATA_KEY) == Some(md5_checksum),
+        (None, Some(modified_time)) => {
+            metadata.get(SOURCE_MODIFIED_TIME_METADATA_KEY) == Some(modified_time)
         }
-        parts.push(part.unwrap());
-    }
-
-    // Complete the multipart upload
-    s3.complete_multipart_upload()
-        .bucket(&bucket_name)
-        .key(&key)
-        .upload_id(&upload_id)
-        .multipart_upload(
-            CompletedMultipartUpload::builder()
-                .set_parts(Some(parts))
-                .build(),
-        )
-        .send()
-        .await
-        .chain_err(|| format!("Could not complete multipart upload for {filename}"))?;
+        (None, None) => false,
+    }
+}
 
-    log::info!("Uploaded {filename} in {} parts", part_number);
+/// For every destination, whether it already holds the current version of `file` as `key`, so
+/// copying it there again can be skipped. A destination that cannot tell gets the file again.
+pub(crate) async fn unchanged_in(
+    destinations: &[Arc<dyn Destination>],
+    file: &google_drive3::api::File,
+    key: &str,
+) -> Vec<bool> {
+    join_all(destinations.iter().map(|destination| async move {
+        match destination.metadata(key).await {
+            Ok(metadata) => metadata.is_some_and(|metadata| is_unchanged(file, &metadata)),
+            Err(e) => {
+                log::warn!("Could not check {key} in {}, copying it again: {e}", destination.url());
+                false
+            }
+        }
+    }))
+    .await
+}
 
+fn log_throughput(file: &google_drive3::api::File, start_time: Instant) {
+    let filename = file.name.as_ref().unwrap();
     if let Some(filesize) = file.size.as_ref() {
         let filesize = Byte::from_str(filesize).unwrap();
         log::info!(
@@ -200,7 +475,7 @@ async fn copy_file(
                 }},
                 "copy_duration": "{}",
             }}"#,
-                SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis().to_string(),
+                SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis(),
                 start_time.elapsed().as_millis()
             )
             .replace("\n", "")
@@ -208,18 +483,75 @@ async fn copy_file(
     } else {
         log::info!("Throughput for file {filename} unknown due to unknown size");
     }
+}
+
+/// Builds the transforms to apply to content of the given MIME type before uploading it, plus the
+/// object metadata `restore` needs to reverse them.
+fn pipeline_for(
+    options: &BackupOptions,
+    mime_type: &str,
+) -> Result<(Pipeline, HashMap<String, String>)> {
+    let mut pipeline = Pipeline::default();
+    let mut metadata = HashMap::new();
+    if let Some(algorithm) = options.compression.algorithm_for(mime_type) {
+        pipeline.push(algorithm.compressor()?);
+        metadata.insert(COMPRESSION_METADATA_KEY.to_string(), algorithm.name().to_string());
+    }
+    if let Some(encryption_key) = options.encryption_key.as_ref() {
+        pipeline.push(Box::new(encryption_key.encryptor()?));
+        metadata.insert(KEY_ID_METADATA_KEY.to_string(), encryption_key.id());
+    }
+    Ok((pipeline, metadata))
+}
+
+/// Uploads a small JSON bookkeeping object, like the manifest or the archive index. It is marked
+/// with `marker` in the object metadata and always stored in STANDARD, since it is small and needed
+/// to make sense of everything else.
+async fn upload_json<T: Serialize>(
+    destination: &dyn Destination,
+    name: &str,
+    value: &T,
+    options: &BackupOptions,
+    marker: (&str, &str),
+) -> Result<()> {
+    let (mut pipeline, mut metadata) = pipeline_for(options, "application/json")?;
+    metadata.insert(marker.0.to_string(), marker.1.to_string());
+
+    let mut data = pipeline.update(&serde_json::to_vec_pretty(value).unwrap())?;
+    data.extend(pipeline.finish()?);
+    let mut upload = destination.create(name, &StorageClass::Standard, metadata).await?;
+    if let Err(e) = upload.write(&data).await {
+        upload.abort().await;
+        return Err(e).chain_err(|| format!("Could not upload {name}"));
+    }
+    upload.finish().await.chain_err(|| format!("Could not upload {name}"))?;
     Ok(())
 }
 
 fn parse_s3_url(u: &str) -> Result<(String, PathBuf)> {
-    let u = Url::parse(u).unwrap();
-    assert_eq!(u.scheme(), "s3");
-    Ok((String::from(u.domain().unwrap()), PathBuf::from(&u.path().trim_start_matches("/"))))
+    let url = Url::parse(u).chain_err(|| format!("{u} is not a valid URL"))?;
+    if url.scheme() != "s3" {
+        return Err(Error::from(format!(
+            "{u} is not an S3 URL. It must have the format s3://bucket-name/some/folder"
+        )));
+    }
+    let bucket_name = url
+        .host_str()
+        .filter(|h| !h.is_empty())
+        .ok_or_else(|| Error::from(format!("{u} does not contain a bucket name")))?;
+    Ok((String::from(bucket_name), PathBuf::from(&url.path().trim_start_matches('/'))))
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::parse_s3_url;
+    use std::sync::Arc;
+
+    use aws_sdk_s3::types::StorageClass;
+    use google_drive3::api::File;
+
+    use crate::destination::Destination;
+    use crate::local::LocalDestination;
+    use crate::{parse_s3_url, source_metadata, unchanged_in};
 
     #[test]
     fn parse_s3_url_splits_bucket_name_and_path_correctly() {
@@ -238,4 +570,47 @@ mod tests {
         assert_eq!(bucket, "mybucket");
         assert_eq!(path.to_str().unwrap(), "");
     }
+
+    #[test]
+    fn parse_s3_url_rejects_invalid_urls() {
+        assert!(parse_s3_url("mybucket/some/path").is_err());
+        assert!(parse_s3_url("https://mybucket/some/path").is_err());
+        assert!(parse_s3_url("s3:///some/path").is_err());
+    }
+
+    #[tokio::test]
+    async fn only_destinations_with_the_current_version_of_a_file_hold_it_unchanged() {
+        let dir = std::env::temp_dir().join(format!("g2s3-unchanged-test-{}", std::process::id()));
+        let current = LocalDestination::new("file:///current", dir.join("current"));
+        let outdated = LocalDestination::new("file:///outdated", dir.join("outdated"));
+        let empty = LocalDestination::new("file:///empty", dir.join("empty"));
+        let file = |md5: Option<&str>, modified_time: &str| File {
+            name: Some("a.txt".to_string()),
+            md5_checksum: md5.map(str::to_string),
+            modified_time: Some(modified_time.to_string()),
+            ..Default::default()
+        };
+        async fn write(destination: &LocalDestination, file: File) {
+            let metadata = source_metadata(&file);
+            let mut writer =
+                destination.create("a.txt", &StorageClass::Standard, metadata).await.unwrap();
+            writer.write(b"hello").await.unwrap();
+            writer.finish().await.unwrap();
+        }
+        write(&current, file(Some("new"), "2024-05-02T00:00:00Z")).await;
+        write(&outdated, file(Some("old"), "2024-05-01T00:00:00Z")).await;
+        let destinations: Vec<Arc<dyn Destination>> =
+            vec![Arc::new(current), Arc::new(outdated), Arc::new(empty)];
+
+        let unchanged =
+            unchanged_in(&destinations, &file(Some("new"), "2024-05-03T00:00:00Z"), "a.txt").await;
+        assert_eq!(unchanged, vec![true, false, false]);
+
+        // Google documents have no checksum and are compared by modification time.
+        let unchanged =
+            unchanged_in(&destinations, &file(None, "2024-05-01T00:00:00Z"), "a.txt").await;
+        assert_eq!(unchanged, vec![false, true, false]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
@@ -0,0 +1,284 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use async_trait::async_trait;
+use aws_sdk_s3::types::StorageClass;
+use futures::StreamExt;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+
+use crate::destination::{safe_path, Destination, ObjectInfo, ObjectReader, ObjectWriter};
+use crate::errors::{Error, Result, ResultExt};
+
+/// Object metadata is kept in JSON files under this directory, mirroring the object names.
+const METADATA_DIR: &str = ".g2s3-metadata";
+
+/// Files are written under this suffix and only renamed to their name once complete.
+const PARTIAL_SUFFIX: &str = ".g2s3-partial";
+
+/// Objects as files in a local directory (`file:///some/folder`), e.g. on a NAS.
+pub struct LocalDestination {
+    url: String,
+    root: PathBuf,
+}
+
+impl LocalDestination {
+    pub fn new(url: &str, root: PathBuf) -> LocalDestination {
+        LocalDestination { url: url.to_string(), root }
+    }
+
+    /// The path of the object `name` relative to the root. Names that are absolute or contain `..`
+    /// are refused, so no object can end up outside of the root.
+    fn relative_path(&self, name: &str) -> Result<String> {
+        safe_path(Path::new(name))
+            .ok_or_else(|| Error::from(format!("Invalid object name {name} for {}", self.url)))
+    }
+
+    fn path(&self, name: &str) -> Result<PathBuf> {
+        Ok(self.root.join(self.relative_path(name)?))
+    }
+
+    fn metadata_path(&self, name: &str) -> Result<PathBuf> {
+        Ok(self.root.join(METADATA_DIR).join(format!("{}.json", self.relative_path(name)?)))
+    }
+}
+
+#[async_trait]
+impl Destination for LocalDestination {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn check_writable(&self) -> Result<()> {
+        let mut writer =
+            self.create(".g2s3-preflight", &StorageClass::Standard, HashMap::new()).await?;
+        writer.write(b"g2s3 preflight check").await?;
+        writer.finish().await?;
+        self.delete(".g2s3-preflight").await
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        _storage_class: &StorageClass,
+        metadata: HashMap<String, String>,
+    ) -> Result<Box<dyn ObjectWriter>> {
+        let path = self.path(name)?;
+        let partial_path = PathBuf::from(format!("{}{PARTIAL_SUFFIX}", path.display()));
+        create_parent_dir(&partial_path).await?;
+        let file = tokio::fs::File::create(&partial_path)
+            .await
+            .chain_err(|| format!("Could not create {}", partial_path.display()))?;
+        Ok(Box::new(FileWriter {
+            file,
+            path,
+            partial_path,
+            metadata_path: self.metadata_path(name)?,
+            metadata,
+        }))
+    }
+
+    async fn metadata(&self, name: &str) -> Result<Option<HashMap<String, String>>> {
+        if !self.path(name)?.is_file() {
+            return Ok(None);
+        }
+        let path = self.metadata_path(name)?;
+        match tokio::fs::read(&path).await {
+            Ok(content) => Ok(Some(
+                serde_json::from_slice(&content)
+                    .chain_err(|| format!("Could not parse {}", path.display()))?,
+            )),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Some(HashMap::new())),
+            Err(e) => Err(e).chain_err(|| format!("Could not read {}", path.display())),
+        }
+    }
+
+    async fn read(&self, name: &str) -> Result<ObjectReader> {
+        let metadata = self
+            .metadata(name)
+            .await?
+            .ok_or_else(|| Error::from(format!("There is no {name} in {}", self.url)))?;
+        let path = self.path(name)?;
+        let file = tokio::fs::File::open(&path)
+            .await
+            .chain_err(|| format!("Could not open {}", path.display()))?;
+        let content = ReaderStream::new(file).map(move |chunk| {
+            chunk.map(|c| c.to_vec()).chain_err(|| format!("Could not read {}", path.display()))
+        });
+        Ok(ObjectReader { metadata, content: Box::pin(content) })
+    }
+
+    async fn list(&self) -> Result<Vec<ObjectInfo>> {
+        let mut objects = vec![];
+        let mut dirs = vec![self.root.clone()];
+        while let Some(dir) = dirs.pop() {
+            let mut entries = match tokio::fs::read_dir(&dir).await {
+                Ok(entries) => entries,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound && dir == self.root => {
+                    return Ok(objects)
+                }
+                Err(e) => return Err(e).chain_err(|| format!("Could not list {}", dir.display())),
+            };
+            while let Some(entry) = entries
+                .next_entry()
+                .await
+                .chain_err(|| format!("Could not list {}", dir.display()))?
+            {
+                let path = entry.path();
+                let file_type = entry
+                    .file_type()
+                    .await
+                    .chain_err(|| format!("Could not read {}", path.display()))?;
+                if file_type.is_dir() {
+                    if path != self.root.join(METADATA_DIR) {
+                        dirs.push(path);
+                    }
+                } else if !path.to_string_lossy().ends_with(PARTIAL_SUFFIX) {
+                    let size = entry
+                        .metadata()
+                        .await
+                        .chain_err(|| format!("Could not read {}", path.display()))?
+                        .len();
+                    let name = path.strip_prefix(&self.root).unwrap().to_str().unwrap().to_string();
+                    objects.push(ObjectInfo { name, size });
+                }
+            }
+        }
+        objects.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(objects)
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let path = self.path(name)?;
+        tokio::fs::remove_file(&path)
+            .await
+            .chain_err(|| format!("Could not delete {}", path.display()))?;
+        match tokio::fs::remove_file(self.metadata_path(name)?).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                Err(e).chain_err(|| format!("Could not delete metadata of {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+struct FileWriter {
+    file: tokio::fs::File,
+    path: PathBuf,
+    partial_path: PathBuf,
+    metadata_path: PathBuf,
+    metadata: HashMap<String, String>,
+}
+
+impl FileWriter {
+    async fn complete(&mut self) -> Result<()> {
+        self.file.flush().await.chain_err(|| format!("Could not write {}", self.path.display()))?;
+        self.file
+            .sync_all()
+            .await
+            .chain_err(|| format!("Could not write {}", self.path.display()))?;
+        if self.metadata.is_empty() {
+            let _ = tokio::fs::remove_file(&self.metadata_path).await;
+        } else {
+            create_parent_dir(&self.metadata_path).await?;
+            tokio::fs::write(
+                &self.metadata_path,
+                serde_json::to_vec_pretty(&self.metadata).unwrap(),
+            )
+            .await
+            .chain_err(|| format!("Could not write {}", self.metadata_path.display()))?;
+        }
+        tokio::fs::rename(&self.partial_path, &self.path)
+            .await
+            .chain_err(|| format!("Could not rename {}", self.partial_path.display()))
+    }
+}
+
+#[async_trait]
+impl ObjectWriter for FileWriter {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.file
+            .write_all(data)
+            .await
+            .chain_err(|| format!("Could not write {}", self.partial_path.display()))
+    }
+
+    async fn finish(mut self: Box<Self>) -> Result<usize> {
+        match self.complete().await {
+            Ok(()) => Ok(1),
+            Err(e) => {
+                self.abort().await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn abort(self: Box<Self>) {
+        let _ = tokio::fs::remove_file(&self.partial_path).await;
+    }
+}
+
+async fn create_parent_dir(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .chain_err(|| format!("Could not create directory {}", parent.display()))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use aws_sdk_s3::types::StorageClass;
+    use futures::TryStreamExt;
+
+    use crate::destination::{Destination, ObjectInfo};
+    use crate::local::LocalDestination;
+
+    #[tokio::test]
+    async fn objects_can_be_written_read_listed_and_deleted() {
+        let root = std::env::temp_dir().join(format!("g2s3-local-test-{}", std::process::id()));
+        let destination = LocalDestination::new("file:///test", root.clone());
+        destination.check_writable().await.unwrap();
+
+        let metadata = HashMap::from([("g2s3-compression".to_string(), "zstd".to_string())]);
+        let mut writer = destination
+            .create("sub/a.txt", &StorageClass::Standard, metadata.clone())
+            .await
+            .unwrap();
+        writer.write(b"hello ").await.unwrap();
+        writer.write(b"world").await.unwrap();
+        assert_eq!(destination.metadata("sub/a.txt").await.unwrap(), None);
+        writer.finish().await.unwrap();
+
+        let mut writer =
+            destination.create("b.txt", &StorageClass::Standard, HashMap::new()).await.unwrap();
+        writer.write(b"discarded").await.unwrap();
+        writer.abort().await;
+
+        assert_eq!(
+            destination.list().await.unwrap(),
+            vec![ObjectInfo { name: "sub/a.txt".to_string(), size: 11 }]
+        );
+        let reader = destination.read("sub/a.txt").await.unwrap();
+        assert_eq!(reader.metadata, metadata);
+        let content: Vec<Vec<u8>> = reader.content.try_collect().await.unwrap();
+        assert_eq!(content.concat(), b"hello world");
+
+        destination.delete("sub/a.txt").await.unwrap();
+        assert_eq!(destination.metadata("sub/a.txt").await.unwrap(), None);
+        assert!(destination.list().await.unwrap().is_empty());
+
+        for name in ["../escape", "sub/../../escape", "/etc/passwd", ""] {
+            let result = destination.create(name, &StorageClass::Standard, HashMap::new()).await;
+            assert!(result.is_err(), "{name} was accepted");
+            assert!(destination.metadata(name).await.is_err());
+            assert!(destination.delete(name).await.is_err());
+        }
+        assert!(!root.parent().unwrap().join("escape").exists());
+
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+use std::collections::HashSet;
+
+use aws_sdk_s3::types::StorageClass;
+use google_drive3::api::File;
+use serde::{Deserialize, Serialize};
+
+/// Object metadata key marking the manifest, so `restore` can skip it.
+pub const MANIFEST_METADATA_KEY: &str = "g2s3-manifest";
+
+pub const MANIFEST_NAME: &str = "g2s3-manifest.json";
+
+/// Records every Drive file the backup runs into a destination stored and where it went.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Manifest {
+    pub files: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Adds the entries of a later run. They replace the entries of the same files in the same
+    /// objects, and whatever else earlier runs stored is kept, so incremental runs do not lose the
+    /// entries of the files they did not copy again.
+    pub fn merge(&mut self, later: Manifest) {
+        let replaced: HashSet<(String, String)> =
+            later.files.iter().map(|e| (e.id.clone(), e.key.clone())).collect();
+        self.files.retain(|e| !replaced.contains(&(e.id.clone(), e.key.clone())));
+        self.files.extend(later.files);
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ManifestEntry {
+    pub id: String,
+    pub name: String,
+    pub mime_type: String,
+    /// Name of the object holding the file's content, relative to the destination folder. For
+    /// packed files, this is the archive.
+    pub key: String,
+    pub packed: bool,
+    pub size: Option<u64>,
+    pub md5_checksum: Option<String>,
+    pub modified_time: Option<String>,
+    pub storage_class: String,
+    /// Gmail labels of a message.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub labels: Vec<String>,
+}
+
+impl ManifestEntry {
+    pub fn new(
+        file: &File,
+        key: String,
+        packed: bool,
+        storage_class: &StorageClass,
+    ) -> ManifestEntry {
+        ManifestEntry {
+            id: file.id.clone().unwrap_or_default(),
+            name: file.name.clone().unwrap_or_default(),
+            mime_type: file.mime_type.clone().unwrap_or_default(),
+            key,
+            packed,
+            size: file.size.as_ref().and_then(|s| s.parse().ok()),
+            md5_checksum: file.md5_checksum.clone(),
+            modified_time: file.modified_time.clone(),
+            storage_class: storage_class.as_str().to_string(),
+            labels: vec![],
+        }
+    }
+}
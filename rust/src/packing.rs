@@ -0,0 +1,501 @@
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use futures::{stream, Stream, StreamExt};
+use google_drive3::api::File;
+use google_drive3::hyper;
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::destination::Destination;
+use crate::drive::Drive;
+use crate::errors::{Error, Result, ResultExt};
+use crate::fan_out::FanOut;
+use crate::manifest::ManifestEntry;
+use crate::transform::{Pipeline, Transform};
+use crate::{pipeline_for, upload_json, BackupOptions};
+
+/// Object metadata key marking archives (`tar`) and their index (`index`).
+pub const ARCHIVE_METADATA_KEY: &str = "g2s3-archive";
+
+/// The name of archive `n` of the run started at `run`. Archives and index are named after the run,
+/// so a later run into a destination without `{date}` does not overwrite them.
+pub fn archive_key(run: DateTime<Utc>, n: usize) -> String {
+    format!("g2s3-archive-{}-{n:05}.tar", run.format("%Y%m%dT%H%M%SZ"))
+}
+
+pub fn index_name(run: DateTime<Utc>) -> String {
+    format!("g2s3-archive-index-{}.json", run.format("%Y%m%dT%H%M%SZ"))
+}
+
+/// Decides which archive of a run every packed file goes into. `pack_files` and the dry-run plan
+/// both use it, so the plan shows the archives a run writes.
+pub struct ArchiveSplit {
+    run: DateTime<Utc>,
+    max_archive_size: u64,
+    count: usize,
+    /// Bytes of the tar stream of the current archive, if one was started.
+    len: Option<u64>,
+}
+
+impl ArchiveSplit {
+    pub fn new(run: DateTime<Utc>, packing: &PackingOptions) -> ArchiveSplit {
+        ArchiveSplit { run, max_archive_size: packing.max_archive_size, count: 0, len: None }
+    }
+
+    /// Returns the key of the archive a file of `size` bytes goes into, and whether that is a new
+    /// archive. A new one is started when the file would not fit into the current one. A file
+    /// takes a 512-byte header plus its content padded to 512-byte blocks.
+    pub fn place(&mut self, size: u64) -> (String, bool) {
+        let new = self.len.is_none_or(|len| len + size > self.max_archive_size);
+        if new {
+            self.count += 1;
+            self.len = Some(0);
+        }
+        self.len = self.len.map(|len| len + 512 + size.div_ceil(512) * 512);
+        (archive_key(self.run, self.count), new)
+    }
+
+    /// The number of archives started.
+    pub fn count(&self) -> usize {
+        self.count
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PackingOptions {
+    /// Files smaller than this are packed. Files of unknown size (Google documents) never are.
+    pub max_file_size: u64,
+    /// A new archive is started once an archive reaches this size.
+    pub max_archive_size: u64,
+}
+
+impl PackingOptions {
+    pub fn should_pack(&self, file: &File) -> bool {
+        file.size
+            .as_ref()
+            .and_then(|s| s.parse::<u64>().ok())
+            .is_some_and(|s| s < self.max_file_size)
+    }
+}
+
+/// Maps every packed Drive file to the archive it ended up in.
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Index {
+    pub files: Vec<IndexEntry>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct IndexEntry {
+    pub id: String,
+    pub name: String,
+    pub mime_type: String,
+    pub md5_checksum: Option<String>,
+    /// Name of the archive, relative to the destination folder.
+    pub archive: String,
+    /// Position of the file's content within the (decompressed and decrypted) tar archive.
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Downloads `files` and streams them into size-capped tar archives in every destination, followed
+/// by an index object that records where each file went. Archives and index are named after `run`.
+/// Once a destination has its archives and index, a manifest entry per packed file is sent to `tx`
+/// for it, tagged with the destination's position in `destinations`. Errors are sent the same way.
+pub async fn pack_files(
+    drive: &Drive,
+    destinations: &[Arc<dyn Destination>],
+    files: Vec<File>,
+    options: &BackupOptions,
+    run: DateTime<Utc>,
+    tx: &UnboundedSender<(usize, Result<ManifestEntry>)>,
+) -> Result<()> {
+    let packing = match options.packing.as_ref() {
+        Some(packing) if !files.is_empty() => packing,
+        _ => return Ok(()),
+    };
+    let downloads = stream::iter(files)
+        .map(|file| async move {
+            let content = match drive.get_content_for(&file).await {
+                Ok(response) => hyper::body::to_bytes(response.into_body())
+                    .await
+                    .map_err(|e| Error::from(format!("Download error: {e}"))),
+                Err(e) => Err(e),
+            };
+            (file, content)
+        })
+        .buffered(options.concurrency);
+    pack_downloads(destinations, downloads, packing, options, run, tx).await
+}
+
+/// Packs files as `downloads` yields them with their content, see `pack_files`.
+async fn pack_downloads(
+    destinations: &[Arc<dyn Destination>],
+    downloads: impl Stream<Item = (File, Result<hyper::body::Bytes>)>,
+    packing: &PackingOptions,
+    options: &BackupOptions,
+    run: DateTime<Utc>,
+    tx: &UnboundedSender<(usize, Result<ManifestEntry>)>,
+) -> Result<()> {
+    let mut packed = Packed {
+        indexes: destinations.iter().map(|_| Index::default()).collect(),
+        entries: destinations.iter().map(|_| vec![]).collect(),
+    };
+    let mut split = ArchiveSplit::new(run, packing);
+    let mut archive: Option<Archive> = None;
+
+    let mut downloads = std::pin::pin!(downloads);
+    while let Some((file, content)) = downloads.next().await {
+        let filename = file.name.clone().unwrap_or_default();
+        let content = match content {
+            Ok(content) => content,
+            Err(e) => {
+                for i in 0..destinations.len() {
+                    let error = Error::from(format!("Could not download {filename}: {e}"));
+                    tx.send((i, Err(error))).unwrap();
+                }
+                continue;
+            }
+        };
+
+        let (key, new) = split.place(content.len() as u64);
+        if new {
+            if let Some(archive) = archive.take() {
+                archive.finish(&mut packed, tx).await;
+            }
+            log::info!("Starting archive {key}");
+            archive = Some(Archive::new(destinations, key, options).await?);
+        }
+        let current = archive.as_mut().unwrap();
+
+        log::info!("Packing file {filename} into {}", current.key);
+        let offset = match current.append(&filename, &content) {
+            Ok(offset) => offset,
+            Err(e) => {
+                for i in 0..destinations.len() {
+                    let error = Error::from(format!("Could not pack {filename}: {e}"));
+                    tx.send((i, Err(error))).unwrap();
+                }
+                continue;
+            }
+        };
+        if let Err(e) = current.write_pending().await {
+            archive.take().unwrap().abort().await;
+            return Err(e).chain_err(|| format!("Could not pack {filename}"));
+        }
+        let manifest_entry =
+            ManifestEntry::new(&file, current.key.clone(), true, &options.storage_class);
+        current.files.push((
+            IndexEntry {
+                id: file.id.clone().unwrap_or_default(),
+                name: filename,
+                mime_type: file.mime_type.clone().unwrap_or_default(),
+                md5_checksum: file.md5_checksum.clone(),
+                archive: current.key.clone(),
+                offset,
+                size: content.len() as u64,
+            },
+            manifest_entry,
+        ));
+    }
+    if let Some(archive) = archive {
+        archive.finish(&mut packed, tx).await;
+    }
+
+    for (i, (destination, (index, entries))) in
+        destinations.iter().zip(packed.indexes.iter().zip(packed.entries)).enumerate()
+    {
+        let uploaded = upload_json(
+            destination.as_ref(),
+            &index_name(run),
+            index,
+            options,
+            (ARCHIVE_METADATA_KEY, "index"),
+        )
+        .await;
+        if let Err(e) = uploaded {
+            tx.send((i, Err(e).chain_err(|| "Could not upload archive index"))).unwrap();
+            continue;
+        }
+        log::info!(
+            "Packed {} files into {} archives in {}",
+            index.files.len(),
+            split.count(),
+            destination.url()
+        );
+        for entry in entries {
+            tx.send((i, Ok(entry))).unwrap();
+        }
+    }
+    Ok(())
+}
+
+/// What ended up in which destination's archives, indexed like the destinations.
+struct Packed {
+    indexes: Vec<Index>,
+    entries: Vec<Vec<ManifestEntry>>,
+}
+
+struct Archive {
+    key: String,
+    builder: tar::Builder<Vec<u8>>,
+    pipeline: Pipeline,
+    upload: FanOut,
+    /// Bytes of the tar stream written so far.
+    len: u64,
+    files: Vec<(IndexEntry, ManifestEntry)>,
+}
+
+impl Archive {
+    async fn new(
+        destinations: &[Arc<dyn Destination>],
+        key: String,
+        options: &BackupOptions,
+    ) -> Result<Archive> {
+        let (pipeline, mut metadata) = pipeline_for(options, "application/x-tar")?;
+        metadata.insert(ARCHIVE_METADATA_KEY.to_string(), "tar".to_string());
+        let upload = FanOut::create(destinations, &key, &options.storage_class, metadata).await;
+        Ok(Archive {
+            key,
+            builder: tar::Builder::new(vec![]),
+            pipeline,
+            upload,
+            len: 0,
+            files: vec![],
+        })
+    }
+
+    /// Appends a file to the tar stream and returns the offset of its content. `write_pending`
+    /// uploads it. A file that cannot be added, e.g. because of its name, leaves the archive as it
+    /// was.
+    fn append(&mut self, name: &str, content: &[u8]) -> Result<u64> {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(content.len() as u64);
+        header.set_mode(0o644);
+        if let Err(e) = self.builder.append_data(&mut header, name, content) {
+            self.builder.get_mut().clear();
+            return Err(e).chain_err(|| format!("Could not add {name} to tar archive"));
+        }
+        // The content is the last thing written, padded to a multiple of the 512-byte block size.
+        let end = self.len + self.builder.get_ref().len() as u64;
+        Ok(end - (content.len() as u64).div_ceil(512) * 512)
+    }
+
+    /// Completes the archive in every destination and credits its files to the destinations that
+    /// got it. Destinations that did not get it receive an error.
+    async fn finish(
+        mut self,
+        packed: &mut Packed,
+        tx: &UnboundedSender<(usize, Result<ManifestEntry>)>,
+    ) {
+        let results = match self.flush().await {
+            Ok(()) => self.upload.finish().await,
+            Err(e) => {
+                let e = Error::with_chain(e, format!("Could not finish tar archive {}", self.key));
+                self.upload.abort(&e.to_string()).await
+            }
+        };
+        for (i, result) in results.into_iter().enumerate() {
+            match result {
+                Ok(parts) => {
+                    log::info!("Uploaded archive {} in {parts} parts", self.key);
+                    for (index_entry, manifest_entry) in &self.files {
+                        packed.indexes[i].files.push(index_entry.clone());
+                        packed.entries[i].push(manifest_entry.clone());
+                    }
+                }
+                Err(e) => tx.send((i, Err(e))).unwrap(),
+            }
+        }
+    }
+
+    async fn abort(self) {
+        self.upload.abort("Packing failed").await;
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        self.builder.finish().chain_err(|| "Could not write end of tar archive")?;
+        self.write_pending().await?;
+        let data = self.pipeline.finish()?;
+        self.upload.write(&data).await
+    }
+
+    async fn write_pending(&mut self) -> Result<()> {
+        let pending = std::mem::take(self.builder.get_mut());
+        self.len += pending.len() as u64;
+        let data = self.pipeline.update(&pending)?;
+        self.upload.write(&data).await
+    }
+}
+
+/// Unpacks a tar archive restored to `archive` into the directory it is in and removes it.
+pub fn unpack(archive: &std::path::Path) -> Result<()> {
+    let target_dir = archive.parent().unwrap();
+    tar::Archive::new(
+        std::fs::File::open(archive)
+            .chain_err(|| format!("Could not open {}", archive.display()))?,
+    )
+    .unpack(target_dir)
+    .chain_err(|| format!("Could not unpack {}", archive.display()))?;
+    std::fs::remove_file(archive).chain_err(|| format!("Could not remove {}", archive.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::io::Read;
+    use std::sync::Arc;
+
+    use aws_sdk_s3::types::StorageClass;
+    use chrono::{TimeZone, Utc};
+    use futures::stream;
+    use google_drive3::api::File;
+    use google_drive3::hyper::body::Bytes;
+    use tokio::sync::mpsc;
+
+    use crate::compression::CompressionRules;
+    use crate::destination::Destination;
+    use crate::local::LocalDestination;
+    use crate::packing::{index_name, pack_downloads, Archive, Index, Packed, PackingOptions};
+    use crate::plan::{Action, Plan};
+    use crate::storage_class::StorageClassRules;
+    use crate::BackupOptions;
+
+    fn options(packing: Option<PackingOptions>) -> BackupOptions {
+        BackupOptions {
+            storage_class: StorageClass::Standard,
+            storage_class_rules: StorageClassRules::default(),
+            compression: CompressionRules::default(),
+            encryption_key: None,
+            packing,
+            concurrency: 1,
+        }
+    }
+
+    #[test]
+    fn only_small_files_of_known_size_are_packed() {
+        let packing = PackingOptions { max_file_size: 1000, max_archive_size: 1 << 30 };
+        let file = |size: Option<&str>| File { size: size.map(String::from), ..Default::default() };
+
+        assert!(packing.should_pack(&file(Some("999"))));
+        assert!(!packing.should_pack(&file(Some("1000"))));
+        assert!(!packing.should_pack(&file(None)));
+    }
+
+    #[test]
+    fn content_offset_is_where_tar_stores_the_file() {
+        let mut builder = tar::Builder::new(vec![]);
+        let mut offsets = HashMap::new();
+        for (name, content) in [("a.txt", "hello".repeat(200)), ("b.txt", "world".to_string())] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            builder.append_data(&mut header, name, content.as_bytes()).unwrap();
+            let len = builder.get_ref().len() as u64;
+            offsets.insert(name, len - (content.len() as u64).div_ceil(512) * 512);
+        }
+        let tar = builder.into_inner().unwrap();
+
+        let mut archive = tar::Archive::new(&tar[..]);
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_str().unwrap().to_string();
+            assert_eq!(entry.raw_file_position(), offsets[name.as_str()]);
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            assert_eq!(
+                &tar[offsets[name.as_str()] as usize..][..content.len()],
+                content.as_bytes()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn a_file_that_cannot_be_added_is_skipped_and_the_archive_stays_valid() {
+        let dir = std::env::temp_dir().join(format!("g2s3-packing-test-{}", std::process::id()));
+        let destinations: Vec<Arc<dyn Destination>> =
+            vec![Arc::new(LocalDestination::new("file:///test", dir.clone()))];
+        let options = options(None);
+        let mut archive = Archive::new(&destinations, "a.tar".to_string(), &options).await.unwrap();
+
+        let mut offsets = HashMap::new();
+        for (name, content) in [("a.txt", "hello"), ("../escape.txt", "nope"), ("b.txt", "world")] {
+            match archive.append(name, content.as_bytes()) {
+                Ok(offset) => {
+                    archive.write_pending().await.unwrap();
+                    offsets.insert(name.to_string(), offset);
+                }
+                Err(e) => assert!(e.to_string().contains("../escape.txt"), "{e}"),
+            }
+        }
+        let mut packed = Packed { indexes: vec![Index::default()], entries: vec![vec![]] };
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        archive.finish(&mut packed, &tx).await;
+        assert!(rx.try_recv().is_err());
+
+        let tar = std::fs::read(dir.join("a.tar")).unwrap();
+        let mut archive = tar::Archive::new(&tar[..]);
+        let mut names = vec![];
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_str().unwrap().to_string();
+            assert_eq!(entry.raw_file_position(), offsets[&name]);
+            let mut content = String::new();
+            entry.read_to_string(&mut content).unwrap();
+            assert_eq!(&tar[offsets[&name] as usize..][..content.len()], content.as_bytes());
+            names.push(name);
+        }
+        assert_eq!(names, vec!["a.txt", "b.txt"]);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn files_end_up_in_the_archives_the_plan_shows() {
+        let dir = std::env::temp_dir().join(format!("g2s3-packing-plan-{}", std::process::id()));
+        let destinations: Vec<Arc<dyn Destination>> =
+            vec![Arc::new(LocalDestination::new("file:///test", dir.clone()))];
+        let packing = PackingOptions { max_file_size: 2000, max_archive_size: 3000 };
+        let options = options(Some(packing.clone()));
+        let files: Vec<File> = [1500, 700, 10, 1999, 512, 1, 900, 1300]
+            .into_iter()
+            .enumerate()
+            .map(|(i, size)| File {
+                id: Some(i.to_string()),
+                name: Some(format!("{i}.bin")),
+                mime_type: Some("application/octet-stream".to_string()),
+                size: Some(size.to_string()),
+                ..Default::default()
+            })
+            .collect();
+        let run = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+
+        let plan = Plan::new(vec![], files.clone(), &HashSet::new(), &options, run);
+        let downloads = stream::iter(files.into_iter().map(|file| {
+            let size = file.size.as_ref().unwrap().parse().unwrap();
+            (file, Ok(Bytes::from(vec![b'x'; size])))
+        }));
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        pack_downloads(&destinations, downloads, &packing, &options, run, &tx).await.unwrap();
+
+        let index: Index =
+            serde_json::from_slice(&std::fs::read(dir.join(index_name(run))).unwrap()).unwrap();
+        let packed: Vec<(String, String)> =
+            index.files.into_iter().map(|f| (f.name, f.archive)).collect();
+        let planned: Vec<(String, String)> = plan
+            .files
+            .into_iter()
+            .map(|f| {
+                assert_eq!(f.action, Action::Pack);
+                (f.name, f.key.unwrap())
+            })
+            .collect();
+        assert_eq!(packed, planned);
+        assert!(planned.iter().any(|(_, key)| key.ends_with("-00003.tar")));
+        while let Ok((_, entry)) = rx.try_recv() {
+            entry.unwrap();
+        }
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
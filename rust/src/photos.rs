@@ -0,0 +1,395 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{stream, StreamExt};
+use google_drive3::api::File;
+use google_drive3::oauth2::authorized_user::AuthorizedUserSecret;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use tokio::sync::mpsc;
+
+use crate::destination::Destination;
+use crate::errors::{Error, Result, ResultExt};
+use crate::google_api::GoogleApi;
+use crate::{check_destinations, copy_file, finish_back_up, BackupOptions, Content};
+
+const API: &str = "https://photospicker.googleapis.com/v1";
+
+/// Google removed `photoslibrary.readonly` on 2025-03-31, and the Library API can no longer read
+/// media items that other apps created. The Picker API can read any item, but only the ones the user
+/// selects in the browser.
+const SCOPE: &str = "https://www.googleapis.com/auth/photospicker.mediaitems.readonly";
+
+/// How long to wait for the user to select media items if Google does not say.
+const DEFAULT_PICKING_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+const DEFAULT_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Client for the Google Photos Picker API.
+pub struct Photos {
+    api: GoogleApi,
+}
+
+/// A picking session, in which the user selects media items at `picker_uri`.
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct PickingSession {
+    pub id: String,
+    #[serde(default)]
+    pub picker_uri: String,
+    #[serde(default)]
+    pub polling_config: PollingConfig,
+    #[serde(default)]
+    pub media_items_set: bool,
+}
+
+/// Durations in the API format, e.g. `5s` or `1800.5s`.
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct PollingConfig {
+    pub poll_interval: Option<String>,
+    pub timeout_in: Option<String>,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaItem {
+    pub id: String,
+    pub create_time: Option<String>,
+    /// `PHOTO` or `VIDEO`.
+    #[serde(default, rename = "type")]
+    pub kind: String,
+    #[serde(default)]
+    pub media_file: MediaFile,
+}
+
+#[derive(Deserialize, Clone, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct MediaFile {
+    /// Valid for an hour after the items were selected.
+    #[serde(default)]
+    pub base_url: String,
+    #[serde(default)]
+    pub mime_type: String,
+    #[serde(default)]
+    pub filename: String,
+}
+
+impl MediaItem {
+    fn is_video(&self) -> bool {
+        self.kind == "VIDEO"
+    }
+
+    /// The URL of the original: `=d` downloads images with their metadata, `=dv` videos.
+    fn download_url(&self) -> String {
+        match self.is_video() {
+            true => format!("{}=dv", self.media_file.base_url),
+            false => format!("{}=d", self.media_file.base_url),
+        }
+    }
+
+    /// Describes the item like a Drive file, so storage class rules and the manifest treat both
+    /// alike. The Picker API does not report sizes, so size conditions never match.
+    fn as_file(&self) -> File {
+        File {
+            id: Some(self.id.clone()),
+            name: Some(self.media_file.filename.clone()),
+            mime_type: Some(self.media_file.mime_type.clone()),
+            modified_time: self.create_time.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct MediaItemList {
+    #[serde(default)]
+    media_items: Vec<MediaItem>,
+    next_page_token: Option<String>,
+}
+
+impl Photos {
+    pub async fn new(aus: AuthorizedUserSecret) -> Result<Photos> {
+        Ok(Photos { api: GoogleApi::new(aus, &[SCOPE]).await? })
+    }
+
+    pub async fn create_session(&self) -> Result<PickingSession> {
+        let request = self
+            .api
+            .http()
+            .post(format!("{API}/sessions"))
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body("{}");
+        self.api
+            .send_json(request)
+            .await
+            .chain_err(|| "Could not start selecting media items in Google Photos")
+    }
+
+    /// Polls the session until the user is done selecting media items, or it times out.
+    pub async fn wait_for_selection(&self, session: &PickingSession) -> Result<()> {
+        let config = &session.polling_config;
+        let interval =
+            config.poll_interval.as_deref().and_then(api_duration).unwrap_or(DEFAULT_POLL_INTERVAL);
+        let timeout =
+            config.timeout_in.as_deref().and_then(api_duration).unwrap_or(DEFAULT_PICKING_TIMEOUT);
+        let deadline = Instant::now() + timeout;
+        loop {
+            let polled: PickingSession = self
+                .api
+                .send_json(self.api.http().get(format!("{API}/sessions/{}", session.id)))
+                .await
+                .chain_err(|| "Could not check the selection in Google Photos")?;
+            if polled.media_items_set {
+                return Ok(());
+            }
+            if Instant::now() + interval > deadline {
+                return Err(Error::from(format!(
+                    "No media items were selected in Google Photos within {}",
+                    humantime::format_duration(timeout)
+                )));
+            }
+            tokio::time::sleep(interval).await;
+        }
+    }
+
+    /// Lists the media items the user selected in the session.
+    pub async fn list_media_items(&self, session: &PickingSession) -> Result<Vec<MediaItem>> {
+        let mut items = vec![];
+        let mut page_token: Option<String> = None;
+        loop {
+            let mut request = self
+                .api
+                .http()
+                .get(format!("{API}/mediaItems"))
+                .query(&[("sessionId", session.id.as_str()), ("pageSize", "100")]);
+            if let Some(page_token) = page_token.as_ref() {
+                request = request.query(&[("pageToken", page_token)]);
+            }
+            let list: MediaItemList = self
+                .api
+                .send_json(request)
+                .await
+                .chain_err(|| "Could not list the selected media items in Google Photos")?;
+            items.extend(list.media_items);
+            match list.next_page_token {
+                Some(next_page_token) => page_token = Some(next_page_token),
+                None => break,
+            }
+        }
+        Ok(items)
+    }
+
+    pub async fn delete_session(&self, session: &PickingSession) -> Result<()> {
+        let request = self.api.http().delete(format!("{API}/sessions/{}", session.id));
+        let resp = self.api.send(request).await?;
+        if !resp.status().is_success() {
+            return Err(Error::from(format!("Request failed with {}", resp.status())));
+        }
+        Ok(())
+    }
+
+    /// Downloads the original of `item`. Unlike Library API URLs, picked ones need an access token.
+    pub async fn get_content_for(&self, item: &MediaItem) -> Result<Content> {
+        let filename = item.media_file.filename.clone();
+        let resp = self
+            .api
+            .send(self.api.http().get(item.download_url()))
+            .await
+            .chain_err(|| format!("Could not download {filename}"))?;
+        if !resp.status().is_success() {
+            return Err(Error::from(format!("Could not download {filename}: {}", resp.status())));
+        }
+        Ok(Box::pin(stream::unfold(resp, move |mut resp| {
+            let filename = filename.clone();
+            async move {
+                match resp.chunk().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk.to_vec()), resp)),
+                    Ok(None) => None,
+                    Err(e) => Some((
+                        Err(Error::from(format!("Download error for {filename}: {e}"))),
+                        resp,
+                    )),
+                }
+            }
+        })))
+    }
+}
+
+/// Parses a duration in the API format, seconds with an `s` suffix.
+fn api_duration(s: &str) -> Option<Duration> {
+    s.strip_suffix('s')?.parse::<f64>().ok().and_then(|s| Duration::try_from_secs_f64(s).ok())
+}
+
+/// Backs up the media items the user selects in Google Photos to every destination, using the same
+/// upload pipeline and manifest as Drive backups. The link to select them is printed, and the run
+/// waits until the selection is done.
+pub async fn back_up_photos(
+    photos: Arc<Photos>,
+    destinations: Vec<Arc<dyn Destination>>,
+    options: &BackupOptions,
+) -> Result<()> {
+    check_destinations(&destinations).await?;
+
+    let session = photos.create_session().await?;
+    println!("Select the media items to back up in Google Photos: {}", session.picker_uri);
+    photos.wait_for_selection(&session).await?;
+    let items = photos.list_media_items(&session).await?;
+    let keyed = keys_for(items);
+    log::info!("Backing up {} media items", keyed.len());
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    stream::iter(keyed)
+        .for_each_concurrent(options.concurrency, |(item, key)| {
+            let (photos, destinations, tx) = (photos.clone(), &destinations, tx.clone());
+            async move {
+                let file = item.as_file();
+                let content = photos.get_content_for(&item);
+                let mime_type = &item.media_file.mime_type;
+                let results =
+                    copy_file(destinations, &file, key, mime_type, content, options).await;
+                for (i, result) in results.into_iter().enumerate() {
+                    tx.send((i, result)).unwrap();
+                }
+            }
+        })
+        .await;
+    if let Err(e) = photos.delete_session(&session).await {
+        log::warn!("Could not end the selection in Google Photos: {e}");
+    }
+
+    finish_back_up(&destinations, rx, options).await
+}
+
+/// Assigns every item its object name, `<year>/<month>/<filename>` by creation time. Names that
+/// would collide get a suffix derived from the item's ID.
+fn keys_for(items: Vec<MediaItem>) -> Vec<(MediaItem, String)> {
+    let mut keyed = vec![];
+    for item in items {
+        let date = match item.create_time.as_deref() {
+            Some(time) if time.len() >= 7 => format!("{}/{}", &time[..4], &time[5..7]),
+            _ => "unknown-date".to_string(),
+        };
+        let key = format!("{date}/{}", sanitize(&item.media_file.filename));
+        keyed.push((item, key));
+    }
+
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    for (_, key) in &keyed {
+        *counts.entry(key.clone()).or_default() += 1;
+    }
+    keyed
+        .into_iter()
+        .map(|(item, key)| match counts[&key] {
+            1 => (item, key),
+            _ => {
+                let suffix = &hex::encode(Sha256::digest(item.id.as_bytes()))[..8];
+                let key = match key.rsplit_once('.') {
+                    Some((stem, ext)) if !stem.ends_with('/') => format!("{stem}-{suffix}.{ext}"),
+                    _ => format!("{key}-{suffix}"),
+                };
+                (item, key)
+            }
+        })
+        .collect()
+}
+
+/// Keeps filenames from adding folder levels or leaving their folder.
+fn sanitize(name: &str) -> String {
+    match name.replace(['/', '\\'], "_").trim() {
+        "" | "." | ".." => "untitled".to_string(),
+        name => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::photos::{api_duration, keys_for, sanitize, MediaFile, MediaItem, PickingSession};
+
+    fn item(id: &str, filename: &str, create_time: &str) -> MediaItem {
+        MediaItem {
+            id: id.to_string(),
+            create_time: Some(create_time.to_string()),
+            kind: "PHOTO".to_string(),
+            media_file: MediaFile {
+                base_url: format!("https://lh3.googleusercontent.com/{id}"),
+                mime_type: "image/jpeg".to_string(),
+                filename: filename.to_string(),
+            },
+        }
+    }
+
+    #[test]
+    fn items_are_stored_by_date() {
+        let keys: Vec<String> = keys_for(vec![
+            item("1", "beach.jpg", "2019-07-04T10:00:00Z"),
+            item("2", "a/cat.jpg", "2021-01-02T10:00:00Z"),
+        ])
+        .into_iter()
+        .map(|(_, key)| key)
+        .collect();
+
+        assert_eq!(keys, vec!["2019/07/beach.jpg", "2021/01/a_cat.jpg"]);
+    }
+
+    #[test]
+    fn names_cannot_add_or_leave_folders() {
+        assert_eq!(sanitize("a/b\\c.jpg"), "a_b_c.jpg");
+        assert_eq!(sanitize(".."), "untitled");
+        assert_eq!(sanitize(" . "), "untitled");
+        assert_eq!(sanitize("..jpg"), "..jpg");
+    }
+
+    #[test]
+    fn colliding_names_get_a_suffix() {
+        let keys: Vec<String> = keys_for(vec![
+            item("1", "IMG_0001.JPG", "2019-07-04T10:00:00Z"),
+            item("2", "IMG_0001.JPG", "2019-07-05T10:00:00Z"),
+            item("3", "IMG_0002.JPG", "2019-07-05T10:00:00Z"),
+        ])
+        .into_iter()
+        .map(|(_, key)| key)
+        .collect();
+
+        assert_eq!(keys[0], "2019/07/IMG_0001-6b86b273.JPG");
+        assert_eq!(keys[1], "2019/07/IMG_0001-d4735e3a.JPG");
+        assert_eq!(keys[2], "2019/07/IMG_0002.JPG");
+    }
+
+    #[test]
+    fn videos_are_downloaded_with_dv() {
+        let mut video = item("1", "clip.mp4", "2019-07-04T10:00:00Z");
+        assert_eq!(video.download_url(), "https://lh3.googleusercontent.com/1=d");
+        video.kind = "VIDEO".to_string();
+        assert_eq!(video.download_url(), "https://lh3.googleusercontent.com/1=dv");
+    }
+
+    #[test]
+    fn picker_responses_are_parsed() {
+        let session: PickingSession = serde_json::from_str(
+            r#"{"id": "s1", "pickerUri": "https://photos.google.com/picker/s1",
+                "pollingConfig": {"pollInterval": "3.5s", "timeoutIn": "1800s"},
+                "mediaItemsSet": false}"#,
+        )
+        .unwrap();
+        assert_eq!(session.picker_uri, "https://photos.google.com/picker/s1");
+        assert_eq!(
+            session.polling_config.poll_interval.as_deref().and_then(api_duration),
+            Some(Duration::from_millis(3500))
+        );
+        assert_eq!(api_duration("1800s"), Some(Duration::from_secs(1800)));
+        assert_eq!(api_duration("soon"), None);
+
+        let item: MediaItem = serde_json::from_str(
+            r#"{"id": "m1", "createTime": "2024-05-01T10:00:00Z", "type": "VIDEO",
+                "mediaFile": {"baseUrl": "https://lh3.googleusercontent.com/m1",
+                              "mimeType": "video/mp4", "filename": "clip.mp4"}}"#,
+        )
+        .unwrap();
+        assert_eq!(item.download_url(), "https://lh3.googleusercontent.com/m1=dv");
+        assert_eq!(keys_for(vec![item])[0].1, "2024/05/clip.mp4");
+    }
+}
@@ -0,0 +1,313 @@
+use std::collections::HashSet;
+use std::future::ready;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use byte_unit::Byte;
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
+use google_drive3::api::File;
+use serde::Serialize;
+
+use crate::destination::Destination;
+use crate::drive::Drive;
+use crate::errors::{Error, Result, ResultExt};
+use crate::filter::FileFilter;
+use crate::packing::ArchiveSplit;
+use crate::{unchanged_in, BackupOptions};
+
+/// What `back_up` would do with a file.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Downloaded and copied as it is.
+    Copy,
+    /// A Google document, exported to `mime_type`.
+    Export,
+    /// Packed into a tar archive with other small files.
+    Pack,
+    /// Already in every destination in its current version, by MD5 checksum or, for Google
+    /// documents, modification time.
+    Skip,
+    /// A Google Drive item that cannot be downloaded or exported, like a folder or a form. Copying
+    /// it fails.
+    Unsupported,
+}
+
+#[derive(Serialize, Debug)]
+pub struct PlannedFile {
+    pub id: String,
+    pub name: String,
+    pub action: Action,
+    /// The MIME type of the content that would be written.
+    pub mime_type: String,
+    /// The object the content would end up in, the archive for packed files.
+    pub key: Option<String>,
+    pub storage_class: Option<String>,
+    /// Unknown for Google documents, whose size is only known once they are exported.
+    pub size: Option<u64>,
+}
+
+/// Everything `back_up` would copy, without having downloaded or written anything.
+#[derive(Serialize, Debug)]
+pub struct Plan {
+    pub destinations: Vec<String>,
+    pub files: Vec<PlannedFile>,
+    /// Sum of the known sizes of the files that would be copied, before compression.
+    pub total_bytes: u64,
+}
+
+/// How a plan is printed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlanFormat {
+    Table,
+    Json,
+}
+
+impl FromStr for PlanFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<PlanFormat> {
+        match s {
+            "table" => Ok(PlanFormat::Table),
+            "json" => Ok(PlanFormat::Json),
+            _ => Err(Error::from(format!("Unknown format {s}. Possible values: table, json"))),
+        }
+    }
+}
+
+/// Lists the Drive folder `source` and works out what `back_up` would do with every file in it that
+/// `filter` matches. Nothing is downloaded and nothing is written to the destinations.
+pub async fn plan_back_up(
+    drive: Arc<Drive>,
+    destinations: &[Arc<dyn Destination>],
+    source: &str,
+    filter: &FileFilter,
+    options: &BackupOptions,
+) -> Result<Plan> {
+    let folder_id = drive
+        .resolve_folder(source)
+        .await
+        .chain_err(|| format!("Could not resolve source {source}."))?;
+    let files = drive.list_matching_files_in_folder_id(&folder_id, filter).await?;
+    // Like back_up, only files that are not packed are compared with the destinations.
+    let checks: Vec<_> = files
+        .iter()
+        .filter(|file| options.packing.as_ref().is_none_or(|p| !p.should_pack(file)))
+        .map(|file| async move {
+            let key = file.name.clone().unwrap_or_default();
+            let unchanged = unchanged_in(destinations, file, &key).await;
+            (file.id.clone().unwrap_or_default(), unchanged.into_iter().all(|u| u))
+        })
+        .collect();
+    let unchanged: HashSet<String> = stream::iter(checks)
+        .buffer_unordered(options.concurrency)
+        .filter_map(|(id, unchanged)| ready(unchanged.then_some(id)))
+        .collect()
+        .await;
+    let urls = destinations.iter().map(|d| d.url().to_string()).collect();
+    Ok(Plan::new(urls, files, &unchanged, options, Utc::now()))
+}
+
+impl Plan {
+    /// `unchanged` holds the IDs of the files every destination already has in their current
+    /// version.
+    pub fn new(
+        destinations: Vec<String>,
+        files: Vec<File>,
+        unchanged: &HashSet<String>,
+        options: &BackupOptions,
+        now: DateTime<Utc>,
+    ) -> Plan {
+        let mut split = options.packing.as_ref().map(|packing| ArchiveSplit::new(now, packing));
+        let mut planned = vec![];
+        for file in files {
+            let size = file.size.as_ref().and_then(|s| s.parse::<u64>().ok());
+            let mime_type = file.mime_type.clone().unwrap_or_default();
+            let content_mime_type = Drive::content_mime_type_for(&file).to_string();
+            let action = if options.packing.as_ref().is_some_and(|p| p.should_pack(&file)) {
+                Action::Pack
+            } else if file.id.as_ref().is_some_and(|id| unchanged.contains(id)) {
+                Action::Skip
+            } else if content_mime_type != mime_type {
+                Action::Export
+            } else if mime_type.starts_with("application/vnd.google-apps.") {
+                Action::Unsupported
+            } else {
+                Action::Copy
+            };
+
+            let (key, storage_class) = match action {
+                Action::Pack => {
+                    let (key, _) = split.as_mut().unwrap().place(size.unwrap_or_default());
+                    (Some(key), Some(options.storage_class.clone()))
+                }
+                Action::Copy | Action::Export | Action::Skip => {
+                    let storage_class = options
+                        .storage_class_rules
+                        .storage_class_for(&file, now)
+                        .unwrap_or_else(|| options.storage_class.clone());
+                    (file.name.clone(), Some(storage_class))
+                }
+                Action::Unsupported => (None, None),
+            };
+            planned.push(PlannedFile {
+                id: file.id.clone().unwrap_or_default(),
+                name: file.name.clone().unwrap_or_default(),
+                action,
+                mime_type: content_mime_type,
+                key,
+                storage_class: storage_class.map(|c| c.as_str().to_string()),
+                size,
+            });
+        }
+        let total_bytes = planned
+            .iter()
+            .filter(|f| f.action != Action::Unsupported && f.action != Action::Skip)
+            .filter_map(|f| f.size)
+            .sum();
+        Plan { destinations, files: planned, total_bytes }
+    }
+
+    pub fn print(&self, format: PlanFormat) {
+        match format {
+            PlanFormat::Json => println!("{}", serde_json::to_string_pretty(self).unwrap()),
+            PlanFormat::Table => self.print_table(),
+        }
+    }
+
+    fn print_table(&self) {
+        let size = |size: Option<u64>| match size {
+            Some(size) => Byte::from_bytes(size as u128).get_appropriate_unit(false).to_string(),
+            None => "?".to_string(),
+        };
+        let mut rows = vec![[
+            "ACTION".to_string(),
+            "SIZE".to_string(),
+            "STORAGE CLASS".to_string(),
+            "KEY".to_string(),
+            "FILE".to_string(),
+        ]];
+        for file in &self.files {
+            let action = match file.action {
+                Action::Copy => "copy".to_string(),
+                Action::Export => format!("export as {}", file.mime_type),
+                Action::Pack => "pack".to_string(),
+                Action::Skip => "skip as unchanged".to_string(),
+                Action::Unsupported => format!("unsupported {}", file.mime_type),
+            };
+            rows.push([
+                action,
+                size(file.size),
+                file.storage_class.clone().unwrap_or_default(),
+                file.key.clone().unwrap_or_default(),
+                file.name.clone(),
+            ]);
+        }
+        let widths: Vec<usize> =
+            (0..5).map(|i| rows.iter().map(|row| row[i].len()).max().unwrap()).collect();
+        for row in rows {
+            let line: Vec<String> =
+                row.iter().zip(&widths).map(|(cell, width)| format!("{cell:width$}")).collect();
+            println!("{}", line.join("  ").trim_end());
+        }
+        let count = |action| self.files.iter().filter(|f| f.action == action).count();
+        println!(
+            "Would copy {} files ({} exported, {} packed), {} in total, to {}, and skip {} \
+             unchanged files",
+            self.files.len() - count(Action::Unsupported) - count(Action::Skip),
+            count(Action::Export),
+            count(Action::Pack),
+            size(Some(self.total_bytes)),
+            self.destinations.join(", "),
+            count(Action::Skip)
+        );
+        if count(Action::Unsupported) > 0 {
+            println!("{} files cannot be copied", count(Action::Unsupported));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use aws_sdk_s3::types::StorageClass;
+    use chrono::{TimeZone, Utc};
+    use google_drive3::api::File;
+
+    use crate::compression::CompressionRules;
+    use crate::packing::PackingOptions;
+    use crate::plan::{Action, Plan};
+    use crate::storage_class::StorageClassRules;
+    use crate::BackupOptions;
+
+    fn file(name: &str, mime_type: &str, size: Option<u64>) -> File {
+        File {
+            id: Some(name.to_string()),
+            name: Some(name.to_string()),
+            mime_type: Some(mime_type.to_string()),
+            size: size.map(|s| s.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn files_are_classified_like_back_up_would_copy_them() {
+        let options = BackupOptions {
+            storage_class: StorageClass::DeepArchive,
+            storage_class_rules: StorageClassRules::parse(&["size<1MiB=STANDARD".to_string()])
+                .unwrap(),
+            compression: CompressionRules::default(),
+            encryption_key: None,
+            packing: Some(PackingOptions { max_file_size: 1000, max_archive_size: 3500 }),
+            concurrency: 4,
+        };
+        let files = vec![
+            file("a.txt", "text/plain", Some(600)),
+            file("b.txt", "text/plain", Some(600)),
+            file("c.txt", "text/plain", Some(600)),
+            file("big.mp4", "video/mp4", Some(5 << 20)),
+            file("notes.txt", "text/plain", Some(4000)),
+            file("same.pdf", "application/pdf", Some(2 << 20)),
+            file("Budget", "application/vnd.google-apps.spreadsheet", None),
+            file("Sub", "application/vnd.google-apps.folder", None),
+        ];
+
+        let now = Utc.with_ymd_and_hms(2024, 5, 1, 12, 0, 0).unwrap();
+        let unchanged = HashSet::from(["same.pdf".to_string()]);
+        let plan = Plan::new(vec!["file:///backup".to_string()], files, &unchanged, &options, now);
+
+        let summary: Vec<_> = plan
+            .files
+            .iter()
+            .map(|f| (f.action, f.key.as_deref(), f.storage_class.as_deref()))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (
+                    Action::Pack,
+                    Some("g2s3-archive-20240501T120000Z-00001.tar"),
+                    Some("DEEP_ARCHIVE")
+                ),
+                (
+                    Action::Pack,
+                    Some("g2s3-archive-20240501T120000Z-00001.tar"),
+                    Some("DEEP_ARCHIVE")
+                ),
+                (
+                    Action::Pack,
+                    Some("g2s3-archive-20240501T120000Z-00002.tar"),
+                    Some("DEEP_ARCHIVE")
+                ),
+                (Action::Copy, Some("big.mp4"), Some("DEEP_ARCHIVE")),
+                (Action::Copy, Some("notes.txt"), Some("STANDARD")),
+                (Action::Skip, Some("same.pdf"), Some("DEEP_ARCHIVE")),
+                (Action::Export, Some("Budget"), Some("DEEP_ARCHIVE")),
+                (Action::Unsupported, None, None),
+            ]
+        );
+        assert_eq!(plan.total_bytes, 3 * 600 + (5 << 20) + 4000);
+    }
+}
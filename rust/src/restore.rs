@@ -0,0 +1,245 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use futures::{stream, StreamExt, TryStreamExt};
+use tokio::io::AsyncWriteExt;
+
+use crate::client_side_encryption::{MasterKey, KEY_ID_METADATA_KEY};
+use crate::compression::{Algorithm, COMPRESSION_METADATA_KEY};
+use crate::destination::{safe_path, Destination};
+use crate::errors::{Error, Result, ResultExt};
+use crate::manifest::MANIFEST_METADATA_KEY;
+use crate::packing::{unpack, ARCHIVE_METADATA_KEY};
+use crate::sync_state::SYNC_STATE_METADATA_KEY;
+use crate::transform::{Pipeline, Transform};
+
+pub struct RestoreOptions {
+    /// Only needed for objects that were uploaded with client-side encryption.
+    pub encryption_key: Option<MasterKey>,
+    /// How many objects are restored at the same time.
+    pub concurrency: usize,
+}
+
+/// Downloads all objects in `source` into `target_dir`, reversing any transformation that was
+/// applied during the backup.
+pub async fn restore(
+    source: &dyn Destination,
+    target_dir: &Path,
+    options: &RestoreOptions,
+) -> Result<()> {
+    let names: Vec<String> = source.list().await?.into_iter().map(|o| o.name).collect();
+
+    let results: Vec<Result<()>> = stream::iter(names)
+        .map(|name| async move {
+            // Names come from the backup, which must not be able to write outside of target_dir.
+            let relative = safe_path(Path::new(&name)).ok_or_else(|| {
+                Error::from(format!(
+                    "Refusing to restore {name} outside of {}",
+                    target_dir.display()
+                ))
+            })?;
+            restore_object(source, &name, &target_dir.join(relative), options).await
+        })
+        .buffer_unordered(options.concurrency)
+        .collect()
+        .await;
+
+    let mut result = Ok(());
+    for r in results {
+        if let Err(e) = r {
+            log::error!("Error during restore: {}", e);
+            result = Err(e);
+        }
+    }
+    result
+}
+
+async fn restore_object(
+    source: &dyn Destination,
+    key: &str,
+    target: &Path,
+    options: &RestoreOptions,
+) -> Result<()> {
+    log::info!("Restoring {key} to {}", target.display());
+    let object = source.read(key).await?;
+    let archive = object.metadata.get(ARCHIVE_METADATA_KEY).cloned();
+    if archive.as_deref() == Some("index")
+        || object.metadata.contains_key(MANIFEST_METADATA_KEY)
+        || object.metadata.contains_key(SYNC_STATE_METADATA_KEY)
+    {
+        return Ok(());
+    }
+
+    let mut pipeline = decoder_for(key, &object.metadata, options)?;
+
+    if let Some(parent) = target.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .chain_err(|| format!("Could not create directory {}", parent.display()))?;
+    }
+    let mut file = tokio::fs::File::create(target)
+        .await
+        .chain_err(|| format!("Could not create {}", target.display()))?;
+
+    let mut content = object.content;
+    while let Some(chunk) = content.try_next().await? {
+        file.write_all(&pipeline.update(&chunk).chain_err(|| format!("Could not restore {key}"))?)
+            .await
+            .chain_err(|| format!("Could not write {}", target.display()))?;
+    }
+    file.write_all(&pipeline.finish().chain_err(|| format!("Could not restore {key}"))?)
+        .await
+        .chain_err(|| format!("Could not write {}", target.display()))?;
+    file.flush().await.chain_err(|| format!("Could not write {}", target.display()))?;
+
+    if archive.as_deref() == Some("tar") {
+        let target = target.to_path_buf();
+        tokio::task::spawn_blocking(move || unpack(&target))
+            .await
+            .chain_err(|| format!("Could not unpack {key}"))??;
+    }
+    Ok(())
+}
+
+/// Builds the transforms that reverse what the backup applied to the object `key` with the given
+/// metadata.
+pub(crate) fn decoder_for(
+    key: &str,
+    metadata: &HashMap<String, String>,
+    options: &RestoreOptions,
+) -> Result<Pipeline> {
+    let mut pipeline = Pipeline::default();
+    if let Some(key_id) = metadata.get(KEY_ID_METADATA_KEY) {
+        let encryption_key = options.encryption_key.as_ref().ok_or_else(|| {
+            Error::from(format!("{key} is encrypted with key {key_id}, but no key was given"))
+        })?;
+        pipeline.push(Box::new(encryption_key.decryptor()));
+    }
+    if let Some(algorithm) = metadata.get(COMPRESSION_METADATA_KEY) {
+        pipeline.push(algorithm.parse::<Algorithm>()?.decompressor()?);
+    }
+    Ok(pipeline)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use async_trait::async_trait;
+    use aws_sdk_s3::types::StorageClass;
+    use futures::stream;
+
+    use crate::client_side_encryption::{MasterKey, KEY_ID_METADATA_KEY};
+    use crate::compression::{Algorithm, COMPRESSION_METADATA_KEY};
+    use crate::destination::{Destination, ObjectInfo, ObjectReader, ObjectWriter};
+    use crate::errors::Result;
+    use crate::local::LocalDestination;
+    use crate::manifest::MANIFEST_METADATA_KEY;
+    use crate::restore::{restore, RestoreOptions};
+    use crate::transform::{Pipeline, Transform};
+
+    #[tokio::test]
+    async fn restore_reverses_compression_and_encryption_and_skips_the_manifest() {
+        let dir = std::env::temp_dir().join(format!("g2s3-restore-test-{}", std::process::id()));
+        let backup = LocalDestination::new("file:///backup", dir.join("backup"));
+        let key = MasterKey::new([3; 32]);
+        let data = "some,csv\n".repeat(10_000).into_bytes();
+
+        let mut pipeline = Pipeline::default();
+        pipeline.push(Algorithm::Gzip.compressor().unwrap());
+        pipeline.push(Box::new(key.encryptor().unwrap()));
+        let metadata = HashMap::from([
+            (COMPRESSION_METADATA_KEY.to_string(), "gzip".to_string()),
+            (KEY_ID_METADATA_KEY.to_string(), key.id()),
+        ]);
+        let mut writer =
+            backup.create("reports/data.csv", &StorageClass::Standard, metadata).await.unwrap();
+        writer.write(&pipeline.update(&data).unwrap()).await.unwrap();
+        writer.write(&pipeline.finish().unwrap()).await.unwrap();
+        writer.finish().await.unwrap();
+
+        let metadata = HashMap::from([(MANIFEST_METADATA_KEY.to_string(), "true".to_string())]);
+        let mut writer =
+            backup.create("g2s3-manifest.json", &StorageClass::Standard, metadata).await.unwrap();
+        writer.write(b"{}").await.unwrap();
+        writer.finish().await.unwrap();
+
+        let target = dir.join("restored");
+        restore(&backup, &target, &RestoreOptions { encryption_key: Some(key), concurrency: 4 })
+            .await
+            .unwrap();
+
+        assert_eq!(std::fs::read(target.join("reports/data.csv")).unwrap(), data);
+        assert!(!target.join("g2s3-manifest.json").exists());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// A backup that also lists an object whose name points outside of the restore target.
+    struct Traversing(LocalDestination);
+
+    #[async_trait]
+    impl Destination for Traversing {
+        fn url(&self) -> &str {
+            self.0.url()
+        }
+
+        async fn check_writable(&self) -> Result<()> {
+            self.0.check_writable().await
+        }
+
+        async fn create(
+            &self,
+            name: &str,
+            storage_class: &StorageClass,
+            metadata: HashMap<String, String>,
+        ) -> Result<Box<dyn ObjectWriter>> {
+            self.0.create(name, storage_class, metadata).await
+        }
+
+        async fn metadata(&self, name: &str) -> Result<Option<HashMap<String, String>>> {
+            self.0.metadata(name).await
+        }
+
+        async fn read(&self, name: &str) -> Result<ObjectReader> {
+            match name {
+                "../escape" => Ok(ObjectReader {
+                    metadata: HashMap::new(),
+                    content: Box::pin(stream::iter([Ok(b"gotcha".to_vec())])),
+                }),
+                _ => self.0.read(name).await,
+            }
+        }
+
+        async fn list(&self) -> Result<Vec<ObjectInfo>> {
+            let mut objects = self.0.list().await?;
+            objects.push(ObjectInfo { name: "../escape".to_string(), size: 6 });
+            Ok(objects)
+        }
+
+        async fn delete(&self, name: &str) -> Result<()> {
+            self.0.delete(name).await
+        }
+    }
+
+    #[tokio::test]
+    async fn restore_refuses_names_that_leave_the_target_directory() {
+        let dir = std::env::temp_dir().join(format!("g2s3-traversal-test-{}", std::process::id()));
+        let backup = Traversing(LocalDestination::new("file:///backup", dir.join("backup")));
+        let mut writer =
+            backup.create("a.txt", &StorageClass::Standard, HashMap::new()).await.unwrap();
+        writer.write(b"a").await.unwrap();
+        writer.finish().await.unwrap();
+
+        let target = dir.join("restored");
+        let result =
+            restore(&backup, &target, &RestoreOptions { encryption_key: None, concurrency: 4 })
+                .await;
+
+        assert!(result.unwrap_err().to_string().contains("Refusing to restore ../escape"));
+        assert!(!dir.join("escape").exists());
+        assert_eq!(std::fs::read(target.join("a.txt")).unwrap(), b"a");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
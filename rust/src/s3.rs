@@ -0,0 +1,820 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use async_trait::async_trait;
+use aws_sdk_s3::error::ProvideErrorMetadata;
+use aws_sdk_s3::operation::create_multipart_upload::builders::CreateMultipartUploadFluentBuilder;
+use aws_sdk_s3::operation::get_object::builders::GetObjectFluentBuilder;
+use aws_sdk_s3::operation::head_object::builders::HeadObjectFluentBuilder;
+use aws_sdk_s3::operation::put_object::builders::PutObjectFluentBuilder;
+use aws_sdk_s3::operation::upload_part::builders::UploadPartFluentBuilder;
+use aws_sdk_s3::primitives::ByteStream;
+use aws_sdk_s3::types::{
+    CompletedMultipartUpload, CompletedPart, Object, ObjectLockEnabled, ObjectLockLegalHoldStatus,
+    ObjectLockMode, ServerSideEncryption, StorageClass,
+};
+use aws_sdk_s3::Client;
+use base64::Engine;
+use byte_unit::Byte;
+use futures::stream;
+
+use crate::destination::{Destination, ObjectInfo, ObjectReader, ObjectWriter};
+use crate::errors::{Error, Result, ResultExt};
+use crate::parse_s3_url;
+
+/// Objects are uploaded in parts of this size. Anything smaller is uploaded with a single PutObject.
+const PART_SIZE: usize = 64 * 1024 * 1024;
+
+const PREFLIGHT_OBJECT_NAME: &str = ".g2s3-preflight";
+
+#[derive(Clone, Debug)]
+struct UploadOptions {
+    pub storage_class: StorageClass,
+    pub encryption: Encryption,
+    pub object_lock: ObjectLock,
+}
+
+/// Server-side encryption settings. They must be sent with every request that writes (and for
+/// SSE-C also reads) an object, so all upload paths go through the `apply_to_*` functions.
+#[derive(Clone, Debug, Default)]
+pub struct Encryption {
+    sse: Option<ServerSideEncryption>,
+    kms_key_id: Option<String>,
+    bucket_key_enabled: Option<bool>,
+    customer_key: Option<CustomerKey>,
+}
+
+#[derive(Clone, Debug)]
+struct CustomerKey {
+    key: String,
+    key_md5: String,
+}
+
+impl Encryption {
+    pub fn new(
+        sse: Option<&str>,
+        kms_key_id: Option<&str>,
+        bucket_key_enabled: bool,
+        customer_key: Option<&str>,
+    ) -> Result<Encryption> {
+        let sse = match sse {
+            None => None,
+            Some("AES256") => Some(ServerSideEncryption::Aes256),
+            Some("aws:kms") => Some(ServerSideEncryption::AwsKms),
+            Some("aws:kms:dsse") => Some(ServerSideEncryption::AwsKmsDsse),
+            Some(other) => {
+                return Err(Error::from(format!(
+                    "Unknown server-side encryption {other}. Possible values: AES256, aws:kms, aws:kms:dsse"
+                )))
+            }
+        };
+        let uses_kms =
+            matches!(sse, Some(ServerSideEncryption::AwsKms | ServerSideEncryption::AwsKmsDsse));
+        if kms_key_id.is_some() && !uses_kms {
+            return Err(Error::from(
+                "A KMS key ID requires server-side encryption aws:kms or aws:kms:dsse",
+            ));
+        }
+        if bucket_key_enabled && !uses_kms {
+            return Err(Error::from(
+                "S3 Bucket Keys require server-side encryption aws:kms or aws:kms:dsse",
+            ));
+        }
+        let customer_key = match customer_key {
+            None => None,
+            Some(_) if sse.is_some() => {
+                return Err(Error::from(
+                    "SSE-C cannot be combined with other server-side encryption",
+                ))
+            }
+            Some(key) => {
+                let raw = base64::engine::general_purpose::STANDARD
+                    .decode(key)
+                    .chain_err(|| "SSE-C key must be base64 encoded")?;
+                if raw.len() != 32 {
+                    return Err(Error::from("SSE-C key must be 256 bits long"));
+                }
+                Some(CustomerKey {
+                    key: key.to_string(),
+                    key_md5: base64::engine::general_purpose::STANDARD.encode(md5::compute(&raw).0),
+                })
+            }
+        };
+        Ok(Encryption {
+            sse,
+            kms_key_id: kms_key_id.map(String::from),
+            bucket_key_enabled: bucket_key_enabled.then_some(true),
+            customer_key,
+        })
+    }
+
+    pub fn apply_to_put_object(&self, request: PutObjectFluentBuilder) -> PutObjectFluentBuilder {
+        request
+            .set_server_side_encryption(self.sse.clone())
+            .set_ssekms_key_id(self.kms_key_id.clone())
+            .set_bucket_key_enabled(self.bucket_key_enabled)
+            .set_sse_customer_algorithm(self.customer_key_algorithm())
+            .set_sse_customer_key(self.customer_key.as_ref().map(|k| k.key.clone()))
+            .set_sse_customer_key_md5(self.customer_key.as_ref().map(|k| k.key_md5.clone()))
+    }
+
+    pub fn apply_to_create_multipart_upload(
+        &self,
+        request: CreateMultipartUploadFluentBuilder,
+    ) -> CreateMultipartUploadFluentBuilder {
+        request
+            .set_server_side_encryption(self.sse.clone())
+            .set_ssekms_key_id(self.kms_key_id.clone())
+            .set_bucket_key_enabled(self.bucket_key_enabled)
+            .set_sse_customer_algorithm(self.customer_key_algorithm())
+            .set_sse_customer_key(self.customer_key.as_ref().map(|k| k.key.clone()))
+            .set_sse_customer_key_md5(self.customer_key.as_ref().map(|k| k.key_md5.clone()))
+    }
+
+    pub fn apply_to_upload_part(
+        &self,
+        request: UploadPartFluentBuilder,
+    ) -> UploadPartFluentBuilder {
+        request
+            .set_sse_customer_algorithm(self.customer_key_algorithm())
+            .set_sse_customer_key(self.customer_key.as_ref().map(|k| k.key.clone()))
+            .set_sse_customer_key_md5(self.customer_key.as_ref().map(|k| k.key_md5.clone()))
+    }
+
+    pub fn apply_to_get_object(&self, request: GetObjectFluentBuilder) -> GetObjectFluentBuilder {
+        request
+            .set_sse_customer_algorithm(self.customer_key_algorithm())
+            .set_sse_customer_key(self.customer_key.as_ref().map(|k| k.key.clone()))
+            .set_sse_customer_key_md5(self.customer_key.as_ref().map(|k| k.key_md5.clone()))
+    }
+
+    pub fn apply_to_head_object(
+        &self,
+        request: HeadObjectFluentBuilder,
+    ) -> HeadObjectFluentBuilder {
+        request
+            .set_sse_customer_algorithm(self.customer_key_algorithm())
+            .set_sse_customer_key(self.customer_key.as_ref().map(|k| k.key.clone()))
+            .set_sse_customer_key_md5(self.customer_key.as_ref().map(|k| k.key_md5.clone()))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sse.is_some() || self.customer_key.is_some()
+    }
+
+    fn customer_key_algorithm(&self) -> Option<String> {
+        self.customer_key.as_ref().map(|_| String::from("AES256"))
+    }
+}
+
+/// Object Lock settings applied to every uploaded object. The retention period starts when the
+/// object is uploaded.
+#[derive(Clone, Debug, Default)]
+pub struct ObjectLock {
+    mode: Option<ObjectLockMode>,
+    retain_for: Option<Duration>,
+    legal_hold: bool,
+}
+
+impl ObjectLock {
+    pub fn new(
+        mode: Option<&str>,
+        retain_for: Option<Duration>,
+        legal_hold: bool,
+    ) -> Result<ObjectLock> {
+        let mode = match mode {
+            None => None,
+            Some("GOVERNANCE") => Some(ObjectLockMode::Governance),
+            Some("COMPLIANCE") => Some(ObjectLockMode::Compliance),
+            Some(other) => {
+                return Err(Error::from(format!(
+                    "Unknown Object Lock mode {other}. Possible values: GOVERNANCE, COMPLIANCE"
+                )))
+            }
+        };
+        if mode.is_some() != retain_for.is_some() {
+            return Err(Error::from(
+                "Object Lock mode and retention period must be given together",
+            ));
+        }
+        Ok(ObjectLock { mode, retain_for, legal_hold })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.mode.is_some() || self.legal_hold
+    }
+
+    pub fn apply_to_put_object(&self, request: PutObjectFluentBuilder) -> PutObjectFluentBuilder {
+        request
+            .set_object_lock_mode(self.mode.clone())
+            .set_object_lock_retain_until_date(self.retain_until())
+            .set_object_lock_legal_hold_status(self.legal_hold_status())
+    }
+
+    pub fn apply_to_create_multipart_upload(
+        &self,
+        request: CreateMultipartUploadFluentBuilder,
+    ) -> CreateMultipartUploadFluentBuilder {
+        request
+            .set_object_lock_mode(self.mode.clone())
+            .set_object_lock_retain_until_date(self.retain_until())
+            .set_object_lock_legal_hold_status(self.legal_hold_status())
+    }
+
+    fn retain_until(&self) -> Option<aws_smithy_types::DateTime> {
+        self.retain_for.map(|d| (SystemTime::now() + d).into())
+    }
+
+    fn legal_hold_status(&self) -> Option<ObjectLockLegalHoldStatus> {
+        self.legal_hold.then_some(ObjectLockLegalHoldStatus::On)
+    }
+}
+
+/// Fails unless Object Lock is enabled on `bucket`. Without it, S3 rejects every upload that asks
+/// for retention or a legal hold, so this is checked before any work starts.
+pub async fn check_object_lock_enabled(s3: &Client, bucket: &str) -> Result<()> {
+    let resp = s3
+        .get_object_lock_configuration()
+        .bucket(bucket)
+        .send()
+        .await
+        .map_err(aws_sdk_s3::Error::from);
+    let enabled = match resp {
+        Ok(resp) => resp.object_lock_configuration().and_then(|c| c.object_lock_enabled()).cloned(),
+        Err(e) if e.code() == Some("ObjectLockConfigurationNotFoundError") => None,
+        Err(e) => {
+            return Err(Error::from(format!(
+                "Could not get Object Lock configuration of bucket {bucket}: {e}"
+            )))
+        }
+    };
+    if enabled != Some(ObjectLockEnabled::Enabled) {
+        return Err(Error::from(format!(
+            "Object Lock is not enabled on bucket {bucket}. It can only be enabled when creating \
+             the bucket or with S3 support, see \
+             https://docs.aws.amazon.com/AmazonS3/latest/userguide/object-lock.html"
+        )));
+    }
+    Ok(())
+}
+
+/// Objects under an `s3://bucket-name/some/folder` URL.
+pub struct S3Destination {
+    s3: Client,
+    url: String,
+    bucket: String,
+    prefix: PathBuf,
+    encryption: Encryption,
+    object_lock: ObjectLock,
+}
+
+impl S3Destination {
+    pub fn new(
+        s3: Client,
+        url: &str,
+        encryption: Encryption,
+        object_lock: ObjectLock,
+    ) -> Result<S3Destination> {
+        let (bucket, prefix) =
+            parse_s3_url(url).chain_err(|| format!("Could not parse S3 URL {url}."))?;
+        Ok(S3Destination { s3, url: url.to_string(), bucket, prefix, encryption, object_lock })
+    }
+
+    fn key(&self, name: &str) -> String {
+        self.prefix.join(name).to_str().unwrap().to_string()
+    }
+
+    /// The test object is written with the same server-side encryption as the backup, but without
+    /// Object Lock, so it can be deleted again.
+    async fn check_bucket_writable(&self) -> Result<()> {
+        let bucket = &self.bucket;
+        self.s3
+            .head_bucket()
+            .bucket(bucket)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)
+            .map_err(|e| Error::from(format!("Could not access bucket {bucket}: {e}")))?;
+
+        let key = self.key(PREFLIGHT_OBJECT_NAME);
+        self.encryption
+            .apply_to_put_object(self.s3.put_object())
+            .bucket(bucket)
+            .key(&key)
+            .body(ByteStream::from_static(b"g2s3 preflight check"))
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)
+            .map_err(|e| {
+                Error::from(format!("Could not write test object s3://{bucket}/{key}: {e}"))
+            })?;
+        self.s3
+            .delete_object()
+            .bucket(bucket)
+            .key(&key)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from)
+            .map_err(|e| {
+                Error::from(format!("Could not delete test object s3://{bucket}/{key}: {e}"))
+            })?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Destination for S3Destination {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    async fn check_writable(&self) -> Result<()> {
+        self.check_bucket_writable().await?;
+        if self.object_lock.is_enabled() {
+            check_object_lock_enabled(&self.s3, &self.bucket).await?;
+        }
+        Ok(())
+    }
+
+    async fn create(
+        &self,
+        name: &str,
+        storage_class: &StorageClass,
+        metadata: HashMap<String, String>,
+    ) -> Result<Box<dyn ObjectWriter>> {
+        Ok(Box::new(ObjectUpload::new(
+            self.s3.clone(),
+            &self.bucket,
+            &self.key(name),
+            UploadOptions {
+                storage_class: storage_class.clone(),
+                encryption: self.encryption.clone(),
+                object_lock: self.object_lock.clone(),
+            },
+            metadata,
+        )))
+    }
+
+    async fn metadata(&self, name: &str) -> Result<Option<HashMap<String, String>>> {
+        let key = self.key(name);
+        let resp = self
+            .encryption
+            .apply_to_head_object(self.s3.head_object())
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await
+            .map_err(aws_sdk_s3::Error::from);
+        match resp {
+            Ok(resp) => Ok(Some(resp.metadata().cloned().unwrap_or_default())),
+            Err(aws_sdk_s3::Error::NotFound(_)) => Ok(None),
+            Err(e) => Err(Error::from(format!("Could not get metadata of {key}: {e}"))),
+        }
+    }
+
+    async fn read(&self, name: &str) -> Result<ObjectReader> {
+        let key = self.key(name);
+        let resp = self
+            .encryption
+            .apply_to_get_object(self.s3.get_object())
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await
+            .chain_err(|| format!("Could not download {key}"))?;
+        let content = stream::unfold(resp.body, move |mut body| {
+            let key = key.clone();
+            async move {
+                match body.try_next().await {
+                    Ok(Some(chunk)) => Some((Ok(chunk.to_vec()), body)),
+                    Ok(None) => None,
+                    Err(e) => {
+                        Some((Err(Error::from(format!("Download error for {key}: {e}"))), body))
+                    }
+                }
+            }
+        });
+        Ok(ObjectReader { metadata: resp.metadata.unwrap_or_default(), content: Box::pin(content) })
+    }
+
+    async fn list(&self) -> Result<Vec<ObjectInfo>> {
+        let prefix = match self.key("") {
+            prefix if prefix.is_empty() || prefix.ends_with('/') => prefix,
+            prefix => format!("{prefix}/"),
+        };
+        let mut objects = vec![];
+        let mut continuation_token: Option<String> = None;
+        loop {
+            let resp = self
+                .s3
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(&prefix)
+                .set_continuation_token(continuation_token.take())
+                .send()
+                .await
+                .chain_err(|| format!("Could not list objects in {}", self.url))?;
+            objects.extend(object_infos(&prefix, resp.contents()));
+            if !resp.is_truncated().unwrap_or(false) {
+                break;
+            }
+            continuation_token = resp.next_continuation_token().map(String::from);
+        }
+        Ok(objects)
+    }
+
+    async fn delete(&self, name: &str) -> Result<()> {
+        let key = self.key(name);
+        self.s3
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(&key)
+            .send()
+            .await
+            .chain_err(|| format!("Could not delete {key}"))?;
+        Ok(())
+    }
+}
+
+/// Streams an object to S3. Data is buffered until a full part is available, so objects smaller
+/// than `PART_SIZE` end up as a single PutObject and everything else as a multipart upload.
+struct ObjectUpload {
+    s3: Client,
+    bucket: String,
+    key: String,
+    options: UploadOptions,
+    metadata: HashMap<String, String>,
+    upload_id: Option<String>,
+    parts: Vec<CompletedPart>,
+    buf: Vec<u8>,
+}
+
+impl ObjectUpload {
+    fn new(
+        s3: Client,
+        bucket: &str,
+        key: &str,
+        options: UploadOptions,
+        metadata: HashMap<String, String>,
+    ) -> Self {
+        ObjectUpload {
+            s3,
+            bucket: bucket.to_string(),
+            key: key.to_string(),
+            options,
+            metadata,
+            upload_id: None,
+            parts: vec![],
+            buf: Vec::with_capacity(PART_SIZE),
+        }
+    }
+
+    async fn complete(&mut self) -> Result<usize> {
+        if self.upload_id.is_none() {
+            let buf = std::mem::take(&mut self.buf);
+            let request = self.options.encryption.apply_to_put_object(self.s3.put_object());
+            self.options
+                .object_lock
+                .apply_to_put_object(request)
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .storage_class(self.options.storage_class.clone())
+                .set_metadata(Some(self.metadata.clone()))
+                .body(ByteStream::from(buf))
+                .send()
+                .await
+                .chain_err(|| format!("Could not upload {}", self.key))?;
+            return Ok(1);
+        }
+        if !self.buf.is_empty() {
+            let part = std::mem::take(&mut self.buf);
+            self.upload_part(part).await?;
+        }
+        self.s3
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(self.upload_id.as_ref().unwrap())
+            .multipart_upload(
+                CompletedMultipartUpload::builder().set_parts(Some(self.parts.clone())).build(),
+            )
+            .send()
+            .await
+            .chain_err(|| format!("Could not complete multipart upload for {}", self.key))?;
+        Ok(self.parts.len())
+    }
+
+    async fn upload_part(&mut self, data: Vec<u8>) -> Result<()> {
+        if self.upload_id.is_none() {
+            let request = self
+                .options
+                .encryption
+                .apply_to_create_multipart_upload(self.s3.create_multipart_upload());
+            let create_resp = self
+                .options
+                .object_lock
+                .apply_to_create_multipart_upload(request)
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .storage_class(self.options.storage_class.clone())
+                .set_metadata(Some(self.metadata.clone()))
+                .send()
+                .await
+                .chain_err(|| format!("Could not create multipart upload for {}", self.key))?;
+            self.upload_id = Some(create_resp.upload_id().unwrap().to_string());
+        }
+        let part_number = self.parts.len() as i32 + 1;
+        let resp = self
+            .options
+            .encryption
+            .apply_to_upload_part(self.s3.upload_part())
+            .bucket(&self.bucket)
+            .key(&self.key)
+            .upload_id(self.upload_id.as_ref().unwrap())
+            .part_number(part_number)
+            .body(ByteStream::from(data))
+            .send()
+            .await
+            .chain_err(|| format!("upload_part failed for part {part_number} of {}", self.key))?;
+        self.parts.push(
+            CompletedPart::builder()
+                .part_number(part_number)
+                .e_tag(resp.e_tag().unwrap_or_default())
+                .build(),
+        );
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl ObjectWriter for ObjectUpload {
+    async fn write(&mut self, data: &[u8]) -> Result<()> {
+        self.buf.extend_from_slice(data);
+        if self.buf.len() >= PART_SIZE {
+            let part = std::mem::replace(&mut self.buf, Vec::with_capacity(PART_SIZE));
+            self.upload_part(part).await?;
+        }
+        Ok(())
+    }
+
+    /// Uploads any remaining data and completes the upload. A failed multipart upload is aborted.
+    async fn finish(mut self: Box<Self>) -> Result<usize> {
+        match self.complete().await {
+            Ok(parts) => Ok(parts),
+            Err(e) => {
+                self.abort().await;
+                Err(e)
+            }
+        }
+    }
+
+    async fn abort(self: Box<Self>) {
+        if let Some(upload_id) = self.upload_id.as_ref() {
+            let _ = self
+                .s3
+                .abort_multipart_upload()
+                .bucket(&self.bucket)
+                .key(&self.key)
+                .upload_id(upload_id)
+                .send()
+                .await;
+        }
+    }
+}
+
+pub struct StaleUpload {
+    pub key: String,
+    pub upload_id: String,
+    pub initiated: SystemTime,
+    pub parts: usize,
+    pub bytes: u64,
+}
+
+pub struct StaleUploadsReport {
+    pub uploads: Vec<StaleUpload>,
+    pub dry_run: bool,
+}
+
+impl StaleUploadsReport {
+    pub fn total_bytes(&self) -> u64 {
+        self.uploads.iter().map(|u| u.bytes).sum()
+    }
+
+    pub fn print(&self) {
+        for upload in &self.uploads {
+            println!(
+                "{} {} (initiated {}, {} parts, {})",
+                if self.dry_run { "Would abort" } else { "Aborted" },
+                upload.key,
+                chrono::DateTime::<chrono::Utc>::from(upload.initiated)
+                    .format("%Y-%m-%dT%H:%M:%SZ"),
+                upload.parts,
+                Byte::from_bytes(upload.bytes as u128).get_appropriate_unit(false)
+            );
+        }
+        println!(
+            "{} {} in {} incomplete multipart uploads",
+            if self.dry_run { "Would reclaim" } else { "Reclaimed" },
+            Byte::from_bytes(self.total_bytes() as u128).get_appropriate_unit(false),
+            self.uploads.len()
+        );
+    }
+}
+
+/// Lists incomplete multipart uploads under `destination` that were initiated more than
+/// `older_than` ago and aborts them, unless `dry_run` is set.
+pub async fn abort_stale_multipart_uploads(
+    s3: &Client,
+    destination: &str,
+    older_than: Duration,
+    dry_run: bool,
+) -> Result<StaleUploadsReport> {
+    let (bucket_name, folder_name) =
+        parse_s3_url(destination).chain_err(|| format!("Could not parse S3 URL {destination}."))?;
+    let prefix = folder_name.to_str().unwrap().to_string();
+    let now = SystemTime::now();
+
+    let mut uploads = vec![];
+    let mut key_marker: Option<String> = None;
+    let mut upload_id_marker: Option<String> = None;
+    loop {
+        let resp = s3
+            .list_multipart_uploads()
+            .bucket(&bucket_name)
+            .prefix(&prefix)
+            .set_key_marker(key_marker.take())
+            .set_upload_id_marker(upload_id_marker.take())
+            .send()
+            .await
+            .chain_err(|| format!("Could not list multipart uploads in {destination}"))?;
+
+        for upload in resp.uploads() {
+            // An upload of unknown age could still be in progress, so it is left alone.
+            let Some(initiated) = upload.initiated() else {
+                log::warn!(
+                    "Skipping multipart upload {} of {} without a start time",
+                    upload.upload_id().unwrap_or_default(),
+                    upload.key().unwrap_or_default()
+                );
+                continue;
+            };
+            let initiated = UNIX_EPOCH + Duration::from_secs(initiated.secs().max(0) as u64);
+            if !is_stale(initiated, now, older_than) {
+                continue;
+            }
+            let key = upload.key().unwrap_or_default().to_string();
+            let upload_id = upload.upload_id().unwrap_or_default().to_string();
+            let (parts, bytes) = uploaded_parts(s3, &bucket_name, &key, &upload_id).await?;
+            uploads.push(StaleUpload { key, upload_id, initiated, parts, bytes });
+        }
+
+        if !resp.is_truncated().unwrap_or(false) {
+            break;
+        }
+        key_marker = resp.next_key_marker().map(String::from);
+        upload_id_marker = resp.next_upload_id_marker().map(String::from);
+    }
+
+    if !dry_run {
+        for upload in &uploads {
+            s3.abort_multipart_upload()
+                .bucket(&bucket_name)
+                .key(&upload.key)
+                .upload_id(&upload.upload_id)
+                .send()
+                .await
+                .chain_err(|| format!("Could not abort multipart upload for {}", upload.key))?;
+            log::info!("Aborted multipart upload {} for {}", upload.upload_id, upload.key);
+        }
+    }
+
+    Ok(StaleUploadsReport { uploads, dry_run })
+}
+
+async fn uploaded_parts(
+    s3: &Client,
+    bucket: &str,
+    key: &str,
+    upload_id: &str,
+) -> Result<(usize, u64)> {
+    let (mut parts, mut bytes) = (0, 0);
+    let mut part_number_marker: Option<String> = None;
+    loop {
+        let resp = s3
+            .list_parts()
+            .bucket(bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .set_part_number_marker(part_number_marker.take())
+            .send()
+            .await
+            .chain_err(|| format!("Could not list parts of multipart upload for {key}"))?;
+        parts += resp.parts().len();
+        bytes += resp.parts().iter().map(|p| p.size().unwrap_or(0).max(0) as u64).sum::<u64>();
+        if !resp.is_truncated().unwrap_or(false) {
+            break;
+        }
+        part_number_marker = resp.next_part_number_marker().map(String::from);
+    }
+    Ok((parts, bytes))
+}
+
+/// The listed objects under `prefix`, named relative to it. The prefix is removed exactly once, so
+/// `a/a/x` under `a/` is `a/x`.
+fn object_infos(prefix: &str, objects: &[Object]) -> Vec<ObjectInfo> {
+    objects
+        .iter()
+        .filter_map(|o| {
+            Some(ObjectInfo {
+                name: o.key()?.strip_prefix(prefix)?.to_string(),
+                size: o.size().unwrap_or_default() as u64,
+            })
+        })
+        .collect()
+}
+
+fn is_stale(initiated: SystemTime, now: SystemTime, older_than: Duration) -> bool {
+    now.duration_since(initiated).map(|age| age > older_than).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use aws_sdk_s3::types::Object;
+
+    use crate::destination::ObjectInfo;
+    use crate::s3::{is_stale, object_infos, Encryption, ObjectLock};
+
+    #[test]
+    fn encryption_accepts_valid_combinations() {
+        assert!(Encryption::new(None, None, false, None).is_ok());
+        assert!(Encryption::new(Some("AES256"), None, false, None).is_ok());
+        assert!(Encryption::new(Some("aws:kms"), Some("alias/backups"), true, None).is_ok());
+
+        let encryption = Encryption::new(
+            None,
+            None,
+            false,
+            Some("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY="),
+        )
+        .unwrap();
+        assert_eq!(encryption.customer_key.unwrap().key_md5, "hRasmdxgYDKV3nvbahU1MA==");
+    }
+
+    #[test]
+    fn encryption_rejects_invalid_combinations() {
+        assert!(Encryption::new(Some("aes256"), None, false, None).is_err());
+        assert!(Encryption::new(Some("AES256"), Some("alias/backups"), false, None).is_err());
+        assert!(Encryption::new(None, None, true, None).is_err());
+        assert!(Encryption::new(None, None, false, Some("c2hvcnQ=")).is_err());
+        assert!(Encryption::new(
+            Some("AES256"),
+            None,
+            false,
+            Some("MDEyMzQ1Njc4OWFiY2RlZjAxMjM0NTY3ODlhYmNkZWY=")
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn object_lock_requires_mode_and_retention_together() {
+        let year = Some(Duration::from_secs(365 * 86_400));
+
+        assert!(!ObjectLock::new(None, None, false).unwrap().is_enabled());
+        assert!(ObjectLock::new(None, None, true).unwrap().is_enabled());
+        assert!(ObjectLock::new(Some("COMPLIANCE"), year, false).unwrap().is_enabled());
+        assert!(ObjectLock::new(Some("GOVERNANCE"), year, true).unwrap().is_enabled());
+
+        assert!(ObjectLock::new(Some("COMPLIANCE"), None, false).is_err());
+        assert!(ObjectLock::new(None, year, false).is_err());
+        assert!(ObjectLock::new(Some("compliance"), year, false).is_err());
+    }
+
+    #[test]
+    fn is_stale_only_matches_uploads_older_than_the_given_age() {
+        let now = UNIX_EPOCH + Duration::from_secs(100_000);
+        let day = Duration::from_secs(86_400);
+
+        assert!(is_stale(UNIX_EPOCH, now, day));
+        assert!(!is_stale(now - Duration::from_secs(3_600), now, day));
+        assert!(!is_stale(now + Duration::from_secs(3_600), now, day));
+    }
+
+    #[test]
+    fn listed_keys_lose_the_prefix_exactly_once() {
+        let objects: Vec<Object> = ["backup/a.txt", "backup/backup/b.txt", "other/c.txt"]
+            .into_iter()
+            .map(|key| Object::builder().key(key).size(1).build())
+            .collect();
+
+        assert_eq!(
+            object_infos("backup/", &objects),
+            vec![
+                ObjectInfo { name: "a.txt".to_string(), size: 1 },
+                ObjectInfo { name: "backup/b.txt".to_string(), size: 1 },
+            ]
+        );
+    }
+}
@@ -0,0 +1,170 @@
+use std::time::Duration;
+
+use aws_sdk_s3::types::StorageClass;
+use chrono::{DateTime, Utc};
+use glob::Pattern;
+use google_drive3::api::File;
+
+use crate::errors::{Error, Result, ResultExt};
+
+/// Picks the storage class per file. The first rule whose conditions all match wins. Files that
+/// match no rule get the default storage class.
+#[derive(Clone, Debug, Default)]
+pub struct StorageClassRules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Clone, Debug)]
+struct Rule {
+    conditions: Vec<Condition>,
+    storage_class: StorageClass,
+}
+
+#[derive(Clone, Debug)]
+enum Condition {
+    SizeBelow(u64),
+    SizeAtLeast(u64),
+    MimeType(Pattern),
+    OlderThan(Duration),
+    NewerThan(Duration),
+}
+
+impl StorageClassRules {
+    /// Parses rules in the format `<condition>[,<condition>...]=<storage class>`. Conditions are
+    /// `size<128KiB`, `size>=1GiB`, `mime=image/*`, `age>365d` and `age<30d`, where age is
+    /// measured from the file's modification time in Drive.
+    pub fn parse(rules: &[String]) -> Result<StorageClassRules> {
+        let mut parsed = vec![];
+        for rule in rules {
+            let (conditions, storage_class) = rule.rsplit_once('=').ok_or_else(|| {
+                Error::from(format!(
+                    "Storage class rule {rule} must have the format <conditions>=<storage class>"
+                ))
+            })?;
+            parsed.push(Rule {
+                conditions: conditions
+                    .split(',')
+                    .map(parse_condition)
+                    .collect::<Result<_>>()
+                    .chain_err(|| format!("Invalid storage class rule {rule}"))?,
+                storage_class: parse_storage_class(storage_class)?,
+            });
+        }
+        Ok(StorageClassRules { rules: parsed })
+    }
+
+    pub fn storage_class_for(&self, file: &File, now: DateTime<Utc>) -> Option<StorageClass> {
+        let size = file.size.as_ref().and_then(|s| s.parse::<u64>().ok());
+        let age = file
+            .modified_time
+            .as_ref()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+            .and_then(|t| (now - t.with_timezone(&Utc)).to_std().ok());
+        let mime_type = file.mime_type.as_deref().unwrap_or_default();
+
+        self.rules
+            .iter()
+            .find(|rule| {
+                rule.conditions.iter().all(|condition| match condition {
+                    Condition::SizeBelow(limit) => size.is_some_and(|s| s < *limit),
+                    Condition::SizeAtLeast(limit) => size.is_some_and(|s| s >= *limit),
+                    Condition::MimeType(pattern) => pattern.matches(mime_type),
+                    Condition::OlderThan(limit) => age.is_some_and(|a| a > *limit),
+                    Condition::NewerThan(limit) => age.is_some_and(|a| a < *limit),
+                })
+            })
+            .map(|rule| rule.storage_class.clone())
+    }
+}
+
+fn parse_condition(condition: &str) -> Result<Condition> {
+    let size = |s: &str| {
+        byte_unit::Byte::from_str(s)
+            .map(|b| b.get_bytes() as u64)
+            .map_err(|e| Error::from(format!("Invalid size {s}: {e}")))
+    };
+    let age = |s: &str| {
+        humantime::parse_duration(s).map_err(|e| Error::from(format!("Invalid age {s}: {e}")))
+    };
+    if let Some(s) = condition.strip_prefix("size<") {
+        Ok(Condition::SizeBelow(size(s)?))
+    } else if let Some(s) = condition.strip_prefix("size>=") {
+        Ok(Condition::SizeAtLeast(size(s)?))
+    } else if let Some(s) = condition.strip_prefix("mime=") {
+        Ok(Condition::MimeType(Pattern::new(s).chain_err(|| format!("Invalid MIME type {s}"))?))
+    } else if let Some(s) = condition.strip_prefix("age>") {
+        Ok(Condition::OlderThan(age(s)?))
+    } else if let Some(s) = condition.strip_prefix("age<") {
+        Ok(Condition::NewerThan(age(s)?))
+    } else {
+        Err(Error::from(format!(
+            "Unknown condition {condition}. Possible conditions: size<, size>=, mime=, age>, age<"
+        )))
+    }
+}
+
+pub fn parse_storage_class(storage_class: &str) -> Result<StorageClass> {
+    if !StorageClass::values().contains(&storage_class) {
+        return Err(Error::from(format!(
+            "Unknown storage class {storage_class}. Possible values: {}",
+            StorageClass::values().join(", ")
+        )));
+    }
+    Ok(StorageClass::from(storage_class))
+}
+
+#[cfg(test)]
+mod tests {
+    use aws_sdk_s3::types::StorageClass;
+    use chrono::{TimeZone, Utc};
+    use google_drive3::api::File;
+
+    use crate::storage_class::StorageClassRules;
+
+    fn file(size: &str, mime_type: &str, modified_time: &str) -> File {
+        File {
+            size: Some(size.to_string()),
+            mime_type: Some(mime_type.to_string()),
+            modified_time: Some(modified_time.to_string()),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn first_matching_rule_determines_storage_class() {
+        let rules = StorageClassRules::parse(&[
+            "size<128KiB=STANDARD".to_string(),
+            "mime=image/*,age>365d=DEEP_ARCHIVE".to_string(),
+            "age<30d=STANDARD_IA".to_string(),
+        ])
+        .unwrap();
+        let now = Utc.with_ymd_and_hms(2023, 6, 1, 0, 0, 0).unwrap();
+
+        assert_eq!(
+            rules.storage_class_for(&file("1000", "image/jpeg", "2020-01-01T00:00:00Z"), now),
+            Some(StorageClass::Standard)
+        );
+        assert_eq!(
+            rules.storage_class_for(&file("5000000", "image/jpeg", "2020-01-01T00:00:00Z"), now),
+            Some(StorageClass::DeepArchive)
+        );
+        assert_eq!(
+            rules.storage_class_for(&file("5000000", "video/mp4", "2023-05-20T10:00:00.000Z"), now),
+            Some(StorageClass::StandardIa)
+        );
+        assert_eq!(
+            rules.storage_class_for(&file("5000000", "video/mp4", "2022-01-01T00:00:00Z"), now),
+            None
+        );
+        assert_eq!(rules.storage_class_for(&File { size: None, ..Default::default() }, now), None);
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(StorageClassRules::parse(&["size<128KiB".to_string()]).is_err());
+        assert!(StorageClassRules::parse(&["size<128KiB=STANDART".to_string()]).is_err());
+        assert!(StorageClassRules::parse(&["size<lots=STANDARD".to_string()]).is_err());
+        assert!(StorageClassRules::parse(&["age>forever=GLACIER".to_string()]).is_err());
+        assert!(StorageClassRules::parse(&["owner=me=GLACIER".to_string()]).is_err());
+    }
+}
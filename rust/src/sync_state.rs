@@ -0,0 +1,76 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use aws_sdk_s3::types::StorageClass;
+use futures::TryStreamExt;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::destination::Destination;
+use crate::errors::{Result, ResultExt};
+
+/// Object metadata key marking the state incremental backups continue from, so `restore` can skip
+/// it.
+pub const SYNC_STATE_METADATA_KEY: &str = "g2s3-sync-state";
+
+/// Reads the state object `name` from every destination, with `None` for destinations that do not
+/// have one yet. The state is small and holds no content, so it is stored as plain JSON.
+pub async fn read_states<T: DeserializeOwned>(
+    destinations: &[Arc<dyn Destination>],
+    name: &str,
+) -> Result<Vec<Option<T>>> {
+    let mut states = vec![];
+    for destination in destinations {
+        if destination.metadata(name).await?.is_none() {
+            states.push(None);
+            continue;
+        }
+        let object = destination.read(name).await?;
+        let content: Vec<Vec<u8>> = object.content.try_collect().await?;
+        let state = serde_json::from_slice(&content.concat())
+            .chain_err(|| format!("Invalid sync state {name} in {}", destination.url()))?;
+        states.push(Some(state));
+    }
+    Ok(states)
+}
+
+pub async fn write_state<T: Serialize>(
+    destination: &dyn Destination,
+    name: &str,
+    state: &T,
+) -> Result<()> {
+    let metadata = HashMap::from([(SYNC_STATE_METADATA_KEY.to_string(), "true".to_string())]);
+    let mut upload = destination.create(name, &StorageClass::Standard, metadata).await?;
+    if let Err(e) = upload.write(&serde_json::to_vec_pretty(state).unwrap()).await {
+        upload.abort().await;
+        return Err(e).chain_err(|| format!("Could not upload {name}"));
+    }
+    upload.finish().await.chain_err(|| format!("Could not upload {name}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::destination::Destination;
+    use crate::local::LocalDestination;
+    use crate::sync_state::{read_states, write_state};
+
+    #[tokio::test]
+    async fn states_are_read_back_per_destination() {
+        let dir = std::env::temp_dir().join(format!("g2s3-sync-state-test-{}", std::process::id()));
+        let destinations: Vec<Arc<dyn Destination>> = vec![
+            Arc::new(LocalDestination::new("file:///a", dir.join("a"))),
+            Arc::new(LocalDestination::new("file:///b", dir.join("b"))),
+        ];
+
+        write_state(destinations[0].as_ref(), "state.json", &"1234".to_string()).await.unwrap();
+
+        assert_eq!(
+            read_states::<String>(&destinations, "state.json").await.unwrap(),
+            vec![Some("1234".to_string()), None]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
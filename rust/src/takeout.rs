@@ -0,0 +1,555 @@
+use std::collections::BTreeSet;
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use flate2::read::MultiGzDecoder;
+use futures::{future, stream, StreamExt};
+use google_drive3::api::File;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+use tokio_util::io::{StreamReader, SyncIoBridge};
+
+use crate::destination::{safe_path, Destination};
+use crate::drive::Drive;
+use crate::errors::{Error, Result, ResultExt};
+use crate::manifest::ManifestEntry;
+use crate::sync_state::{read_states, write_state};
+use crate::{
+    combine_results, copy_file, drive_content, preflight, upload_manifests, BackupOptions, Content,
+};
+
+/// The IDs of the archives copied before, stored in every destination.
+pub const STATE_NAME: &str = "g2s3-takeout-state.json";
+
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+#[derive(Clone, Debug, Default)]
+pub struct TakeoutOptions {
+    /// Store every file in the archives as its own object instead of the archives themselves.
+    pub unpack: bool,
+    /// Move archives to the Drive trash once they, the manifest and the state are in every
+    /// destination.
+    pub trash: bool,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct TakeoutState {
+    pub archives: BTreeSet<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum ArchiveFormat {
+    Zip,
+    Tgz,
+}
+
+/// A file in an archive.
+#[derive(Debug, PartialEq)]
+struct EntryHeader {
+    path: String,
+    size: u64,
+    modified_time: Option<DateTime<Utc>>,
+}
+
+struct ArchiveEntry {
+    header: EntryHeader,
+    content: mpsc::Receiver<Result<Vec<u8>>>,
+}
+
+/// Copies the Takeout exports (`takeout-*.zip` and `takeout-*.tgz`) in the Drive folder `source`
+/// that are not in every destination yet to `takeout/<archive name>`, or with `unpack`, every file
+/// in them to `takeout/<export name>/<path in archive>`. Archives are verified against their Drive
+/// MD5 checksum while they are copied.
+pub async fn back_up_takeout(
+    drive: Arc<Drive>,
+    destinations: Vec<Arc<dyn Destination>>,
+    source: &str,
+    takeout: &TakeoutOptions,
+    options: &BackupOptions,
+) -> Result<()> {
+    let folder_id = preflight(&drive, &destinations, source).await?;
+    let mut states: Vec<TakeoutState> = read_states(&destinations, STATE_NAME)
+        .await?
+        .into_iter()
+        .map(Option::unwrap_or_default)
+        .collect();
+
+    let archives: Vec<File> = drive
+        .list_files_in_folder_id(&folder_id)
+        .await?
+        .into_iter()
+        .filter(|f| archive_format(f.name.as_deref().unwrap_or_default()).is_some())
+        .filter(|f| {
+            let id = f.id.clone().unwrap_or_default();
+            states.iter().any(|s| !s.archives.contains(&id))
+        })
+        .collect();
+    log::info!("Found {} new Takeout archives in {source}", archives.len());
+
+    let (tx, rx) = mpsc::unbounded_channel();
+    let mut copied = vec![];
+    for archive in archives {
+        let results = match takeout.unpack {
+            true => unpack(&drive, &destinations, &archive, options).await,
+            false => {
+                let name = archive.name.clone().unwrap_or_default();
+                let content =
+                    async { Ok(verified(drive_content(&drive, &archive).await?, &archive)) };
+                let mime_type = archive.mime_type.clone().unwrap_or_default();
+                copy_file(
+                    &destinations,
+                    &archive,
+                    format!("takeout/{name}"),
+                    &mime_type,
+                    content,
+                    options,
+                )
+                .await
+                .into_iter()
+                .map(|r| r.map(|entry| vec![entry]))
+                .collect()
+            }
+        };
+        let id = archive.id.clone().unwrap_or_default();
+        if results.iter().all(Result::is_ok) && archive.md5_checksum.is_some() {
+            copied.push(archive);
+        }
+        for ((i, result), state) in results.into_iter().enumerate().zip(states.iter_mut()) {
+            match result {
+                Ok(entries) => {
+                    state.archives.insert(id.clone());
+                    for entry in entries {
+                        tx.send((i, Ok(entry))).unwrap();
+                    }
+                }
+                Err(e) => tx.send((i, Err(e))).unwrap(),
+            }
+        }
+    }
+
+    let mut results = upload_manifests(&destinations, rx, options).await;
+    for ((destination, result), state) in destinations.iter().zip(results.iter_mut()).zip(&states) {
+        let written = write_state(destination.as_ref(), STATE_NAME, state).await;
+        *result = std::mem::replace(result, Ok(())).and(written);
+    }
+    // Only once the manifest and state are in every destination, too, as the archives are the
+    // only other copy.
+    if takeout.trash && results.iter().all(Result::is_ok) {
+        for archive in copied {
+            let name = archive.name.as_deref().unwrap_or_default();
+            log::info!("Moving {name} to the trash");
+            if let Err(e) = drive.trash_file(archive.id.as_deref().unwrap_or_default()).await {
+                log::error!("Could not move {name} to the trash: {e}");
+            }
+        }
+    }
+    combine_results(&destinations, results)
+}
+
+/// Fails the content with a checksum error at the end unless it matches the Drive MD5 checksum of
+/// `file`.
+fn verified(content: Content, file: &File) -> Content {
+    let name = file.name.clone().unwrap_or_default();
+    let expected = file.md5_checksum.clone();
+    let mut context = md5::Context::new();
+    Box::pin(content.map(Some).chain(stream::iter([None])).filter_map(move |chunk| {
+        let item = match chunk {
+            Some(Ok(data)) => {
+                context.consume(&data);
+                Some(Ok(data))
+            }
+            Some(Err(e)) => Some(Err(e)),
+            None => {
+                let actual =
+                    format!("{:x}", std::mem::replace(&mut context, md5::Context::new()).compute());
+                match expected.as_ref() {
+                    Some(expected) if *expected != actual => Some(Err(Error::from(format!(
+                        "Checksum mismatch for {name}: expected {expected}, got {actual}"
+                    )))),
+                    _ => None,
+                }
+            }
+        };
+        future::ready(item)
+    }))
+}
+
+/// Copies every file in `archive` to all destinations and returns their manifest entries, or the
+/// first error, per destination.
+async fn unpack(
+    drive: &Drive,
+    destinations: &[Arc<dyn Destination>],
+    archive: &File,
+    options: &BackupOptions,
+) -> Vec<Result<Vec<ManifestEntry>>> {
+    let name = archive.name.clone().unwrap_or_default();
+    let mut results: Vec<Result<Vec<ManifestEntry>>> =
+        destinations.iter().map(|_| Ok(vec![])).collect();
+    if let Err(e) = unpack_into(drive, destinations, archive, options, &mut results).await {
+        for result in results.iter_mut().filter(|r| r.is_ok()) {
+            *result = Err(Error::from(format!("Could not unpack {name}: {e}")));
+        }
+    }
+    results
+}
+
+async fn unpack_into(
+    drive: &Drive,
+    destinations: &[Arc<dyn Destination>],
+    archive: &File,
+    options: &BackupOptions,
+    results: &mut [Result<Vec<ManifestEntry>>],
+) -> Result<()> {
+    let name = archive.name.clone().unwrap_or_default();
+    let format = archive_format(&name).unwrap();
+    let export = export_name(&name);
+    let id = archive.id.clone().unwrap_or_default();
+
+    let content = verified(drive_content(drive, archive).await?, archive);
+    let source = match format {
+        // Zip archives can only be read from the end, so they are downloaded before unpacking.
+        ArchiveFormat::Zip => ArchiveSource::Zip(download(archive, content).await?),
+        // Tar archives are read from the start, so they are unpacked while they are downloaded.
+        ArchiveFormat::Tgz => {
+            let content = content
+                .map(|chunk| chunk.map(Cursor::new).map_err(|e| io::Error::other(e.to_string())));
+            ArchiveSource::Tgz(Box::new(SyncIoBridge::new(StreamReader::new(content))))
+        }
+    };
+
+    let (entries_tx, mut entries_rx) = mpsc::channel(1);
+    let reader = tokio::task::spawn_blocking(move || {
+        let result =
+            read_entries(source, &mut |header, reader| send_entry(&entries_tx, header, reader));
+        if let Err(e) = result {
+            let _ = entries_tx.blocking_send(Err(e));
+        }
+    });
+    while let Some(entry) = entries_rx.recv().await {
+        let ArchiveEntry { header, content } = entry?;
+        let file = File {
+            id: Some(format!("{id}/{}", header.path)),
+            name: Some(header.path.clone()),
+            mime_type: Some(mime_type_for(&header.path).to_string()),
+            size: Some(header.size.to_string()),
+            modified_time: header.modified_time.map(|t| t.to_rfc3339()),
+            ..Default::default()
+        };
+        let content: Content = Box::pin(stream::unfold(content, |mut content| async move {
+            content.recv().await.map(|chunk| (chunk, content))
+        }));
+        let key = format!("takeout/{export}/{}", header.path);
+        let mime_type = file.mime_type.clone().unwrap_or_default();
+        let copied =
+            copy_file(destinations, &file, key, &mime_type, async { Ok(content) }, options).await;
+        for (result, copied) in results.iter_mut().zip(copied) {
+            match (result, copied) {
+                (Ok(entries), Ok(entry)) => entries.push(entry),
+                (result @ Ok(_), Err(e)) => *result = Err(e),
+                (Err(_), _) => {}
+            }
+        }
+    }
+    reader.await.chain_err(|| format!("Could not unpack {name}"))?;
+    Ok(())
+}
+
+/// Downloads `archive` to a temporary file.
+async fn download(archive: &File, mut content: Content) -> Result<TempFile> {
+    let name = archive.name.clone().unwrap_or_default();
+    let id = archive.id.clone().unwrap_or_default();
+    let download = TempFile(std::env::temp_dir().join(format!("g2s3-takeout-{id}")));
+    log::info!("Downloading {name} to {}", download.0.display());
+    let mut file = tokio::fs::File::create(&download.0)
+        .await
+        .chain_err(|| format!("Could not create {}", download.0.display()))?;
+    let write_error = |e: io::Error| match e.kind() {
+        io::ErrorKind::StorageFull => Error::from(format!(
+            "Not enough space in {} to unpack {name}, which needs {} bytes. Set TMPDIR to a \
+             directory with more space, or back up the archive without unpacking it",
+            std::env::temp_dir().display(),
+            archive.size.as_deref().unwrap_or("?")
+        )),
+        _ => Error::with_chain(e, format!("Could not write {}", download.0.display())),
+    };
+    while let Some(chunk) = content.next().await {
+        file.write_all(&chunk?).await.map_err(write_error)?;
+    }
+    file.flush().await.map_err(write_error)?;
+    Ok(download)
+}
+
+/// Removes the file when dropped.
+struct TempFile(PathBuf);
+
+/// Where the entries of an archive are read from.
+enum ArchiveSource {
+    Zip(TempFile),
+    Tgz(Box<dyn Read + Send>),
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Hands an entry and its content over to the upload. If the upload gives up on the entry, the
+/// rest of its content is skipped.
+fn send_entry(
+    tx: &mpsc::Sender<Result<ArchiveEntry>>,
+    header: EntryHeader,
+    reader: &mut dyn Read,
+) -> Result<()> {
+    let path = header.path.clone();
+    let (chunk_tx, chunk_rx) = mpsc::channel(4);
+    if tx.blocking_send(Ok(ArchiveEntry { header, content: chunk_rx })).is_err() {
+        return Err(Error::from("Unpacking was cancelled"));
+    }
+    let mut buffer = vec![0; CHUNK_SIZE];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => {
+                if chunk_tx.blocking_send(Ok(buffer[..n].to_vec())).is_err() {
+                    return Ok(());
+                }
+            }
+            Err(e) => {
+                let message = format!("Could not read {path}: {e}");
+                let _ = chunk_tx.blocking_send(Err(Error::from(message.clone())));
+                return Err(Error::from(message));
+            }
+        }
+    }
+}
+
+/// Calls `visit` with every regular file in the archive. Entries whose path would leave the
+/// export, like `../x`, are skipped.
+fn read_entries(
+    source: ArchiveSource,
+    visit: &mut dyn FnMut(EntryHeader, &mut dyn Read) -> Result<()>,
+) -> Result<()> {
+    match source {
+        ArchiveSource::Zip(download) => {
+            let path = &download.0;
+            let file = std::fs::File::open(path)
+                .chain_err(|| format!("Could not open {}", path.display()))?;
+            let mut archive =
+                zip::ZipArchive::new(BufReader::new(file)).chain_err(|| "Invalid zip archive")?;
+            for i in 0..archive.len() {
+                let mut entry = archive.by_index(i).chain_err(|| "Invalid zip archive")?;
+                if entry.is_dir() {
+                    continue;
+                }
+                let Some(entry_path) = safe_path(Path::new(entry.name())) else {
+                    log::warn!("Skipping {} in archive", entry.name());
+                    continue;
+                };
+                let t = entry.last_modified();
+                let modified_time =
+                    NaiveDate::from_ymd_opt(t.year().into(), t.month().into(), t.day().into())
+                        .and_then(|d| {
+                            d.and_hms_opt(t.hour().into(), t.minute().into(), t.second().into())
+                        })
+                        .map(|t| t.and_utc());
+                let header = EntryHeader { path: entry_path, size: entry.size(), modified_time };
+                visit(header, &mut entry)?;
+            }
+        }
+        ArchiveSource::Tgz(reader) => {
+            let mut archive = tar::Archive::new(MultiGzDecoder::new(BufReader::new(reader)));
+            for entry in archive.entries().chain_err(|| "Invalid tar archive")? {
+                let mut entry = entry.chain_err(|| "Invalid tar archive")?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let raw_path = entry.path().chain_err(|| "Invalid tar archive")?.into_owned();
+                let Some(entry_path) = safe_path(&raw_path) else {
+                    log::warn!("Skipping {} in archive", raw_path.display());
+                    continue;
+                };
+                let modified_time =
+                    entry.header().mtime().ok().and_then(|t| DateTime::from_timestamp(t as i64, 0));
+                let header = EntryHeader { path: entry_path, size: entry.size(), modified_time };
+                visit(header, &mut entry)?;
+            }
+            // The tar archive ends before the download does, whose end is where its checksum is
+            // verified.
+            io::copy(&mut archive.into_inner(), &mut io::sink())
+                .chain_err(|| "Could not read the end of the archive")?;
+        }
+    }
+    Ok(())
+}
+
+fn archive_format(name: &str) -> Option<ArchiveFormat> {
+    let name = name.to_lowercase();
+    if !name.starts_with("takeout-") {
+        return None;
+    }
+    if name.ends_with(".zip") {
+        Some(ArchiveFormat::Zip)
+    } else if name.ends_with(".tgz") || name.ends_with(".tar.gz") {
+        Some(ArchiveFormat::Tgz)
+    } else {
+        None
+    }
+}
+
+/// The name of the export an archive belongs to, e.g. `takeout-20240501T120000Z` for
+/// `takeout-20240501T120000Z-001.zip`, so the parts of an export are unpacked into one folder.
+fn export_name(archive: &str) -> String {
+    // Case-insensitive, like archive_format, so Takeout.ZIP is recognized.
+    let stem = [".zip", ".tgz", ".tar.gz"]
+        .iter()
+        .find_map(|extension| {
+            let start = archive.len().checked_sub(extension.len())?;
+            let suffix = archive.get(start..)?;
+            suffix.eq_ignore_ascii_case(extension).then(|| &archive[..start])
+        })
+        .unwrap_or(archive);
+    match stem.rsplit_once('-') {
+        Some((export, part)) if !part.is_empty() && part.chars().all(|c| c.is_ascii_digit()) => {
+            export.to_string()
+        }
+        _ => stem.to_string(),
+    }
+}
+
+/// The MIME type for the file types Takeout exports contain, so compression and storage class
+/// rules work for unpacked files.
+fn mime_type_for(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map(|(_, e)| e.to_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "json" => "application/json",
+        "html" | "htm" => "text/html",
+        "csv" => "text/csv",
+        "txt" => "text/plain",
+        "xml" => "application/xml",
+        "ics" => "text/calendar",
+        "vcf" => "text/vcard",
+        "mbox" => "application/mbox",
+        "eml" => "message/rfc822",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "heic" => "image/heic",
+        "webp" => "image/webp",
+        "mp4" => "video/mp4",
+        "mov" => "video/quicktime",
+        "mp3" => "audio/mpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Cursor, Write};
+    use std::path::Path;
+
+    use flate2::write::GzEncoder;
+    use futures::{stream, StreamExt};
+    use tokio_util::io::{StreamReader, SyncIoBridge};
+
+    use crate::destination::safe_path;
+    use crate::errors::{Error, Result};
+    use crate::takeout::{
+        archive_format, export_name, read_entries, ArchiveFormat, ArchiveSource, TempFile,
+    };
+
+    fn entries(source: ArchiveSource) -> Result<Vec<(String, u64, Vec<u8>)>> {
+        let mut entries = vec![];
+        read_entries(source, &mut |header, reader| {
+            let mut content = vec![];
+            reader.read_to_end(&mut content).unwrap();
+            entries.push((header.path, header.size, content));
+            Ok(())
+        })?;
+        Ok(entries)
+    }
+
+    fn tgz(entries: &[(String, u64, Vec<u8>)]) -> Vec<u8> {
+        let mut tar = tar::Builder::new(GzEncoder::new(vec![], Default::default()));
+        for (path, size, content) in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(*size);
+            header.set_mode(0o644);
+            header.set_cksum();
+            tar.append_data(&mut header, path, content.as_slice()).unwrap();
+        }
+        tar.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn zip_and_tgz_archives_are_unpacked() {
+        let dir = std::env::temp_dir().join(format!("g2s3-takeout-test-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let expected = vec![
+            ("Takeout/Drive/notes.txt".to_string(), 5, b"notes".to_vec()),
+            ("Takeout/Mail/All mail.mbox".to_string(), 4, b"From".to_vec()),
+        ];
+
+        let zip_path = dir.join("takeout.zip");
+        let mut zip = zip::ZipWriter::new(std::fs::File::create(&zip_path).unwrap());
+        zip.add_directory("Takeout/", Default::default()).unwrap();
+        for (path, _, content) in &expected {
+            zip.start_file(path, Default::default()).unwrap();
+            zip.write_all(content).unwrap();
+        }
+        zip.start_file("../escape.txt", Default::default()).unwrap();
+        zip.finish().unwrap();
+
+        let tgz = Box::new(Cursor::new(tgz(&expected)));
+
+        // The zip archive is removed once it is read, like a download.
+        assert_eq!(entries(ArchiveSource::Zip(TempFile(zip_path.clone()))).unwrap(), expected);
+        assert!(!zip_path.exists());
+        assert_eq!(entries(ArchiveSource::Tgz(tgz)).unwrap(), expected);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tgz_archives_are_unpacked_while_they_are_downloaded() {
+        let expected = vec![("Takeout/Drive/notes.txt".to_string(), 5, b"notes".to_vec())];
+        let archive = tgz(&expected);
+        let download = |end: Option<Error>| {
+            let chunks = archive.chunks(100).map(|c| Ok(c.to_vec())).chain(end.map(Err));
+            let content = stream::iter(chunks.collect::<Vec<Result<Vec<u8>>>>())
+                .map(|chunk| chunk.map(Cursor::new).map_err(|e| io::Error::other(e.to_string())));
+            ArchiveSource::Tgz(Box::new(SyncIoBridge::new(StreamReader::new(content))))
+        };
+
+        let source = download(None);
+        let unpacked = tokio::task::spawn_blocking(move || entries(source)).await.unwrap();
+        assert_eq!(unpacked.unwrap(), expected);
+
+        // A checksum mismatch shows at the end of the download, after the last entry.
+        let source = download(Some(Error::from("Checksum mismatch for takeout.tgz")));
+        let unpacked = tokio::task::spawn_blocking(move || entries(source)).await.unwrap();
+        assert!(unpacked.is_err());
+    }
+
+    #[test]
+    fn archives_are_recognized_by_name() {
+        assert_eq!(archive_format("takeout-20240501T120000Z-001.zip"), Some(ArchiveFormat::Zip));
+        assert_eq!(archive_format("takeout-20240501T120000Z-001.tgz"), Some(ArchiveFormat::Tgz));
+        assert_eq!(archive_format("Takeout-20240501T120000Z.tar.gz"), Some(ArchiveFormat::Tgz));
+        assert_eq!(archive_format("photos.zip"), None);
+        assert_eq!(archive_format("takeout-notes.txt"), None);
+
+        assert_eq!(export_name("takeout-20240501T120000Z-001.zip"), "takeout-20240501T120000Z");
+        assert_eq!(export_name("takeout-20240501T120000Z.tar.gz"), "takeout-20240501T120000Z");
+        assert_eq!(export_name("Takeout-20240501T120000Z-002.ZIP"), "Takeout-20240501T120000Z");
+        assert_eq!(export_name("takeout-20240501T120000Z.Tar.Gz"), "takeout-20240501T120000Z");
+
+        assert_eq!(safe_path(Path::new("./Takeout//a.json")), Some("Takeout/a.json".to_string()));
+        assert_eq!(safe_path(Path::new("/etc/passwd")), None);
+        assert_eq!(safe_path(Path::new("Takeout/../../x")), None);
+    }
+}
@@ -0,0 +1,70 @@
+use crate::errors::Result;
+
+/// A streaming byte transformation applied between the Drive download and the upload (and reversed
+/// on restore), e.g. encryption.
+pub trait Transform: Send {
+    /// Feeds `data` into the transform and returns whatever output is ready.
+    fn update(&mut self, data: &[u8]) -> Result<Vec<u8>>;
+
+    /// Flushes all remaining output. Must be called exactly once after the last `update`.
+    fn finish(&mut self) -> Result<Vec<u8>>;
+}
+
+/// Chains transforms so the output of each one is the input of the next.
+#[derive(Default)]
+pub struct Pipeline(Vec<Box<dyn Transform>>);
+
+impl Pipeline {
+    pub fn push(&mut self, transform: Box<dyn Transform>) {
+        self.0.push(transform);
+    }
+}
+
+impl Transform for Pipeline {
+    fn update(&mut self, data: &[u8]) -> Result<Vec<u8>> {
+        let mut data = data.to_vec();
+        for transform in self.0.iter_mut() {
+            data = transform.update(&data)?;
+        }
+        Ok(data)
+    }
+
+    fn finish(&mut self) -> Result<Vec<u8>> {
+        let mut data = vec![];
+        for transform in self.0.iter_mut() {
+            data = transform.update(&data)?;
+            data.extend(transform.finish()?);
+        }
+        Ok(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::client_side_encryption::MasterKey;
+    use crate::compression::Algorithm;
+    use crate::transform::{Pipeline, Transform};
+
+    #[test]
+    fn pipeline_output_can_be_reversed_by_the_reverse_pipeline() {
+        let key = MasterKey::new([1; 32]);
+        let data = "some,csv\n".repeat(50_000).into_bytes();
+
+        let mut backup = Pipeline::default();
+        backup.push(Algorithm::Zstd.compressor().unwrap());
+        backup.push(Box::new(key.encryptor().unwrap()));
+        let mut stored = vec![];
+        for piece in data.chunks(10_000) {
+            stored.extend(backup.update(piece).unwrap());
+        }
+        stored.extend(backup.finish().unwrap());
+
+        let mut restore = Pipeline::default();
+        restore.push(Box::new(key.decryptor()));
+        restore.push(Algorithm::Zstd.decompressor().unwrap());
+        let mut restored = restore.update(&stored).unwrap();
+        restored.extend(restore.finish().unwrap());
+
+        assert_eq!(restored, data);
+    }
+}
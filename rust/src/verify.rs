@@ -0,0 +1,161 @@
+use std::collections::HashSet;
+
+use futures::{stream, StreamExt, TryStreamExt};
+
+use crate::destination::Destination;
+use crate::errors::{Result, ResultExt};
+use crate::manifest::{Manifest, ManifestEntry, MANIFEST_NAME};
+use crate::restore::{decoder_for, RestoreOptions};
+use crate::transform::Transform;
+
+pub struct VerifyReport {
+    /// Number of manifest entries checked.
+    pub checked: usize,
+    pub problems: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    pub fn print(&self) {
+        for problem in &self.problems {
+            println!("{problem}");
+        }
+        println!("Checked {} files, found {} problems", self.checked, self.problems.len());
+    }
+}
+
+/// Checks that every object the manifest of `destination` lists exists. With `deep`, every object
+/// is also downloaded and decoded, which detects corrupted or tampered content, and the size of
+/// every file that was not packed is compared with the size recorded in the manifest.
+pub async fn verify(
+    destination: &dyn Destination,
+    options: &RestoreOptions,
+    deep: bool,
+) -> Result<VerifyReport> {
+    let manifest = read_manifest(destination, options).await?;
+    let existing: HashSet<String> = destination.list().await?.into_iter().map(|o| o.name).collect();
+
+    let mut problems = vec![];
+    let mut present: Vec<&ManifestEntry> = vec![];
+    let mut seen = HashSet::new();
+    for entry in &manifest.files {
+        if !existing.contains(&entry.key) {
+            problems.push(format!("{} is missing (for {})", entry.key, entry.name));
+        } else if seen.insert(&entry.key) {
+            present.push(entry);
+        }
+    }
+
+    if deep {
+        let results: Vec<(&ManifestEntry, Result<u64>)> = stream::iter(present)
+            .map(|entry| async move {
+                log::info!("Verifying {}", entry.key);
+                let mut size = 0;
+                let decoded =
+                    decode(destination, &entry.key, options, |data| size += data.len() as u64)
+                        .await;
+                (entry, decoded.map(|_| size))
+            })
+            .buffer_unordered(options.concurrency)
+            .collect()
+            .await;
+        for (entry, size) in results {
+            match (size, entry.size) {
+                (Err(e), _) => problems.push(format!("{} cannot be read: {e}", entry.key)),
+                (Ok(size), Some(expected)) if !entry.packed && size != expected => {
+                    problems.push(format!("{} has {size} bytes instead of {expected}", entry.key))
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(VerifyReport { checked: manifest.files.len(), problems })
+}
+
+/// Reads and decodes the manifest of `destination`.
+pub(crate) async fn read_manifest(
+    destination: &dyn Destination,
+    options: &RestoreOptions,
+) -> Result<Manifest> {
+    let mut manifest = vec![];
+    decode(destination, MANIFEST_NAME, options, |data| manifest.extend(data))
+        .await
+        .chain_err(|| format!("Could not read the manifest of {}", destination.url()))?;
+    serde_json::from_slice(&manifest)
+        .chain_err(|| format!("Invalid manifest in {}", destination.url()))
+}
+
+/// Downloads the object `key` and passes its content to `consume` as it was before the backup.
+async fn decode(
+    destination: &dyn Destination,
+    key: &str,
+    options: &RestoreOptions,
+    mut consume: impl FnMut(Vec<u8>),
+) -> Result<()> {
+    let object = destination.read(key).await?;
+    let mut pipeline = decoder_for(key, &object.metadata, options)?;
+    let mut content = object.content;
+    while let Some(chunk) = content.try_next().await? {
+        consume(pipeline.update(&chunk)?);
+    }
+    consume(pipeline.finish()?);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use aws_sdk_s3::types::StorageClass;
+    use google_drive3::api::File;
+
+    use crate::destination::Destination;
+    use crate::local::LocalDestination;
+    use crate::manifest::{Manifest, ManifestEntry, MANIFEST_NAME};
+    use crate::restore::RestoreOptions;
+    use crate::verify::verify;
+
+    async fn put(destination: &dyn Destination, name: &str, data: &[u8]) {
+        let mut writer =
+            destination.create(name, &StorageClass::Standard, HashMap::new()).await.unwrap();
+        writer.write(data).await.unwrap();
+        writer.finish().await.unwrap();
+    }
+
+    fn entry(name: &str, size: u64) -> ManifestEntry {
+        let file = File {
+            id: Some(name.to_string()),
+            name: Some(name.to_string()),
+            size: Some(size.to_string()),
+            ..Default::default()
+        };
+        ManifestEntry::new(&file, name.to_string(), false, &StorageClass::Standard)
+    }
+
+    #[tokio::test]
+    async fn missing_objects_and_wrong_sizes_are_reported() {
+        let dir = std::env::temp_dir().join(format!("g2s3-verify-test-{}", std::process::id()));
+        let backup = LocalDestination::new("file:///backup", dir.clone());
+        put(&backup, "ok.txt", b"hello").await;
+        put(&backup, "truncated.txt", b"hel").await;
+        let manifest = Manifest {
+            files: vec![entry("ok.txt", 5), entry("truncated.txt", 5), entry("gone.txt", 1)],
+        };
+        put(&backup, MANIFEST_NAME, &serde_json::to_vec(&manifest).unwrap()).await;
+        let options = RestoreOptions { encryption_key: None, concurrency: 4 };
+
+        let shallow = verify(&backup, &options, false).await.unwrap();
+        let deep = verify(&backup, &options, true).await.unwrap();
+
+        assert_eq!(shallow.checked, 3);
+        assert_eq!(shallow.problems, vec!["gone.txt is missing (for gone.txt)"]);
+        assert_eq!(
+            deep.problems,
+            vec!["gone.txt is missing (for gone.txt)", "truncated.txt has 3 bytes instead of 5"]
+        );
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,13 @@
+#!/bin/bash -ex
+
+# Starts the Azurite storage emulator on http://127.0.0.1:10000 with a container named g2s3-test,
+# for trying out azblob:// destinations without Azure. See "Azure Blob Storage" in README.md.
+
+docker run --rm -d --name g2s3-azurite -p 10000:10000 \
+    mcr.microsoft.com/azure-storage/azurite azurite-blob --blobHost 0.0.0.0
+
+until curl -s http://127.0.0.1:10000/ > /dev/null; do sleep 1; done
+
+# The well-known development account of Azurite.
+az storage container create --name g2s3-test --connection-string \
+    "DefaultEndpointsProtocol=http;AccountName=devstoreaccount1;AccountKey=Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==;BlobEndpoint=http://127.0.0.1:10000/devstoreaccount1;"
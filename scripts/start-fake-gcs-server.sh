@@ -0,0 +1,12 @@
+#!/bin/bash -ex
+
+# Starts fake-gcs-server on http://localhost:4443 with a bucket named g2s3-test, for trying out gs://
+# destinations without Google Cloud. See "Google Cloud Storage" in README.md.
+
+docker run --rm -d --name g2s3-fake-gcs -p 4443:4443 \
+    fsouza/fake-gcs-server -scheme http -external-url http://localhost:4443
+
+until curl -sf http://localhost:4443/storage/v1/b > /dev/null; do sleep 1; done
+
+curl -sf -X POST -H "Content-Type: application/json" -d '{"name": "g2s3-test"}' \
+    "http://localhost:4443/storage/v1/b?project=g2s3"
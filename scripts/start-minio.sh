@@ -0,0 +1,15 @@
+#!/bin/bash -ex
+
+# Starts a local MinIO on http://localhost:9000 (console on http://localhost:9001) with a bucket
+# named g2s3-test, for trying out backups without AWS. See "Testing against MinIO" in README.md.
+
+docker run --rm -d --name g2s3-minio \
+    -p 9000:9000 -p 9001:9001 \
+    -e MINIO_ROOT_USER=minioadmin \
+    -e MINIO_ROOT_PASSWORD=minioadmin \
+    minio/minio server /data --console-address :9001
+
+until curl -sf http://localhost:9000/minio/health/live; do sleep 1; done
+
+AWS_ACCESS_KEY_ID=minioadmin AWS_SECRET_ACCESS_KEY=minioadmin AWS_REGION=us-east-1 \
+    aws --endpoint-url http://localhost:9000 s3 mb s3://g2s3-test